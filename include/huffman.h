@@ -0,0 +1,39 @@
+#ifndef HUFFMAN_H
+#define HUFFMAN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define HUFFMAN_OK 0
+#define HUFFMAN_INVALID_ARGUMENT 1
+#define HUFFMAN_INVALID_DATA 2
+#define HUFFMAN_ERROR 3
+
+/*
+ * Compresses `input_len` bytes of `input` using `letter_size` bit letters (2-24).
+ * On success `*output` points to a buffer of `*output_len` bytes that must be
+ * released with huffman_free().
+ */
+int huffman_compress(const uint8_t *input, size_t input_len, uint8_t letter_size,
+                     uint8_t **output, size_t *output_len);
+
+/*
+ * Decompresses `input_len` bytes of `input` produced by huffman_compress().
+ * On success `*output` points to a buffer of `*output_len` bytes that must be
+ * released with huffman_free().
+ */
+int huffman_decompress(const uint8_t *input, size_t input_len,
+                       uint8_t **output, size_t *output_len);
+
+/* Releases a buffer returned by huffman_compress() or huffman_decompress(). */
+void huffman_free(uint8_t *buffer, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* HUFFMAN_H */
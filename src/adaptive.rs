@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use crate::bits::{BitSink, BitSource};
+
+struct Node {
+    weight: u64,
+    parent: usize,
+    children: Option<(usize, usize)>,
+    symbol: Option<u32>,
+}
+
+/// Adaptive Huffman code (FGK): both sides start from a tree holding only the not-yet-
+/// transmitted node and update it after every letter, so no code is stored. A letter seen
+/// for the first time is sent as the code of that node followed by the letter itself.
+///
+/// Nodes are kept in order of decreasing weight, the root first, and an update swaps a node
+/// with the first of the same weight before incrementing it, which keeps the tree a Huffman
+/// tree for the letters so far.
+pub(crate) struct AdaptiveCode {
+    letter_size: u8,
+    nodes: Vec<Node>,
+    leaves: HashMap<u32, usize>,
+    /// The not-yet-transmitted node.
+    nyt: usize,
+}
+
+impl AdaptiveCode {
+    pub fn new(letter_size: u8) -> Self {
+        AdaptiveCode {
+            letter_size,
+            nodes: vec![Node {
+                weight: 0,
+                parent: 0,
+                children: None,
+                symbol: None,
+            }],
+            leaves: HashMap::new(),
+            nyt: 0,
+        }
+    }
+
+    pub fn encode<W: BitSink>(
+        &mut self,
+        writer: &mut W,
+        symbol: u32,
+    ) -> Result<(), std::io::Error> {
+        match self.leaves.get(&symbol) {
+            Some(&leaf) => self.write_path(writer, leaf)?,
+            None => {
+                self.write_path(writer, self.nyt)?;
+                writer.put_bits(self.letter_size as u32, symbol)?;
+            }
+        }
+
+        self.update(symbol);
+        Ok(())
+    }
+
+    pub fn decode<R: BitSource>(&mut self, reader: &mut R) -> Result<u32, std::io::Error> {
+        let mut node = 0;
+
+        while let Some((left, right)) = self.nodes[node].children {
+            node = match reader.get_bit()? {
+                false => left,
+                true => right,
+            };
+        }
+
+        let symbol = match self.nodes[node].symbol {
+            Some(symbol) => symbol,
+            None => reader.get_bits(self.letter_size as u32)?,
+        };
+
+        self.update(symbol);
+        Ok(symbol)
+    }
+
+    fn write_path<W: BitSink>(
+        &self,
+        writer: &mut W,
+        mut node: usize,
+    ) -> Result<(), std::io::Error> {
+        let mut path = Vec::new();
+
+        while node != 0 {
+            let parent = self.nodes[node].parent;
+            path.push(
+                self.nodes[parent]
+                    .children
+                    .is_some_and(|(_, right)| right == node),
+            );
+            node = parent;
+        }
+
+        for &bit in path.iter().rev() {
+            writer.put_bit(bit)?;
+        }
+
+        Ok(())
+    }
+
+    fn update(&mut self, symbol: u32) {
+        let mut node = match self.leaves.get(&symbol) {
+            Some(&leaf) => leaf,
+            None => self.split_nyt(symbol),
+        };
+
+        loop {
+            let weight = self.nodes[node].weight;
+            let mut leader = node;
+
+            while leader > 0 && self.nodes[leader - 1].weight == weight {
+                leader -= 1;
+            }
+
+            if leader != node && leader != self.nodes[node].parent {
+                self.swap(node, leader);
+                node = leader;
+            }
+
+            self.nodes[node].weight += 1;
+
+            if node == 0 {
+                break;
+            }
+
+            node = self.nodes[node].parent;
+        }
+    }
+
+    /// Gives the not-yet-transmitted node two children, a leaf for `symbol` and a new
+    /// not-yet-transmitted node, and returns the leaf.
+    fn split_nyt(&mut self, symbol: u32) -> usize {
+        let parent = self.nyt;
+        let (leaf, nyt) = (self.nodes.len(), self.nodes.len() + 1);
+
+        for symbol in [Some(symbol), None] {
+            self.nodes.push(Node {
+                weight: 0,
+                parent,
+                children: None,
+                symbol,
+            });
+        }
+
+        self.nodes[parent].children = Some((nyt, leaf));
+        self.leaves.insert(symbol, leaf);
+        self.nyt = nyt;
+
+        leaf
+    }
+
+    /// Swaps the subtrees at `a` and `b`, which keep their places under their parents.
+    fn swap(&mut self, a: usize, b: usize) {
+        let (parent_a, parent_b) = (self.nodes[a].parent, self.nodes[b].parent);
+        self.nodes.swap(a, b);
+        self.nodes[a].parent = parent_a;
+        self.nodes[b].parent = parent_b;
+
+        for node in [a, b] {
+            match (self.nodes[node].children, self.nodes[node].symbol) {
+                (Some((left, right)), _) => {
+                    self.nodes[left].parent = node;
+                    self.nodes[right].parent = node;
+                }
+                (None, Some(symbol)) => {
+                    self.leaves.insert(symbol, node);
+                }
+                (None, None) => self.nyt = node,
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::path::{Component, Path};
+
+use crate::decode::decompress;
+use crate::encode::compress;
+
+/// Version of the archive layout, the digit ending its magic.
+pub const ARCHIVE_VERSION: u8 = 1;
+
+const MAGIC: &[u8; 4] = b"HFA1";
+
+/// One file stored in an archive. `path` uses `/` separators and is always relative.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+impl ArchiveEntry {
+    /// `size / compressed_size`, or 0 for an empty payload.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_size == 0 {
+            return 0.0;
+        }
+
+        self.size as f64 / self.compressed_size as f64
+    }
+}
+
+fn check_path(path: &str) -> Result<(), std::io::Error> {
+    let safe = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+    if !safe {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsafe path in archive: {}", path),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Writes an archive of several compressed files.
+///
+/// Layout: the magic `HFA1`, then for every file its path length as a big endian `u16`, the
+/// path, its original and compressed sizes as big endian `u64`s and the output of
+/// [`compress`]. A zero path length ends the archive.
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut inner: W) -> Result<Self, std::io::Error> {
+        inner.write_all(MAGIC)?;
+        Ok(ArchiveWriter { inner })
+    }
+
+    pub fn add<R: Read + Seek>(
+        &mut self,
+        path: &str,
+        input: R,
+        letter_size: u8,
+    ) -> Result<ArchiveEntry, std::io::Error> {
+        if check_path(path).is_err() || path.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("path cannot be stored in an archive: {}", path),
+            ));
+        }
+
+        let mut payload = Vec::new();
+        let report = compress(input, &mut payload, letter_size)?;
+
+        let entry = ArchiveEntry {
+            path: path.to_string(),
+            size: report.input_size,
+            compressed_size: payload.len() as u64,
+        };
+
+        self.inner.write_all(&(path.len() as u16).to_be_bytes())?;
+        self.inner.write_all(path.as_bytes())?;
+        self.inner.write_all(&entry.size.to_be_bytes())?;
+        self.inner.write_all(&entry.compressed_size.to_be_bytes())?;
+        self.inner.write_all(&payload)?;
+
+        Ok(entry)
+    }
+
+    /// Writes the end marker and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        self.inner.write_all(&0u16.to_be_bytes())?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+/// Reads an archive written by [`ArchiveWriter`] one entry at a time. Paths are checked to be
+/// relative and free of `..` so they can be joined onto a destination directory.
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+    pending: u64,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, std::io::Error> {
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not an archive",
+            ));
+        }
+
+        Ok(ArchiveReader {
+            inner,
+            pending: 0,
+            finished: false,
+        })
+    }
+
+    /// Reads the next entry header, skipping the payload of the previous entry if it was not
+    /// extracted.
+    pub fn next_entry(&mut self) -> Result<Option<ArchiveEntry>, std::io::Error> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        self.skip_pending()?;
+
+        let mut length = [0; 2];
+        self.inner.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length) as usize;
+
+        if length == 0 {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        let mut path = vec![0; length];
+        self.inner.read_exact(&mut path)?;
+        let path = String::from_utf8(path).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidData, "archive path is not UTF-8")
+        })?;
+        check_path(&path)?;
+
+        let mut sizes = [0; 16];
+        self.inner.read_exact(&mut sizes)?;
+
+        let entry = ArchiveEntry {
+            path,
+            size: u64::from_be_bytes(sizes[..8].try_into().unwrap()),
+            compressed_size: u64::from_be_bytes(sizes[8..].try_into().unwrap()),
+        };
+
+        self.pending = entry.compressed_size;
+
+        Ok(Some(entry))
+    }
+
+    /// Decompresses the entry last returned by [`next_entry`](Self::next_entry) into `output`.
+    pub fn extract<W: Write>(&mut self, output: W) -> Result<(), std::io::Error> {
+        let mut payload = (&mut self.inner).take(self.pending);
+        decompress(&mut payload, output)?;
+        io::copy(&mut payload, &mut io::sink())?;
+
+        self.pending = 0;
+
+        Ok(())
+    }
+
+    fn skip_pending(&mut self) -> Result<(), std::io::Error> {
+        let skipped = io::copy(&mut (&mut self.inner).take(self.pending), &mut io::sink())?;
+
+        if skipped != self.pending {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        self.pending = 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::bits::{BitBuffer, BitSink, BitSource, SliceReader};
+use crate::histogram::Histogram;
+
+/// Largest letter size arithmetic coding works with, as every letter needs a share of the
+/// model's total.
+pub const MAX_ARITHMETIC_LETTER_SIZE: u8 = 16;
+
+/// Bits of the total every model's frequencies are scaled to.
+const TOTAL_BITS: u32 = 16;
+
+/// The range is widened a byte at a time whenever it drops below this.
+const TOP: u32 = 1 << 24;
+
+/// Bits of the width stored with every frequency.
+const WIDTH_BITS: u32 = 5;
+
+/// How letters are turned into bits once they are counted. Huffman codes are the default,
+/// arithmetic coding comes closer to the entropy of skewed letters at some cost in speed.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Coder {
+    #[default]
+    Huffman,
+    Arithmetic,
+}
+
+impl Coder {
+    pub const ALL: [Coder; 2] = [Coder::Huffman, Coder::Arithmetic];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Coder::Huffman => "huffman",
+            Coder::Arithmetic => "arithmetic",
+        }
+    }
+}
+
+impl fmt::Display for Coder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Coder {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Coder::ALL
+            .into_iter()
+            .find(|coder| coder.name() == name)
+            .ok_or_else(|| format!("unknown coder {}, expected huffman or arithmetic", name))
+    }
+}
+
+fn invalid_model() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "invalid letter frequencies")
+}
+
+/// Letter counts, scaled down to a total of at most `2^16` when they add up to more with every
+/// counted letter keeping at least 1, which is what arithmetic coding codes letters with.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Model {
+    /// Letters in increasing order.
+    symbols: Vec<u32>,
+    frequencies: Vec<u32>,
+    /// Sum of the frequencies of the letters before every letter, and the total last.
+    starts: Vec<u32>,
+}
+
+impl Model {
+    pub fn from_histogram(histogram: &Histogram) -> Self {
+        let mut counts = histogram
+            .iter()
+            .map(|(&symbol, count)| (symbol, count as u64))
+            .collect::<Vec<_>>();
+        counts.sort_unstable();
+
+        let total = counts.iter().map(|&(_, count)| count).sum::<u64>();
+        let spare = (1 << TOTAL_BITS) - counts.len() as u64;
+        let scale = |count: u64| match total > 1 << TOTAL_BITS {
+            true => 1 + count * spare / total,
+            false => count,
+        };
+
+        Self::from_frequencies(
+            counts
+                .into_iter()
+                .map(|(symbol, count)| (symbol, scale(count) as u32))
+                .collect(),
+        )
+    }
+
+    fn from_frequencies(frequencies: Vec<(u32, u32)>) -> Self {
+        let mut starts = Vec::with_capacity(frequencies.len() + 1);
+        let mut total = 0;
+
+        for &(_, frequency) in &frequencies {
+            starts.push(total);
+            total += frequency;
+        }
+
+        starts.push(total);
+
+        let (symbols, frequencies) = frequencies.into_iter().unzip();
+
+        Model {
+            symbols,
+            frequencies,
+            starts,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    fn total(&self) -> u32 {
+        *self.starts.last().unwrap()
+    }
+
+    /// Writes the frequencies: a flag bit picks the smaller of two lists. The sparse list (0)
+    /// gives the number of letters less one in `letter_size` bits, then every letter and its
+    /// frequency. The dense list (1) gives the frequency of every possible letter. Frequencies
+    /// are written as their width in 5 bits, 0 for letters that do not occur, then the bits
+    /// below the leading one.
+    pub fn write<W: BitSink>(&self, writer: &mut W, letter_size: u8) -> Result<(), std::io::Error> {
+        let letters = 1u64 << letter_size;
+        let dense = letters * WIDTH_BITS as u64;
+        let sparse =
+            letter_size as u64 + self.len() as u64 * (letter_size as u64 + WIDTH_BITS as u64);
+
+        let write_frequency = |writer: &mut W, frequency: u32| {
+            let width = 32 - frequency.leading_zeros();
+            writer.put_bits(WIDTH_BITS, width)?;
+            writer.put_bits(width.saturating_sub(1), frequency ^ (1 << width >> 1))
+        };
+
+        if dense < sparse {
+            writer.put_bit(true)?;
+
+            let mut next = self.symbols.iter().zip(&self.frequencies).peekable();
+
+            for letter in 0..letters as u32 {
+                let frequency = next.next_if(|&(&symbol, _)| symbol == letter);
+                write_frequency(writer, frequency.map_or(0, |(_, &frequency)| frequency))?;
+            }
+        } else {
+            writer.put_bit(false)?;
+            writer.put_bits(letter_size as u32, self.len() as u32 - 1)?;
+
+            for (&symbol, &frequency) in self.symbols.iter().zip(&self.frequencies) {
+                writer.put_bits(letter_size as u32, symbol)?;
+                write_frequency(writer, frequency)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads what [`write`](Self::write) wrote, checking that the letters are in order and
+    /// the total within bounds.
+    pub fn read<R: BitSource>(reader: &mut R, letter_size: u8) -> Result<Self, std::io::Error> {
+        let read_frequency = |reader: &mut R| match reader.get_bits(WIDTH_BITS)? {
+            0 => Ok(0),
+            width if width > TOTAL_BITS + 1 => Err(invalid_model()),
+            width => Ok(1 << (width - 1) | reader.get_bits(width - 1)?),
+        };
+
+        let mut frequencies = Vec::new();
+
+        if reader.get_bit()? {
+            for letter in 0..1u32 << letter_size {
+                match read_frequency(reader)? {
+                    0 => {}
+                    frequency => frequencies.push((letter, frequency)),
+                }
+            }
+        } else {
+            let count = reader.get_bits(letter_size as u32)? as usize + 1;
+
+            for _ in 0..count {
+                let symbol = reader.get_bits(letter_size as u32)?;
+
+                if frequencies.last().is_some_and(|&(last, _)| last >= symbol) {
+                    return Err(invalid_model());
+                }
+
+                match read_frequency(reader)? {
+                    0 => return Err(invalid_model()),
+                    frequency => frequencies.push((symbol, frequency)),
+                }
+            }
+        }
+
+        let total = frequencies
+            .iter()
+            .map(|&(_, frequency)| frequency as u64)
+            .sum::<u64>();
+
+        if frequencies.is_empty() || total > 1 << TOTAL_BITS {
+            return Err(invalid_model());
+        }
+
+        Ok(Self::from_frequencies(frequencies))
+    }
+}
+
+/// Range coder in the style of LZMA's: `low` carries into bytes already produced, which are
+/// held back while they could still change.
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    /// The last byte held back and how many bytes, it and 0xff ones after it, are pending.
+    cache: u8,
+    pending: u64,
+}
+
+impl RangeEncoder {
+    fn new() -> Self {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+        }
+    }
+
+    fn encode<W: BitSink>(
+        &mut self,
+        writer: &mut W,
+        model: &Model,
+        symbol: u32,
+    ) -> Result<(), std::io::Error> {
+        let index = model.symbols.binary_search(&symbol).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "symbol is missing from the letter frequencies",
+            )
+        })?;
+
+        let step = self.range / model.total();
+        self.low += step as u64 * model.starts[index] as u64;
+        self.range = step * model.frequencies[index];
+
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn shift_low<W: BitSink>(&mut self, writer: &mut W) -> Result<(), std::io::Error> {
+        if self.low < 0xff00_0000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+
+            for _ in 0..self.pending {
+                writer.put_bits(8, byte.wrapping_add(carry) as u32)?;
+                byte = 0xff;
+            }
+
+            self.pending = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+
+        self.pending += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+
+        Ok(())
+    }
+
+    fn finish<W: BitSink>(mut self, writer: &mut W) -> Result<(), std::io::Error> {
+        for _ in 0..5 {
+            self.shift_low(writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct RangeDecoder {
+    code: u32,
+    range: u32,
+}
+
+impl RangeDecoder {
+    pub fn start<R: BitSource>(reader: &mut R) -> Result<Self, std::io::Error> {
+        let mut code = 0;
+
+        for _ in 0..5 {
+            code = code << 8 | reader.get_bits(8)?;
+        }
+
+        Ok(RangeDecoder {
+            code,
+            range: u32::MAX,
+        })
+    }
+
+    pub fn decode<R: BitSource>(
+        &mut self,
+        reader: &mut R,
+        model: &Model,
+    ) -> Result<u32, std::io::Error> {
+        let step = self.range / model.total();
+        let value = self.code / step;
+
+        if value >= model.total() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid arithmetic coded data",
+            ));
+        }
+
+        let index = model.starts.partition_point(|&start| start <= value) - 1;
+        self.code -= step * model.starts[index];
+        self.range = step * model.frequencies[index];
+
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | reader.get_bits(8)?;
+        }
+
+        Ok(model.symbols[index])
+    }
+}
+
+/// Encodes `block` into whole bytes: the frequencies of `model` when `store` is set, then the
+/// letters coded with a range coder and the bits of a trailing partial letter as they are.
+pub(crate) fn encode_block(
+    block: &[u8],
+    letter_size: u8,
+    model: &Model,
+    store: bool,
+    header: &mut Duration,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut reader = SliceReader::new(block);
+    let mut buffer = BitBuffer::default();
+
+    if store {
+        let phase = Instant::now();
+        model.write(&mut buffer, letter_size)?;
+        *header += phase.elapsed();
+    }
+
+    let bits = block.len() as u64 * 8;
+    let letters = bits / letter_size as u64;
+
+    if letters != 0 {
+        let mut encoder = RangeEncoder::new();
+
+        for _ in 0..letters {
+            encoder.encode(&mut buffer, model, reader.get_bits(letter_size as u32)?)?;
+        }
+
+        encoder.finish(&mut buffer)?;
+    }
+
+    let remaining = (bits - letters * letter_size as u64) as u32;
+
+    if remaining != 0 {
+        buffer.put_bits(remaining, reader.get_bits(remaining)?)?;
+    }
+
+    Ok(buffer.into_bytes())
+}
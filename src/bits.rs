@@ -0,0 +1,239 @@
+use std::io::ErrorKind;
+
+use bitstream_io::{BitRead, BitWrite};
+
+pub(crate) trait BitSink {
+    fn put_bit(&mut self, bit: bool) -> Result<(), std::io::Error>;
+
+    fn put_bits(&mut self, bits: u32, value: u32) -> Result<(), std::io::Error>;
+
+    fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        for &byte in bytes {
+            self.put_bits(8, byte as u32)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) trait BitSource {
+    fn get_bit(&mut self) -> Result<bool, std::io::Error>;
+
+    fn get_bits(&mut self, bits: u32) -> Result<u32, std::io::Error>;
+
+    fn get_u64(&mut self) -> Result<u64, std::io::Error> {
+        let high = self.get_bits(32)? as u64;
+        let low = self.get_bits(32)? as u64;
+
+        Ok((high << 32) | low)
+    }
+
+    fn get_bytes(&mut self, bytes: &mut [u8]) -> Result<(), std::io::Error> {
+        for byte in bytes {
+            *byte = self.get_bits(8)? as u8;
+        }
+
+        Ok(())
+    }
+
+    /// Skips to the start of the next byte.
+    fn align(&mut self);
+}
+
+impl<W: BitWrite> BitSink for W {
+    fn put_bit(&mut self, bit: bool) -> Result<(), std::io::Error> {
+        self.write_bit(bit)
+    }
+
+    fn put_bits(&mut self, bits: u32, value: u32) -> Result<(), std::io::Error> {
+        self.write(bits, value)
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        self.write_bytes(bytes)
+    }
+}
+
+impl<R: BitRead> BitSource for R {
+    fn get_bit(&mut self) -> Result<bool, std::io::Error> {
+        self.read_bit()
+    }
+
+    fn get_bits(&mut self, bits: u32) -> Result<u32, std::io::Error> {
+        self.read(bits)
+    }
+
+    fn get_bytes(&mut self, bytes: &mut [u8]) -> Result<(), std::io::Error> {
+        self.read_bytes(bytes)
+    }
+
+    fn align(&mut self) {
+        self.byte_align()
+    }
+}
+
+fn end_of_input() -> std::io::Error {
+    std::io::Error::new(ErrorKind::UnexpectedEof, "unexpected end of input")
+}
+
+pub(crate) struct SliceReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        SliceReader { data, position: 0 }
+    }
+}
+
+impl BitSource for SliceReader<'_> {
+    fn get_bit(&mut self) -> Result<bool, std::io::Error> {
+        let byte = self.data.get(self.position / 8).ok_or_else(end_of_input)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+
+        Ok(bit == 1)
+    }
+
+    fn get_bits(&mut self, bits: u32) -> Result<u32, std::io::Error> {
+        if self.position + bits as usize > self.data.len() * 8 {
+            return Err(end_of_input());
+        }
+
+        let mut value = 0;
+        let mut remaining = bits;
+
+        while remaining > 0 {
+            let byte = self.data[self.position / 8] as u32;
+            let available = 8 - (self.position % 8) as u32;
+            let take = available.min(remaining);
+
+            value = (value << take) | ((byte >> (available - take)) & ((1 << take) - 1));
+            self.position += take as usize;
+            remaining -= take;
+        }
+
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+pub(crate) struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub(crate) fn new(buffer: &'a mut [u8]) -> Self {
+        SliceWriter {
+            buffer,
+            position: 0,
+        }
+    }
+
+    pub(crate) fn written(&self) -> usize {
+        self.position.div_ceil(8)
+    }
+}
+
+impl BitSink for SliceWriter<'_> {
+    fn put_bit(&mut self, bit: bool) -> Result<(), std::io::Error> {
+        self.put_bits(1, bit as u32)
+    }
+
+    fn put_bits(&mut self, bits: u32, value: u32) -> Result<(), std::io::Error> {
+        if self.position + bits as usize > self.buffer.len() * 8 {
+            return Err(std::io::Error::new(
+                ErrorKind::WriteZero,
+                "output buffer is too small",
+            ));
+        }
+
+        let mut remaining = bits;
+
+        while remaining > 0 {
+            let index = self.position / 8;
+            let available = 8 - (self.position % 8) as u32;
+            let take = available.min(remaining);
+            let chunk = (value >> (remaining - take)) & ((1 << take) - 1);
+
+            if available == 8 {
+                self.buffer[index] = 0;
+            }
+
+            self.buffer[index] |= (chunk << (available - take)) as u8;
+            self.position += take as usize;
+            remaining -= take;
+        }
+
+        Ok(())
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        if !self.position.is_multiple_of(8) {
+            for &byte in bytes {
+                self.put_bits(8, byte as u32)?;
+            }
+
+            return Ok(());
+        }
+
+        let start = self.position / 8;
+        let target = self
+            .buffer
+            .get_mut(start..start + bytes.len())
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::WriteZero, "output buffer is too small")
+            })?;
+
+        target.copy_from_slice(bytes);
+        self.position += bytes.len() * 8;
+
+        Ok(())
+    }
+}
+
+/// Bits collected in memory, padded with zeros to whole bytes.
+#[derive(Default)]
+pub(crate) struct BitBuffer {
+    bytes: Vec<u8>,
+    bits: u64,
+}
+
+impl BitBuffer {
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl BitSink for BitBuffer {
+    fn put_bit(&mut self, bit: bool) -> Result<(), std::io::Error> {
+        self.put_bits(1, bit as u32)
+    }
+
+    fn put_bits(&mut self, bits: u32, value: u32) -> Result<(), std::io::Error> {
+        let mut remaining = bits;
+
+        while remaining > 0 {
+            let offset = (self.bits % 8) as u32;
+
+            if offset == 0 {
+                self.bytes.push(0);
+            }
+
+            let available = 8 - offset;
+            let take = available.min(remaining);
+            let chunk = (value >> (remaining - take)) & ((1 << take) - 1);
+
+            *self.bytes.last_mut().unwrap() |= (chunk << (available - take)) as u8;
+            self.bits += take as u64;
+            remaining -= take;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+
+/// Hash of the original data stored after the compressed payload and checked on
+/// decompression. CRC32 unless chosen otherwise, so corruption is reported instead of
+/// decoding to garbage.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Checksum {
+    None,
+    #[default]
+    Crc32,
+    Xxh64,
+    Blake3,
+}
+
+impl Checksum {
+    pub const ALL: [Checksum; 4] = [
+        Checksum::None,
+        Checksum::Crc32,
+        Checksum::Xxh64,
+        Checksum::Blake3,
+    ];
+
+    /// Number stored in bits 5 and 6 of the letter size byte.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc32 => 1,
+            Checksum::Xxh64 => 2,
+            Checksum::Blake3 => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self, std::io::Error> {
+        match id {
+            0 => Ok(Checksum::None),
+            1 => Ok(Checksum::Crc32),
+            2 => Ok(Checksum::Xxh64),
+            3 => Ok(Checksum::Blake3),
+            _ => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "unknown checksum algorithm",
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Checksum::None => "none",
+            Checksum::Crc32 => "crc32",
+            Checksum::Xxh64 => "xxh64",
+            Checksum::Blake3 => "blake3",
+        }
+    }
+
+    /// Size of the stored digest in bytes.
+    pub fn len(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc32 => 4,
+            Checksum::Xxh64 => 8,
+            Checksum::Blake3 => 32,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Checksum::None
+    }
+
+    /// Whether this build can compute the checksum. XXH64 and BLAKE3 need the `xxh64` and
+    /// `blake3` features.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Checksum::None | Checksum::Crc32 => true,
+            Checksum::Xxh64 => cfg!(feature = "xxh64"),
+            Checksum::Blake3 => cfg!(feature = "blake3"),
+        }
+    }
+
+    /// Fails with [`ErrorKind::Unsupported`] for checksums this build leaves out.
+    pub(crate) fn hasher(self) -> Result<Hasher, std::io::Error> {
+        Ok(match self {
+            Checksum::None => Hasher::None,
+            Checksum::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            #[cfg(feature = "xxh64")]
+            Checksum::Xxh64 => Hasher::Xxh64(xxhash_rust::xxh64::Xxh64::new(0)),
+            #[cfg(feature = "blake3")]
+            Checksum::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            #[cfg(not(all(feature = "xxh64", feature = "blake3")))]
+            checksum => {
+                return Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "{} checksums need the {} feature of this crate",
+                        checksum, checksum
+                    ),
+                ))
+            }
+        })
+    }
+
+    /// Digest of `data` as stored in the output.
+    pub fn digest(self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut hasher = self.hasher()?;
+        hasher.update(data);
+        Ok(hasher.finish())
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Checksum::ALL
+            .into_iter()
+            .find(|checksum| checksum.name() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown checksum {}, expected crc32, xxh64, blake3 or none",
+                    name
+                )
+            })
+    }
+}
+
+pub(crate) enum Hasher {
+    None,
+    Crc32(crc32fast::Hasher),
+    #[cfg(feature = "xxh64")]
+    Xxh64(xxhash_rust::xxh64::Xxh64),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::None => {}
+            Hasher::Crc32(hasher) => hasher.update(data),
+            #[cfg(feature = "xxh64")]
+            Hasher::Xxh64(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// The digest in big endian byte order.
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::None => Vec::new(),
+            Hasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            #[cfg(feature = "xxh64")]
+            Hasher::Xxh64(hasher) => hasher.digest().to_be_bytes().to_vec(),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Hashes the bytes passing through a reader or writer.
+pub(crate) struct Hashing<T> {
+    inner: T,
+    hasher: Hasher,
+}
+
+impl<T> Hashing<T> {
+    pub fn new(inner: T, checksum: Checksum) -> Result<Self, std::io::Error> {
+        Ok(Hashing {
+            inner,
+            hasher: checksum.hasher()?,
+        })
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,249 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::decode::Decompressor;
+use crate::encode::Compressor;
+use crate::report::{CompressionReport, Counter};
+
+/// Version of the container layout, the digit ending its magic.
+pub const CONTAINER_VERSION: u8 = 1;
+
+/// First bytes of every container.
+pub const CONTAINER_MAGIC: &[u8; 4] = b"HFS1";
+
+/// Bytes taken by the number of streams and the offset of the directory at the end.
+const TRAILER_SIZE: u64 = 12;
+
+/// One named stream of a container.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamEntry {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    /// Offset of the compressed stream from the start of the container.
+    offset: u64,
+}
+
+impl StreamEntry {
+    /// `size / compressed_size`, or 0 for an empty payload.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_size == 0 {
+            return 0.0;
+        }
+
+        self.size as f64 / self.compressed_size as f64
+    }
+}
+
+fn invalid_container() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "invalid container directory")
+}
+
+/// Writes several independently compressed streams into one output, each under a name it is
+/// read back by.
+///
+/// Layout: the magic `HFS1`, the compressed streams one after another, then a directory
+/// holding for every stream its name length as a big endian `u16`, the name, and its offset
+/// from the start of the container, original size and compressed size as big endian `u64`s.
+/// The number of streams as a big endian `u32` and the offset of the directory as a big
+/// endian `u64` end the container, so readers find any stream without reading the others.
+pub struct ContainerWriter<W: Write> {
+    inner: Counter<W>,
+    entries: Vec<StreamEntry>,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    pub fn new(inner: W) -> Result<Self, std::io::Error> {
+        let mut inner = Counter::new(inner);
+        inner.write_all(CONTAINER_MAGIC)?;
+
+        Ok(ContainerWriter {
+            inner,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Compresses `input` with `letter_size` bit letters as the stream `name`.
+    pub fn add<R: Read + Seek>(
+        &mut self,
+        name: &str,
+        input: R,
+        letter_size: u8,
+    ) -> Result<StreamEntry, std::io::Error> {
+        self.add_with(name, input, &mut Compressor::new(letter_size))
+    }
+
+    /// Compresses `input` with `compressor` as the stream `name`, so every stream can be
+    /// compressed its own way.
+    pub fn add_with<R: Read + Seek>(
+        &mut self,
+        name: &str,
+        input: R,
+        compressor: &mut Compressor,
+    ) -> Result<StreamEntry, std::io::Error> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("stream name cannot be stored in a container: {}", name),
+            ));
+        }
+
+        if self.entries.iter().any(|entry| entry.name == name) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("container already has a stream named {}", name),
+            ));
+        }
+
+        let offset = self.inner.count();
+        let report = compressor.compress(input, &mut self.inner)?;
+
+        let entry = StreamEntry {
+            name: name.to_string(),
+            size: report.input_size,
+            compressed_size: self.inner.count() - offset,
+            offset,
+        };
+        self.entries.push(entry.clone());
+
+        Ok(entry)
+    }
+
+    /// Writes the directory and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        let directory = self.inner.count();
+
+        for entry in &self.entries {
+            self.inner
+                .write_all(&(entry.name.len() as u16).to_be_bytes())?;
+            self.inner.write_all(entry.name.as_bytes())?;
+            self.inner.write_all(&entry.offset.to_be_bytes())?;
+            self.inner.write_all(&entry.size.to_be_bytes())?;
+            self.inner.write_all(&entry.compressed_size.to_be_bytes())?;
+        }
+
+        self.inner
+            .write_all(&(self.entries.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&directory.to_be_bytes())?;
+        self.inner.flush()?;
+
+        Ok(self.inner.into_inner())
+    }
+}
+
+/// Reads the streams of a container written by [`ContainerWriter`] by name, in any order.
+pub struct ContainerReader<R: Read + Seek> {
+    inner: R,
+    /// Position of the container in `inner`.
+    start: u64,
+    entries: Vec<StreamEntry>,
+}
+
+impl<R: Read + Seek> ContainerReader<R> {
+    /// Reads the directory of the container starting at the current position of `inner`.
+    pub fn new(mut inner: R) -> Result<Self, std::io::Error> {
+        let start = inner.stream_position()?;
+        let mut magic = [0; 4];
+        inner.read_exact(&mut magic)?;
+
+        if &magic != CONTAINER_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a container",
+            ));
+        }
+
+        let len = inner.seek(SeekFrom::End(0))? - start;
+
+        if len < CONTAINER_MAGIC.len() as u64 + TRAILER_SIZE {
+            return Err(invalid_container());
+        }
+
+        inner.seek(SeekFrom::Start(start + len - TRAILER_SIZE))?;
+        let mut trailer = [0; TRAILER_SIZE as usize];
+        inner.read_exact(&mut trailer)?;
+
+        let count = u32::from_be_bytes(trailer[..4].try_into().unwrap());
+        let directory = u64::from_be_bytes(trailer[4..].try_into().unwrap());
+
+        if directory < CONTAINER_MAGIC.len() as u64 || directory > len - TRAILER_SIZE {
+            return Err(invalid_container());
+        }
+
+        inner.seek(SeekFrom::Start(start + directory))?;
+        let mut entries = Vec::<StreamEntry>::new();
+
+        for _ in 0..count {
+            let mut length = [0; 2];
+            inner.read_exact(&mut length)?;
+
+            let mut name = vec![0; u16::from_be_bytes(length) as usize];
+            inner.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid_container())?;
+
+            let mut fields = [0; 24];
+            inner.read_exact(&mut fields)?;
+
+            let entry = StreamEntry {
+                name,
+                offset: u64::from_be_bytes(fields[..8].try_into().unwrap()),
+                size: u64::from_be_bytes(fields[8..16].try_into().unwrap()),
+                compressed_size: u64::from_be_bytes(fields[16..].try_into().unwrap()),
+            };
+
+            let end = entry.offset.checked_add(entry.compressed_size);
+
+            if entry.offset < CONTAINER_MAGIC.len() as u64
+                || end.is_none_or(|end| end > directory)
+                || entries.iter().any(|other| other.name == entry.name)
+            {
+                return Err(invalid_container());
+            }
+
+            entries.push(entry);
+        }
+
+        Ok(ContainerReader {
+            inner,
+            start,
+            entries,
+        })
+    }
+
+    /// The streams in the order they were added.
+    pub fn entries(&self) -> &[StreamEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&StreamEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Decompresses the stream `name` into `output`.
+    pub fn extract<W: Write>(
+        &mut self,
+        name: &str,
+        output: W,
+    ) -> Result<CompressionReport, std::io::Error> {
+        self.extract_with(name, output, &mut Decompressor::new())
+    }
+
+    /// Decompresses the stream `name` into `output` with `decompressor`, e.g. to give the
+    /// password of an encrypted stream.
+    pub fn extract_with<W: Write>(
+        &mut self,
+        name: &str,
+        output: W,
+        decompressor: &mut Decompressor,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let entry = self.entry(name).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("container has no stream named {}", name),
+            )
+        })?;
+        let (offset, compressed_size) = (entry.offset, entry.compressed_size);
+
+        self.inner.seek(SeekFrom::Start(self.start + offset))?;
+        decompressor.decompress((&mut self.inner).take(compressed_size), output)
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::bits::{BitBuffer, BitSink, BitSource, SliceReader};
+use crate::decode::{decode_canonical, read_header};
+use crate::encode::{build_code, encode_symbol, write_header};
+use crate::histogram::Histogram;
+use crate::limits::Limits;
+use crate::tree::{CanonicalCode, CodeTable};
+
+/// Bits of the letter before every letter that pick its code, the top ones of longer letters.
+const CONTEXT_BITS: u8 = 8;
+
+/// The context of the letter after `previous`.
+fn context(previous: u32, letter_size: u8) -> usize {
+    (previous >> letter_size.saturating_sub(CONTEXT_BITS)) as usize
+}
+
+fn context_count(letter_size: u8) -> usize {
+    1 << letter_size.min(CONTEXT_BITS)
+}
+
+/// Counts the letters of `block` paired with their contexts, as `context << letter_size |
+/// letter`. The first letter of a block is in context 0.
+pub(crate) fn count_pairs(block: &[u8], letter_size: u8) -> Result<Histogram, std::io::Error> {
+    let mut reader = SliceReader::new(block);
+    let mut histogram = Histogram::new();
+    let mut previous = 0;
+
+    for _ in 0..block.len() as u64 * 8 / letter_size as u64 {
+        let letter = reader.get_bits(letter_size as u32)?;
+        histogram.add((context(previous, letter_size) as u32) << letter_size | letter);
+        previous = letter;
+    }
+
+    Ok(histogram)
+}
+
+/// A canonical code for every context that occurs, which the letters in that context are
+/// coded with.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Contexts {
+    codes: Vec<Option<CanonicalCode>>,
+    tables: Vec<CodeTable>,
+}
+
+impl Contexts {
+    /// Builds the codes for letters counted by [`count_pairs`], limited to `max_length` bits
+    /// when given.
+    pub fn build(
+        pairs: Histogram,
+        letter_size: u8,
+        max_length: Option<u32>,
+    ) -> Result<Self, std::io::Error> {
+        let mut histograms = vec![Histogram::new(); context_count(letter_size)];
+        let mask = (1 << letter_size) - 1;
+
+        for (pair, count) in pairs {
+            histograms[(pair >> letter_size) as usize].add_count(pair & mask, count);
+        }
+
+        let codes = histograms
+            .into_iter()
+            .map(|histogram| build_code(histogram, max_length))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::from_codes(codes))
+    }
+
+    fn from_codes(codes: Vec<Option<CanonicalCode>>) -> Self {
+        let tables = codes
+            .iter()
+            .map(|code| code.as_ref().map(CanonicalCode::table).unwrap_or_default())
+            .collect();
+
+        Contexts { codes, tables }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.iter().all(Option::is_none)
+    }
+
+    /// Letters over the codes of all contexts.
+    pub fn len(&self) -> usize {
+        self.codes.iter().flatten().map(CanonicalCode::len).sum()
+    }
+
+    pub fn max_length(&self) -> u32 {
+        self.codes
+            .iter()
+            .flatten()
+            .map(CanonicalCode::max_length)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Writes a bit for every context, set for those with a code, then the code lengths of
+    /// those codes as [`write_header`] writes them, with coded lists allowed.
+    pub fn write<W: BitSink>(&self, writer: &mut W, letter_size: u8) -> Result<(), std::io::Error> {
+        for code in &self.codes {
+            writer.put_bit(code.is_some())?;
+        }
+
+        for code in self.codes.iter().flatten() {
+            write_header(writer, code, letter_size, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads what [`write`](Self::write) wrote, with coded lists when `coded` is set.
+    pub fn read<R: BitSource>(
+        reader: &mut R,
+        letter_size: u8,
+        limits: &Limits,
+        coded: bool,
+    ) -> Result<Self, std::io::Error> {
+        let mut present = Vec::with_capacity(context_count(letter_size));
+
+        for _ in 0..context_count(letter_size) {
+            present.push(reader.get_bit()?);
+        }
+
+        let codes = present
+            .into_iter()
+            .map(|present| {
+                present
+                    .then(|| read_header(reader, letter_size, limits, coded))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::from_codes(codes))
+    }
+
+    /// Decodes the letter after `previous` from `reader`.
+    pub fn decode<R: BitSource>(
+        &self,
+        reader: &mut R,
+        previous: u32,
+        letter_size: u8,
+    ) -> Result<u32, std::io::Error> {
+        match self.codes.get(context(previous, letter_size)) {
+            Some(Some(code)) => decode_canonical(reader, code),
+            _ => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "no code for the context of a letter",
+            )),
+        }
+    }
+}
+
+/// Encodes `block` into whole bytes: the codes of `contexts` when `store` is set, then every
+/// letter coded with the code of the context the letter before it gives, and the bits of a
+/// trailing partial letter as they are.
+pub(crate) fn encode_block(
+    block: &[u8],
+    letter_size: u8,
+    contexts: &Contexts,
+    store: bool,
+    header: &mut Duration,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut reader = SliceReader::new(block);
+    let mut buffer = BitBuffer::default();
+
+    if store {
+        let phase = Instant::now();
+        contexts.write(&mut buffer, letter_size)?;
+        *header += phase.elapsed();
+    }
+
+    let bits = block.len() as u64 * 8;
+    let letters = bits / letter_size as u64;
+    let mut previous = 0;
+
+    for _ in 0..letters {
+        let letter = reader.get_bits(letter_size as u32)?;
+        let table = &contexts.tables[context(previous, letter_size)];
+        encode_symbol(&mut buffer, table, &letter)?;
+        previous = letter;
+    }
+
+    let remaining = (bits - letters * letter_size as u64) as u32;
+
+    if remaining != 0 {
+        buffer.put_bits(remaining, reader.get_bits(remaining)?)?;
+    }
+
+    Ok(buffer.into_bytes())
+}
@@ -0,0 +1,560 @@
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::Aes256Gcm;
+#[cfg(feature = "encryption")]
+use argon2::{Algorithm as Variant, Argon2, Params, Version};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+const SALT_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Largest Argon2 memory cost in KiB and number of passes a stream may ask for, so corrupt
+/// costs cannot tie up the reader.
+const MAX_KEY_MEMORY: u32 = 1 << 20;
+const MAX_KEY_PASSES: u32 = 64;
+
+/// Bytes before the first chunk that [`Sealer`] and [`Opener`] keep to authenticate, more
+/// than any stream writes before it.
+const MAX_HEADER_SIZE: usize = 128;
+
+fn wrong_password() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "wrong password or corrupt data")
+}
+
+/// Authenticated cipher encrypted streams are sealed with. ChaCha20-Poly1305 unless chosen
+/// otherwise, AES-256-GCM is faster on processors with AES instructions.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Encryption {
+    #[default]
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl Encryption {
+    pub const ALL: [Encryption; 2] = [Encryption::ChaCha20Poly1305, Encryption::Aes256Gcm];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encryption::ChaCha20Poly1305 => "chacha20-poly1305",
+            Encryption::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+
+    /// Whether this build can encrypt and decrypt, which needs the `encryption` feature.
+    pub fn is_supported() -> bool {
+        cfg!(feature = "encryption")
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Encryption::ALL
+            .into_iter()
+            .find(|encryption| encryption.name() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown cipher {}, expected chacha20-poly1305 or aes-256-gcm",
+                    name
+                )
+            })
+    }
+}
+
+#[cfg(feature = "encryption")]
+enum Algorithm {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+/// Argon2id costs a key is derived with: memory in KiB, passes over it and lanes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct KeyCosts {
+    memory: u32,
+    passes: u32,
+    lanes: u32,
+}
+
+impl KeyCosts {
+    /// The costs Argon2 recommends, which new streams are written with.
+    const DEFAULT: KeyCosts = KeyCosts {
+        memory: 19 * 1024,
+        passes: 2,
+        lanes: 1,
+    };
+
+    fn to_bytes(self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&self.memory.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.passes.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.lanes.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 12]) -> Result<Self, std::io::Error> {
+        let field = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let costs = KeyCosts {
+            memory: field(0),
+            passes: field(4),
+            lanes: field(8),
+        };
+
+        if costs.memory > MAX_KEY_MEMORY || costs.passes > MAX_KEY_PASSES {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the key derivation costs of the stream are too high",
+            ));
+        }
+
+        Ok(costs)
+    }
+}
+
+/// The chosen [`Encryption`] with a key derived from a password by Argon2id. Every chunk gets
+/// its own nonce: a random prefix, the chunk number and a flag marking the last chunk, so
+/// chunks cannot be reordered, dropped or cut off unnoticed. Every chunk is also bound to
+/// `header`, the bytes of the stream before the first one.
+#[cfg(feature = "encryption")]
+struct Cipher {
+    aead: Algorithm,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    header: Vec<u8>,
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    fn new(
+        encryption: Encryption,
+        password: &[u8],
+        salt: &[u8; SALT_SIZE],
+        costs: KeyCosts,
+        prefix: [u8; NONCE_PREFIX_SIZE],
+        header: Vec<u8>,
+    ) -> Result<Self, std::io::Error> {
+        let params = Params::new(costs.memory, costs.passes, costs.lanes, None)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let mut key = [0; 32];
+        Argon2::new(Variant::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, salt, &mut key)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+
+        let aead = match encryption {
+            Encryption::ChaCha20Poly1305 => {
+                Algorithm::ChaCha20Poly1305(ChaCha20Poly1305::new(&key.into()))
+            }
+            Encryption::Aes256Gcm => Algorithm::Aes256Gcm(Box::new(Aes256Gcm::new(&key.into()))),
+        };
+
+        Ok(Cipher {
+            aead,
+            prefix,
+            counter: 0,
+            header,
+        })
+    }
+
+    fn encrypt(&mut self, plain: &[u8], last: bool) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = self.nonce(last)?;
+        let plain = Payload {
+            msg: plain,
+            aad: &self.header,
+        };
+        let sealed = match &self.aead {
+            Algorithm::ChaCha20Poly1305(aead) => aead.encrypt(&nonce, plain),
+            Algorithm::Aes256Gcm(aead) => aead.encrypt(&nonce, plain),
+        };
+
+        sealed.map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "encryption failed"))
+    }
+
+    fn decrypt(&mut self, sealed: &[u8], last: bool) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = self.nonce(last)?;
+        let sealed = Payload {
+            msg: sealed,
+            aad: &self.header,
+        };
+        let plain = match &self.aead {
+            Algorithm::ChaCha20Poly1305(aead) => aead.decrypt(&nonce, sealed),
+            Algorithm::Aes256Gcm(aead) => aead.decrypt(&nonce, sealed),
+        };
+
+        plain.map_err(|_| wrong_password())
+    }
+
+    fn nonce(&mut self, last: bool) -> Result<Nonce, std::io::Error> {
+        let mut nonce = [0; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "too much data to encrypt")
+        })?;
+
+        Ok(*Nonce::from_slice(&nonce))
+    }
+}
+
+/// Stands in for [`Cipher`] in builds without the `encryption` feature, which cannot make one.
+#[cfg(not(feature = "encryption"))]
+enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    fn new(
+        _: Encryption,
+        _: &[u8],
+        _: &[u8; SALT_SIZE],
+        _: KeyCosts,
+        _: [u8; NONCE_PREFIX_SIZE],
+        _: Vec<u8>,
+    ) -> Result<Self, std::io::Error> {
+        Err(unsupported())
+    }
+
+    fn encrypt(&mut self, _: &[u8], _: bool) -> Result<Vec<u8>, std::io::Error> {
+        match *self {}
+    }
+
+    fn decrypt(&mut self, _: &[u8], _: bool) -> Result<Vec<u8>, std::io::Error> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn fill_random(buffer: &mut [u8]) -> Result<(), std::io::Error> {
+    getrandom::getrandom(buffer).map_err(std::io::Error::other)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn fill_random(_: &mut [u8]) -> Result<(), std::io::Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::Unsupported,
+        "encrypted streams need the encryption feature of this crate",
+    )
+}
+
+/// Passes bytes through to `inner` until [`start`](Sealer::start) is called and encrypts
+/// everything written after that.
+///
+/// The encrypted part is the salt, the Argon2id memory cost in KiB, passes and lanes as big
+/// endian `u32`s and the nonce prefix, followed by chunks of at most 64 KiB of plain text,
+/// each framed as a last chunk flag byte and the big endian `u32` length of the cipher text.
+/// All bytes before the first chunk are authenticated with every chunk.
+pub(crate) struct Sealer<W: Write> {
+    inner: W,
+    cipher: Option<Cipher>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Sealer<W> {
+    pub fn new(inner: W) -> Self {
+        Sealer {
+            inner,
+            cipher: None,
+            header: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn start(&mut self, encryption: Encryption, password: &[u8]) -> Result<(), std::io::Error> {
+        let mut salt = [0; SALT_SIZE];
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        fill_random(&mut salt)?;
+        fill_random(&mut prefix)?;
+
+        let costs = KeyCosts::DEFAULT;
+        let mut header = std::mem::take(&mut self.header);
+        header.extend(salt);
+        header.extend(costs.to_bytes());
+        header.extend(prefix);
+
+        let cipher = Cipher::new(encryption, password, &salt, costs, prefix, header)?;
+        self.inner.write_all(&salt)?;
+        self.inner.write_all(&costs.to_bytes())?;
+        self.inner.write_all(&prefix)?;
+        self.cipher = Some(cipher);
+
+        Ok(())
+    }
+
+    /// Writes the last chunk. Does nothing unless encryption was started.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        match self.cipher {
+            Some(_) => self.seal(self.buffer.len(), true),
+            None => Ok(()),
+        }
+    }
+
+    fn seal(&mut self, size: usize, last: bool) -> Result<(), std::io::Error> {
+        let cipher = self.cipher.as_mut().unwrap();
+        let sealed = cipher.encrypt(&self.buffer[..size], last)?;
+        self.buffer.drain(..size);
+
+        self.inner.write_all(&[last as u8])?;
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)
+    }
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if self.cipher.is_none() {
+            let written = self.inner.write(buf)?;
+            let kept = written.min(MAX_HEADER_SIZE.saturating_sub(self.header.len()));
+            self.header.extend_from_slice(&buf[..kept]);
+            return Ok(written);
+        }
+
+        self.buffer.extend_from_slice(buf);
+
+        // A full chunk is held back until more follows, as the last one has to be flagged.
+        while self.buffer.len() > CHUNK_SIZE {
+            self.seal(CHUNK_SIZE, false)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Reads what [`Sealer`] wrote: bytes pass through until [`start`](Opener::start) is called,
+/// after which they are decrypted and authenticated a chunk at a time.
+pub(crate) struct Opener<R: Read> {
+    inner: R,
+    cipher: Option<Cipher>,
+    header: Vec<u8>,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> Opener<R> {
+    pub fn new(inner: R) -> Self {
+        Opener {
+            inner,
+            cipher: None,
+            header: Vec::new(),
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    pub fn start(&mut self, encryption: Encryption, password: &[u8]) -> Result<(), std::io::Error> {
+        let mut salt = [0; SALT_SIZE];
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        let mut costs = [0; 12];
+        self.inner.read_exact(&mut salt)?;
+        self.inner.read_exact(&mut costs)?;
+        self.inner.read_exact(&mut prefix)?;
+
+        let mut header = std::mem::take(&mut self.header);
+        header.extend(salt);
+        header.extend(costs);
+        header.extend(prefix);
+
+        let costs = KeyCosts::from_bytes(costs)?;
+        self.cipher = Some(Cipher::new(
+            encryption, password, &salt, costs, prefix, header,
+        )?);
+
+        Ok(())
+    }
+
+    /// Checks that the last chunk was read and all of it was used.
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+
+        if self.position == self.chunk.len() && !self.finished {
+            self.open()?;
+        }
+
+        if self.position != self.chunk.len() || !self.finished {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "unexpected data after the compressed stream",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<(), std::io::Error> {
+        let mut frame = [0; 5];
+        self.inner.read_exact(&mut frame)?;
+
+        let last = match frame[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(wrong_password()),
+        };
+        let size = u32::from_be_bytes(frame[1..].try_into().unwrap()) as usize;
+
+        if !(TAG_SIZE..=CHUNK_SIZE + TAG_SIZE).contains(&size) {
+            return Err(wrong_password());
+        }
+
+        let mut sealed = vec![0; size];
+        self.inner.read_exact(&mut sealed)?;
+
+        let cipher = self.cipher.as_mut().unwrap();
+        self.chunk = cipher.decrypt(&sealed, last)?;
+        self.position = 0;
+        self.finished = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.cipher.is_none() {
+            let read = self.inner.read(buf)?;
+            let kept = read.min(MAX_HEADER_SIZE.saturating_sub(self.header.len()));
+            self.header.extend_from_slice(&buf[..kept]);
+            return Ok(read);
+        }
+
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.open()?;
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use std::io::Cursor;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::decode::Decompressor;
+    use crate::encode::Compressor;
+    use crate::format::MAGIC;
+
+    /// Compressed with a password, in more than one chunk.
+    fn sealed(encryption: Encryption) -> Vec<u8> {
+        let data = (0..3 * CHUNK_SIZE as u32)
+            .map(|i| (i % 97 + i / 5000) as u8)
+            .collect::<Vec<_>>();
+        let mut compressed = Vec::new();
+
+        Compressor::new(8)
+            .password(b"secret")
+            .encryption(encryption)
+            .compress(Cursor::new(data), &mut compressed)
+            .unwrap();
+
+        compressed
+    }
+
+    fn open(compressed: &[u8], password: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut output = Vec::new();
+        Decompressor::new()
+            .password(password)
+            .decompress(compressed, &mut output)?;
+
+        Ok(output)
+    }
+
+    #[test]
+    fn rejects_tampered_input() {
+        for encryption in Encryption::ALL {
+            let compressed = sealed(encryption);
+            assert!(open(&compressed, b"secret").is_ok());
+            assert!(open(&compressed, b"wrong").is_err());
+
+            let len = compressed.len();
+
+            for position in [24, 40, len / 2, len - TAG_SIZE, len - 1] {
+                let mut tampered = compressed.clone();
+                tampered[position] ^= 1;
+
+                assert!(
+                    open(&tampered, b"secret").is_err(),
+                    "{:?} {}",
+                    encryption,
+                    position
+                );
+            }
+
+            for cut in [1, CHUNK_SIZE] {
+                let truncated = &compressed[..len - cut];
+                assert!(
+                    open(truncated, b"secret").is_err(),
+                    "{:?} {}",
+                    encryption,
+                    cut
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn authenticates_the_plain_prefix() {
+        let mut compressed = Vec::new();
+        Compressor::new(8)
+            .password(b"secret")
+            .mtime(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .compress(Cursor::new(b"some data"), &mut compressed)
+            .unwrap();
+        assert_eq!(open(&compressed, b"secret").unwrap(), b"some data");
+
+        // Magic, version, letter size, two flags bytes, original size and modification time.
+        let salt = MAGIC.len() + 4 + 8 + 8;
+        let costs = salt + SALT_SIZE;
+
+        for position in [salt - 1, salt, costs + 3, costs + 12] {
+            let mut tampered = compressed.clone();
+            tampered[position] ^= 1;
+
+            assert!(open(&tampered, b"secret").is_err(), "{}", position);
+        }
+
+        let mut costly = compressed.clone();
+        costly[costs..costs + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let error = open(&costly, b"secret").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,120 +1,1955 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, ErrorKind, Read};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use bitstream_io::{BigEndian, BitQueue, BitRead, BitReader, BitWrite, BitWriter, Endianness};
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWriter};
+use tracing::{debug, debug_span, info_span};
 
-#[derive(Debug)]
-enum Node {
-    Leaf(u32),
-    Joint(Box<Node>, Box<Node>),
+use crate::adaptive::AdaptiveCode;
+use crate::arithmetic::{Coder, Model, RangeDecoder, MAX_ARITHMETIC_LETTER_SIZE};
+use crate::bits::{BitSink, BitSource, SliceReader, SliceWriter};
+use crate::checksum::{Checksum, Hashing};
+use crate::context::Contexts;
+use crate::crypto::{Encryption, Opener};
+use crate::encode::{check_letter_size, LENGTH_CODE_BITS, TRAILING_BITS};
+use crate::filter::Filters;
+use crate::format::{
+    FormatInfo, ADAPTIVE, AES_GCM, ARITHMETIC, BASELINE_LETTER_SIZES, BWT, CODED_LENGTHS, EOS,
+    FORMAT_VERSIONS, INDEXED, KNOWN_FLAGS, LSB_FIRST, LZ77, MAGIC, MODE, MORE_FLAGS, MTIME, ORDER1,
+    RLE, UNKNOWN_SIZE,
+};
+use crate::limits::{LimitExceeded, Limits};
+use crate::lz77;
+use crate::progress::{Callback, Progress};
+use crate::report::{CompressionReport, Counter, Timings};
+use crate::tree::{CanonicalCode, CodeTable, Tree};
+
+pub(crate) fn decode_symbol<'a, S, R: BitSource>(
+    reader: &mut R,
+    tree: &'a Tree<S>,
+) -> Result<&'a S, std::io::Error> {
+    let mut node = tree;
+
+    loop {
+        match node {
+            Tree::Leaf(symbol) => return Ok(symbol),
+            Tree::Joint(left, right) => {
+                node = match reader.get_bit()? {
+                    false => left,
+                    true => right,
+                };
+            }
+        }
+    }
 }
 
-fn create_table(tree: &Node) -> HashMap<(u32, u32), u32> {
-    fn walk(root: &Node, table: &mut HashMap<(u32, u32), u32>, current_path: &mut Vec<bool>) {
-        match &root {
-            Node::Leaf(code) => {
-                let mut queue = BitQueue::<BigEndian, u32>::new();
+/// Decodes one letter of a canonical code, reading a bit at a time and comparing against the
+/// first code of every length instead of walking a tree.
+pub(crate) fn decode_canonical<R: BitSource>(
+    reader: &mut R,
+    code: &CanonicalCode,
+) -> Result<u32, std::io::Error> {
+    let counts = code.counts();
+    let mut value = 0u64;
+    let mut first = 0u64;
+    let mut index = 0;
 
-                for bit in current_path {
-                    match *bit {
-                        false => queue.push(1, 0),
-                        true => queue.push(1, 1),
-                    }
+    if counts.len() == 1 {
+        return Ok(code.symbols()[0]);
+    }
+
+    for &count in &counts[1..] {
+        value |= reader.get_bit()? as u64;
+
+        if value.wrapping_sub(first) < count as u64 {
+            return Ok(code.symbols()[index + (value - first) as usize]);
+        }
+
+        index += count as usize;
+        first = (first + count as u64) << 1;
+        value <<= 1;
+    }
+
+    Err(std::io::Error::new(ErrorKind::InvalidData, "invalid code"))
+}
+
+pub fn read_symbol<'a, S, R: BitRead>(
+    reader: &mut R,
+    tree: &'a Tree<S>,
+) -> Result<&'a S, std::io::Error> {
+    decode_symbol(reader, tree)
+}
+
+pub fn read_symbols<S: Clone, R: BitRead>(
+    reader: &mut R,
+    tree: &Tree<S>,
+    count: usize,
+) -> Result<Vec<S>, std::io::Error> {
+    let mut symbols = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        symbols.push(read_symbol(reader, tree)?.clone());
+    }
+
+    Ok(symbols)
+}
+
+/// Decodes symbols from a stream of bits. Trailing bits that do not complete a code are
+/// ignored, and a single-leaf tree yields nothing since its code carries no bits.
+pub fn decode_symbols<'a, S, I>(tree: &'a Tree<S>, bits: I) -> impl Iterator<Item = &'a S> + 'a
+where
+    I: IntoIterator<Item = bool>,
+    I::IntoIter: 'a,
+{
+    let mut bits = bits.into_iter();
+
+    std::iter::from_fn(move || {
+        if let Tree::Leaf(_) = tree {
+            return None;
+        }
+
+        let mut node = tree;
+
+        loop {
+            match node {
+                Tree::Leaf(symbol) => return Some(symbol),
+                Tree::Joint(left, right) => {
+                    node = match bits.next()? {
+                        false => left,
+                        true => right,
+                    };
                 }
+            }
+        }
+    })
+}
 
-                let len = queue.len();
-                table.insert((len, queue.value()), *code);
+/// Reads the tree of a baseline stream: the tree in pre-order, a 1 bit for every joint and a
+/// 0 bit followed by the letter for every leaf.
+fn read_baseline_tree<R: BitSource>(
+    reader: &mut R,
+    letter_size: u8,
+    limits: &Limits,
+) -> Result<Tree, std::io::Error> {
+    fn read_node<R: BitSource>(
+        reader: &mut R,
+        letter_size: u8,
+        limits: &Limits,
+        depth: u32,
+        nodes: &mut usize,
+    ) -> Result<Tree, std::io::Error> {
+        *nodes += 1;
+
+        if *nodes > limits.max_header_nodes {
+            return Err(LimitExceeded::HeaderNodes.into());
+        }
+
+        // Every node but the root lives in a box of its own.
+        if nodes.saturating_mul(size_of::<Tree>()) > limits.max_memory {
+            return Err(LimitExceeded::Memory.into());
+        }
+
+        match reader.get_bit()? {
+            false => {
+                let code = reader.get_bits(letter_size as u32)?;
+                Ok(Tree::Leaf(code))
             }
-            Node::Joint(left, right) => {
-                current_path.push(false);
-                walk(left, table, current_path);
-                current_path.pop();
+            true => {
+                if depth >= limits.max_tree_depth {
+                    return Err(LimitExceeded::TreeDepth.into());
+                }
 
-                current_path.push(true);
-                walk(right, table, current_path);
-                current_path.pop();
+                let left = read_node(reader, letter_size, limits, depth + 1, nodes)?;
+                let right = read_node(reader, letter_size, limits, depth + 1, nodes)?;
+
+                Ok(Tree::Joint(Box::new(left), Box::new(right)))
             }
         }
     }
 
-    let mut path = Vec::new();
-    let mut table = HashMap::new();
-    walk(tree, &mut table, &mut path);
+    read_node(reader, letter_size, limits, 0, &mut 0)
+}
 
-    table
+fn invalid_lengths() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "invalid code lengths")
 }
 
-fn read_header<R: BitRead>(reader: &mut R, letter_size: u8) -> Result<Node, std::io::Error> {
-    match reader.read_bit()? {
-        false => {
-            let code = reader.read::<u32>(letter_size as u32)?;
-            Ok(Node::Leaf(code))
+/// Reads the code lengths written by [`write_header`](crate::encode::write_header), with
+/// coded lists when `coded` is set, and checks that they form a complete code within `limits`.
+pub(crate) fn read_header<R: BitSource>(
+    reader: &mut R,
+    letter_size: u8,
+    limits: &Limits,
+    coded: bool,
+) -> Result<CanonicalCode, std::io::Error> {
+    let max_length = reader.get_bits(6)?;
+
+    if max_length > limits.max_tree_depth {
+        return Err(LimitExceeded::TreeDepth.into());
+    }
+
+    if max_length == 0 {
+        let symbol = reader.get_bits(letter_size as u32)?;
+        return Ok(CanonicalCode::from_lengths([(symbol, 0)]));
+    }
+
+    let letters = 1usize << letter_size;
+    let check_size = |symbols: usize| {
+        if symbols.saturating_mul(2).saturating_sub(1) > limits.max_header_nodes {
+            return Err(LimitExceeded::HeaderNodes.into());
+        }
+
+        if symbols.saturating_mul(size_of::<u32>()) > limits.max_memory {
+            return Err(LimitExceeded::Memory.into());
+        }
+
+        Ok::<_, std::io::Error>(())
+    };
+
+    let mut lengths = Vec::new();
+
+    // 0 picks the sparse list, 1 the dense one, or 11 the coded one when it is allowed.
+    let dense = reader.get_bit()?;
+    let coded = dense && coded && reader.get_bit()?;
+
+    if coded {
+        let (repeat, zeros, long_zeros) = (max_length + 1, max_length + 2, max_length + 3);
+        let mut code_lengths = Vec::new();
+
+        for symbol in 0..=long_zeros {
+            match reader.get_bits(LENGTH_CODE_BITS)? {
+                0 => {}
+                length => code_lengths.push((symbol, length)),
+            }
+        }
+
+        let length_code = CanonicalCode::from_lengths(code_lengths);
+
+        if length_code.len() < 2 || !length_code.is_complete() {
+            return Err(invalid_lengths());
+        }
+
+        let mut letter = 0;
+        let mut previous = None;
+
+        while letter < letters {
+            let (length, run) = match decode_canonical(reader, &length_code)? {
+                symbol if symbol == repeat => (
+                    previous.ok_or_else(invalid_lengths)?,
+                    3 + reader.get_bits(2)? as usize,
+                ),
+                symbol if symbol == zeros => (0, 3 + reader.get_bits(3)? as usize),
+                symbol if symbol == long_zeros => {
+                    (0, 11 + reader.get_bits(letter_size as u32)? as usize)
+                }
+                length => (length, 1),
+            };
+
+            if run > letters - letter {
+                return Err(invalid_lengths());
+            }
+
+            if length != 0 {
+                lengths.extend((letter..letter + run).map(|symbol| (symbol as u32, length)));
+                check_size(lengths.len())?;
+            }
+
+            letter += run;
+            previous = Some(length);
+        }
+    } else if dense {
+        let width = 32 - max_length.leading_zeros();
+
+        for symbol in 0..letters as u32 {
+            match reader.get_bits(width)? {
+                0 => {}
+                length if length > max_length => return Err(invalid_lengths()),
+                length => {
+                    lengths.push((symbol, length));
+                    check_size(lengths.len())?;
+                }
+            }
+        }
+    } else {
+        let mut counts = Vec::with_capacity(max_length as usize);
+
+        for _ in 0..max_length {
+            counts.push(reader.get_bits(letter_size as u32 + 1)? as usize);
+        }
+
+        let symbols = counts.iter().sum::<usize>();
+
+        if symbols == 0 || symbols > letters {
+            return Err(invalid_lengths());
+        }
+
+        check_size(symbols)?;
+
+        let mut seen = HashSet::with_capacity(symbols);
+
+        for (length, count) in (1..).zip(counts) {
+            for _ in 0..count {
+                let symbol = reader.get_bits(letter_size as u32)?;
+
+                if !seen.insert(symbol) {
+                    return Err(invalid_lengths());
+                }
+
+                lengths.push((symbol, length));
+            }
+        }
+    }
+
+    let code = CanonicalCode::from_lengths(lengths);
+
+    if code.max_length() != max_length || !code.is_complete() {
+        return Err(invalid_lengths());
+    }
+
+    Ok(code)
+}
+
+/// Reads the letters of `target_size` bits with `next`, writing them out.
+fn decode_letters<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    target_size: u64,
+    letter_size: u8,
+    progress: &mut Progress,
+    mut next: F,
+) -> Result<(), std::io::Error>
+where
+    R: BitSource,
+    W: BitSink,
+    F: FnMut(&mut R) -> Result<u32, std::io::Error>,
+{
+    let mut written = 0;
+
+    while written < target_size {
+        let value = next(reader)?;
+        writer.put_bits(letter_size as u32, value)?;
+        written += letter_size as u64;
+        progress.advance(letter_size as u64)?;
+    }
+
+    Ok(())
+}
+
+/// The fixed fields at the start of a stream.
+pub(crate) struct Prefix {
+    pub version: u8,
+    pub letter_size: u8,
+    pub checksum: Checksum,
+    pub encrypted: bool,
+    /// The flags bytes.
+    pub flags: u16,
+    pub file_size: u64,
+    /// Seconds since the Unix epoch, with the [`MTIME`] flag.
+    pub mtime: Option<u64>,
+    /// Unix permission bits, with the [`MODE`] flag.
+    pub mode: Option<u32>,
+}
+
+impl Prefix {
+    /// Whether a block index follows the checksum.
+    pub fn indexed(&self) -> bool {
+        self.flags & INDEXED != 0
+    }
+
+    /// Cipher of encrypted streams.
+    pub fn encryption(&self) -> Option<Encryption> {
+        match (self.encrypted, self.flags & AES_GCM) {
+            (false, _) => None,
+            (true, 0) => Some(Encryption::ChaCha20Poly1305),
+            (true, _) => Some(Encryption::Aes256Gcm),
+        }
+    }
+
+    pub fn filters(&self) -> Filters {
+        Filters::from_flags(self.flags)
+    }
+
+    /// Whether the blocks are coded with an adaptive code.
+    pub fn adaptive(&self) -> bool {
+        self.flags & ADAPTIVE != 0
+    }
+
+    /// Whether the blocks are coded as LZ77 matches and literals.
+    pub fn lz77(&self) -> bool {
+        self.flags & LZ77 != 0
+    }
+
+    /// Whether every letter is coded with a code for the letter before it.
+    pub fn order1(&self) -> bool {
+        self.flags & ORDER1 != 0
+    }
+
+    /// Whether the last block ends with an end-of-stream letter.
+    pub fn eos(&self) -> bool {
+        self.flags & EOS != 0
+    }
+
+    /// Whether the bits of payloads are packed from the lowest bit of every byte up.
+    pub fn lsb_first(&self) -> bool {
+        self.flags & LSB_FIRST != 0
+    }
+
+    /// Whether code lengths may be stored as a coded list.
+    pub fn coded_lengths(&self) -> bool {
+        self.flags & CODED_LENGTHS != 0
+    }
+
+    pub fn coder(&self) -> Coder {
+        match self.flags & ARITHMETIC {
+            0 => Coder::Huffman,
+            _ => Coder::Arithmetic,
+        }
+    }
+
+    /// Whether the stream was written without knowing its size.
+    pub fn streamed(&self) -> bool {
+        self.file_size == UNKNOWN_SIZE
+    }
+
+    /// Whether every block carries its original length, as those of streamed input without
+    /// an end-of-stream letter do.
+    pub fn framed(&self) -> bool {
+        self.streamed() && !self.eos()
+    }
+
+    pub fn format_info(&self) -> FormatInfo {
+        FormatInfo {
+            version: self.version,
+            letter_size: self.letter_size,
+            checksum: self.checksum,
+            encrypted: self.encrypted,
+            encryption: self.encryption(),
+            indexed: self.indexed(),
+            adaptive: self.adaptive(),
+            lz77: self.lz77(),
+            coder: self.coder(),
+            order1: self.order1(),
+            eos: self.eos(),
+            lsb_first: self.lsb_first(),
+            rle: self.filters().rle,
+            bwt: self.filters().bwt,
+            original_size: self.file_size,
+            mtime: self
+                .mtime
+                .and_then(|seconds| UNIX_EPOCH.checked_add(Duration::from_secs(seconds))),
+            mode: self.mode,
         }
-        true => {
-            let left = read_header(reader, letter_size)?;
-            let right = read_header(reader, letter_size)?;
+    }
+}
+
+/// Checks the magic number and version, then reads the letter size byte, whose bits 5 and 6
+/// name the checksum and whose top bit marks encrypted streams, the flags byte from version 5
+/// on, the original size and the modification time and permission bits if the flags say so.
+pub(crate) fn read_prefix<R: BitSource>(reader: &mut R) -> Result<Prefix, std::io::Error> {
+    let first = reader.get_bits(8)? as u8;
+    read_prefix_after(reader, first)
+}
+
+/// [`read_prefix`] for a stream whose `first` byte was read already.
+fn read_prefix_after<R: BitSource>(reader: &mut R, first: u8) -> Result<Prefix, std::io::Error> {
+    let mut magic = [first, 0, 0, 0];
+
+    for byte in &mut magic[1..] {
+        *byte = reader.get_bits(8)? as u8;
+    }
+
+    if &magic != MAGIC {
+        let message = match BASELINE_LETTER_SIZES.contains(&first) {
+            true => "not a huffman-code stream, or a baseline stream, which only decompress reads",
+            false => "not a huffman-code stream",
+        };
+        return Err(std::io::Error::new(ErrorKind::InvalidData, message));
+    }
+
+    let version = reader.get_bits(8)? as u8;
+
+    if !FORMAT_VERSIONS.contains(&version) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "format version {} is not supported, expected {}",
+                version,
+                FORMAT_VERSIONS.end()
+            ),
+        ));
+    }
+
+    let byte = reader.get_bits(8)? as u8;
+    let letter_size = byte & 0x1f;
 
-            Ok(Node::Joint(Box::new(left), Box::new(right)))
+    if check_letter_size(letter_size).is_err() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid letter size",
+        ));
+    }
+
+    let mut flags = reader.get_bits(8)? as u16;
+
+    if flags & MORE_FLAGS != 0 {
+        flags |= (reader.get_bits(8)? as u16) << 8;
+    }
+
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown format flags {:#06x}", flags & !KNOWN_FLAGS),
+        ));
+    }
+
+    if flags & ORDER1 != 0 && flags & (ADAPTIVE | LZ77 | ARITHMETIC) != 0
+        || flags & EOS != 0 && flags & (ADAPTIVE | LZ77 | ARITHMETIC | ORDER1 | RLE | BWT) != 0
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid format flags",
+        ));
+    }
+
+    if flags & LZ77 != 0 && letter_size != 8
+        || flags & ARITHMETIC != 0 && letter_size > MAX_ARITHMETIC_LETTER_SIZE
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid letter size",
+        ));
+    }
+
+    Ok(Prefix {
+        version,
+        letter_size,
+        checksum: Checksum::from_id(byte >> 5 & 0b11)?,
+        encrypted: byte & 0x80 != 0,
+        flags,
+        file_size: reader.get_u64()?,
+        mtime: match flags & MTIME {
+            0 => None,
+            _ => Some(reader.get_u64()?),
+        },
+        mode: match flags & MODE {
+            0 => None,
+            _ => Some(reader.get_bits(32)?),
+        },
+    })
+}
+
+/// Reads the fixed fields of a stream following another one, `None` at the end of the input.
+fn next_prefix<R: BitSource>(reader: &mut R) -> Result<Option<Prefix>, std::io::Error> {
+    match reader.get_bits(8) {
+        Ok(first) if first as u8 != MAGIC[0] => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "unexpected data after the compressed stream",
+        )),
+        Ok(first) => read_prefix_after(reader, first as u8).map(Some),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the digest stored after the payload and compares it with `digest`.
+fn check_digest<R: BitSource>(reader: &mut R, digest: &[u8]) -> Result<(), std::io::Error> {
+    reader.align();
+
+    for &byte in digest {
+        if reader.get_bits(8)? as u8 != byte {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "checksum mismatch, the data is corrupt",
+            ));
         }
     }
+
+    Ok(())
 }
 
-fn decompress<R: Read, W: BitWrite, E: Endianness>(
-    reader: &mut BitReader<R, E>,
+/// Decodes a baseline stream, written before streams had a magic number, after its letter
+/// size byte: the original size as a big endian `u64`, then unless it is empty the tree as
+/// [`read_baseline_tree`] reads it, the codes of its letters and the bits left over.
+fn decode_baseline<R: BitSource, W: BitSink>(
+    reader: &mut R,
     writer: &mut W,
+    letter_size: u8,
+    limits: &Limits,
+    progress: &mut Progress,
+    report: &mut CompressionReport,
 ) -> Result<(), std::io::Error> {
-    let letter_size = reader.read::<u8>(8)?;
-    let file_size = reader.read::<u64>(64)? * 8;
+    let file_size = reader.get_u64()?;
+
+    if file_size > limits.max_output_size {
+        return Err(LimitExceeded::OutputSize.into());
+    }
+
+    let file_size = file_size
+        .checked_mul(8)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "invalid file size"))?;
+    progress.set_total(file_size / 8);
+    report.letter_size = letter_size;
+    report.output_size = file_size / 8;
     let target_size = letter_size as u64 * (file_size / letter_size as u64);
     let remaining_size = file_size - target_size;
 
-    let mut written = 0;
+    let mut phase = Instant::now();
+
+    if target_size != 0 {
+        let tree = debug_span!("read_header")
+            .in_scope(|| read_baseline_tree(reader, letter_size, limits))?;
+        report.timings.header = phase.elapsed();
+
+        (report.symbol_count, report.tree_depth) = (tree.leaf_count(), tree.depth());
+        debug!(
+            symbols = report.symbol_count,
+            depth = report.tree_depth,
+            "read tree"
+        );
+        phase = Instant::now();
+
+        let _span = debug_span!("decode").entered();
 
-    let table = match read_header(reader, letter_size) {
-        Err(e) => match e.kind() {
-            ErrorKind::UnexpectedEof => return Ok(()),
-            _ => return Err(e),
+        decode_letters(
+            reader,
+            writer,
+            target_size,
+            letter_size,
+            progress,
+            |reader| decode_symbol(reader, &tree).copied(),
+        )?;
+    }
+
+    if remaining_size != 0 {
+        let value = reader.get_bits(remaining_size as u32)?;
+        writer.put_bits(remaining_size as u32, value)?;
+        progress.advance(remaining_size)?;
+    }
+
+    reader.align();
+    report.timings.coding = phase.elapsed();
+
+    Ok(())
+}
+
+/// Largest payload accepted for a block of `block_len` bytes, well above what any code can
+/// make of it, so a corrupt length cannot ask for gigabytes.
+pub(crate) fn max_payload(block_len: usize) -> usize {
+    block_len.saturating_mul(7).saturating_add(256 * 1024)
+}
+
+/// Reads the block size of a blocked stream and checks it against `limits`.
+fn read_block_size<R: BitSource>(
+    reader: &mut R,
+    prefix: &Prefix,
+    limits: &Limits,
+) -> Result<u64, std::io::Error> {
+    let block_size = reader.get_bits(32)? as u64;
+
+    if block_size == 0 || !(block_size * 8).is_multiple_of(prefix.letter_size as u64) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid block size",
+        ));
+    }
+
+    if block_size.min(prefix.file_size) > limits.max_memory as u64 {
+        return Err(LimitExceeded::Memory.into());
+    }
+
+    Ok(block_size)
+}
+
+/// What a block stores to code its letters and the blocks after it that store nothing.
+#[derive(Clone)]
+pub(crate) enum StoredCode {
+    Canonical(CanonicalCode),
+    Model(Model),
+    Contexts(Contexts),
+}
+
+impl StoredCode {
+    fn len(&self) -> usize {
+        match self {
+            StoredCode::Canonical(code) => code.len(),
+            StoredCode::Model(model) => model.len(),
+            StoredCode::Contexts(contexts) => contexts.len(),
+        }
+    }
+
+    /// Longest code, 0 for models, whose letters take no whole number of bits.
+    fn max_length(&self) -> u32 {
+        match self {
+            StoredCode::Canonical(code) => code.max_length(),
+            StoredCode::Model(_) => 0,
+            StoredCode::Contexts(contexts) => contexts.max_length(),
+        }
+    }
+}
+
+/// Reads the code stored with a block of a stream starting with `prefix`.
+fn read_stored_code<R: BitSource>(
+    reader: &mut R,
+    prefix: &Prefix,
+    limits: &Limits,
+) -> Result<StoredCode, std::io::Error> {
+    match prefix.coder() {
+        Coder::Huffman if prefix.order1() => {
+            Contexts::read(reader, prefix.letter_size, limits, prefix.coded_lengths())
+                .map(StoredCode::Contexts)
+        }
+        Coder::Huffman => {
+            let letter_size = prefix.letter_size + prefix.eos() as u8;
+            read_header(reader, letter_size, limits, prefix.coded_lengths())
+                .map(StoredCode::Canonical)
+        }
+        Coder::Arithmetic => Model::read(reader, prefix.letter_size).map(StoredCode::Model),
+    }
+}
+
+/// A block as stored: its number, whether a code comes first in its payload or the payload
+/// is the block as it is, its original length, or the most it can be when an end-of-stream
+/// letter ends it, the CRC32 of its original bytes unless the stream has no checksum, and
+/// the payload.
+struct Frame {
+    index: u64,
+    has_code: bool,
+    stored: bool,
+    len: usize,
+    crc: Option<u32>,
+    payload: Vec<u8>,
+}
+
+/// Reads the frame of block `index`, which is `block_len` bytes long, or at most that for
+/// streamed input.
+fn read_frame<R: BitSource>(
+    reader: &mut R,
+    prefix: &Prefix,
+    index: u64,
+    block_len: usize,
+) -> Result<Frame, std::io::Error> {
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid frame for block {}", index),
+        )
+    };
+
+    let flags = reader.get_bits(8)?;
+    let block_len = match prefix.framed() {
+        true => Some(reader.get_bits(32)? as usize).filter(|&len| len <= block_len),
+        false => Some(block_len),
+    }
+    .ok_or_else(invalid)?;
+    let payload_len = reader.get_bits(32)? as usize;
+
+    let codeless = prefix.adaptive() || prefix.lz77();
+
+    if flags > 2
+        || flags == 1 && codeless
+        || flags == 2 && payload_len != block_len && (prefix.framed() || !prefix.streamed())
+        || flags == 2 && payload_len > block_len
+        || payload_len > max_payload(block_len)
+    {
+        return Err(invalid());
+    }
+
+    let crc = match prefix.checksum {
+        Checksum::None => None,
+        _ => Some(reader.get_bits(32)?),
+    };
+
+    let mut payload = vec![0; payload_len];
+    reader.get_bytes(&mut payload)?;
+
+    // Decoding reads bits from the highest of every byte down.
+    if prefix.lsb_first() && flags != 2 {
+        payload
+            .iter_mut()
+            .for_each(|byte| *byte = byte.reverse_bits());
+    }
+
+    Ok(Frame {
+        index,
+        has_code: flags & 1 != 0,
+        stored: flags & 2 != 0,
+        len: match flags {
+            2 => payload_len,
+            _ => block_len,
         },
-        Ok(tree) => create_table(&tree),
+        crc,
+        payload,
+    })
+}
+
+/// Decodes `frame` into `block`, which holds the length of the frame and is cut to where an
+/// end-of-stream letter ends it. A frame storing a code replaces `code`, one without uses
+/// it, or `tree` when there is none. Adaptive and LZ77 streams use neither.
+fn decode_frame(
+    frame: &Frame,
+    block: &mut Vec<u8>,
+    prefix: &Prefix,
+    code: &mut Option<StoredCode>,
+    tree: Option<&Tree>,
+    limits: &Limits,
+    timings: &mut Timings,
+) -> Result<(), std::io::Error> {
+    let index = frame.index;
+    let filters = prefix.filters();
+    let phase = Instant::now();
+    let mut payload = SliceReader::new(&frame.payload);
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid filtered data in block {}", index),
+        )
+    };
+
+    if frame.stored {
+        block.copy_from_slice(&frame.payload);
+        timings.coding += phase.elapsed();
+        return check_frame_crc(frame, block);
+    }
+
+    // Filtered blocks are decoded into a buffer of their filtered length first.
+    let mut filtered = match filters.is_empty() {
+        true => None,
+        false => {
+            let len = payload.get_bits(32)? as usize;
+
+            if len > filters.max_len(block.len()) {
+                return Err(invalid());
+            }
+
+            Some(vec![0; len])
+        }
     };
 
-    let mut buffer = 0;
-    let mut iteration = 0;
+    if frame.has_code {
+        *code = Some(
+            debug_span!("read_header", block = index)
+                .in_scope(|| read_stored_code(&mut payload, prefix, limits))?,
+        );
+    }
+    timings.header += phase.elapsed();
 
-    while written < target_size {
-        let bit = reader.read_bit()?;
-        buffer = (buffer << 1) | bit as u32;
-        iteration += 1;
+    let phase = Instant::now();
+    let target = match &mut filtered {
+        Some(filtered) => filtered.as_mut_slice(),
+        None => block.as_mut_slice(),
+    };
+
+    if prefix.lz77() {
+        lz77::decode_block(&mut payload, target, limits, prefix.coded_lengths())?;
+    } else if prefix.eos() {
+        let len = decode_to_end(&mut payload, target, prefix.letter_size, code, index)?;
 
-        if let Some(value) = table.get(&(iteration, buffer)) {
-            writer.write(letter_size as u32, *value)?;
-            written += letter_size as u64;
-            buffer = 0;
-            iteration = 0;
+        // Only blocks of streamed input may end before the length they were read with.
+        if len != block.len() && !prefix.streamed() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("block {} ends early", index),
+            ));
         }
+
+        block.truncate(len);
+    } else {
+        decode_letters_into(&mut payload, target, prefix, code, tree, index)?;
     }
 
-    if remaining_size != 0 {
-        let value = reader.read::<u32>(remaining_size as u32)?;
-        writer.write(remaining_size as u32, value)?;
+    if let Some(filtered) = &filtered {
+        filters.undo(filtered, block).ok_or_else(invalid)?;
+    }
+
+    timings.coding += phase.elapsed();
+
+    check_frame_crc(frame, block)
+}
+
+/// Checks `block`, decoded from `frame`, against the CRC32 of the frame.
+fn check_frame_crc(frame: &Frame, block: &[u8]) -> Result<(), std::io::Error> {
+    if frame.crc.is_some_and(|crc| crc32fast::hash(block) != crc) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch in block {}, the data is corrupt",
+                frame.index
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decodes the letters of block `index` from `payload` into `target` with the code of the
+/// block, or `tree` when there is none, then the bits of a trailing partial letter.
+fn decode_letters_into(
+    payload: &mut SliceReader,
+    target: &mut [u8],
+    prefix: &Prefix,
+    code: &Option<StoredCode>,
+    tree: Option<&Tree>,
+    index: u64,
+) -> Result<(), std::io::Error> {
+    let letter_size = prefix.letter_size;
+    let bits = target.len() as u64 * 8;
+    let letters = bits / letter_size as u64 * letter_size as u64;
+    let remaining = bits - letters;
+    let mut output = SliceWriter::new(target);
+    let mut disabled = Progress::disabled();
+
+    if letters != 0 {
+        match (code, tree) {
+            _ if prefix.adaptive() => {
+                let mut code = AdaptiveCode::new(letter_size);
+                decode_letters(
+                    payload,
+                    &mut output,
+                    letters,
+                    letter_size,
+                    &mut disabled,
+                    |reader| code.decode(reader),
+                )?
+            }
+            (Some(StoredCode::Canonical(code)), _) => decode_letters(
+                payload,
+                &mut output,
+                letters,
+                letter_size,
+                &mut disabled,
+                |reader| decode_canonical(reader, code),
+            )?,
+            (Some(StoredCode::Contexts(contexts)), _) => {
+                let mut previous = 0;
+                decode_letters(
+                    payload,
+                    &mut output,
+                    letters,
+                    letter_size,
+                    &mut disabled,
+                    |reader| {
+                        previous = contexts.decode(reader, previous, letter_size)?;
+                        Ok(previous)
+                    },
+                )?
+            }
+            (Some(StoredCode::Model(model)), _) => {
+                let mut decoder = RangeDecoder::start(payload)?;
+                decode_letters(
+                    payload,
+                    &mut output,
+                    letters,
+                    letter_size,
+                    &mut disabled,
+                    |reader| decoder.decode(reader, model),
+                )?
+            }
+            (None, Some(tree)) if prefix.coder() == Coder::Huffman => decode_letters(
+                payload,
+                &mut output,
+                letters,
+                letter_size,
+                &mut disabled,
+                |reader| decode_symbol(reader, tree).copied(),
+            )?,
+            (None, _) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "block {} has no code to decode it with, it needs the code table it was compressed with",
+                        index
+                    ),
+                ))
+            }
+        }
+    }
+
+    if remaining != 0 {
+        let value = payload.get_bits(remaining as u32)?;
+        output.put_bits(remaining as u32, value)?;
     }
 
     Ok(())
 }
 
+/// Decodes the letters of block `index` from `payload` into `target` with the code of the
+/// block until `target` is full or the end-of-stream letter comes, then the bits of a
+/// trailing partial letter after it. Returns the length of the block.
+fn decode_to_end(
+    payload: &mut SliceReader,
+    target: &mut [u8],
+    letter_size: u8,
+    code: &Option<StoredCode>,
+    index: u64,
+) -> Result<usize, std::io::Error> {
+    let Some(StoredCode::Canonical(code)) = code else {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("block {} has no code to decode it with", index),
+        ));
+    };
+    let invalid = || {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid end of block {}", index),
+        )
+    };
+
+    let end = 1 << letter_size;
+    let size = target.len() as u64 * 8;
+    let mut output = SliceWriter::new(target);
+    let mut bits = 0;
+
+    while bits < size {
+        match decode_canonical(payload, code)? {
+            letter if letter < end && bits + letter_size as u64 <= size => {
+                output.put_bits(letter_size as u32, letter)?;
+                bits += letter_size as u64;
+            }
+            letter if letter == end => {
+                let remaining = payload.get_bits(TRAILING_BITS)?;
+
+                if remaining >= letter_size as u32
+                    || bits + remaining as u64 > size
+                    || !(bits + remaining as u64).is_multiple_of(8)
+                {
+                    return Err(invalid());
+                }
+
+                if remaining != 0 {
+                    output.put_bits(remaining, payload.get_bits(remaining)?)?;
+                }
+
+                return Ok(((bits + remaining as u64) / 8) as usize);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(target.len())
+}
+
+/// Decodes the blocks of a stream: the block size as a big endian `u32`,
+/// then for every block a flags byte whose lowest bit marks a code stored with it, the
+/// payload length as a big endian `u32`, the CRC32 of the block unless the stream has no
+/// checksum, and the payload. A block without a code uses the code of the one before it.
+/// Blocks of streamed input have their original length after the flags byte and end with
+/// the first one shorter than the block size. Damaged blocks are handled as `decoding` says.
+/// Returns the number of blocks.
+fn decode_blocks<R: BitSource, W: BitSink>(
+    reader: &mut R,
+    writer: &mut W,
+    prefix: &Prefix,
+    decoding: &mut Decoding,
+    progress: &mut Progress,
+    report: &mut CompressionReport,
+) -> Result<u64, std::io::Error> {
+    let (tree, limits) = (decoding.tree, decoding.limits);
+    let streamed = prefix.streamed();
+    let file_size = prefix.file_size;
+
+    if !streamed && file_size > limits.max_output_size {
+        return Err(LimitExceeded::OutputSize.into());
+    }
+
+    if !streamed {
+        progress.set_total(file_size);
+    }
+
+    report.letter_size = prefix.letter_size;
+
+    let block_size = read_block_size(reader, prefix, limits)?;
+    let mut code = None;
+    let mut block = Vec::new();
+    let mut written = 0;
+    let mut index = 0;
+
+    while streamed || written < file_size {
+        let block_len = block_size.min(file_size - written) as usize;
+        // Nothing after a frame that cannot be read can be found.
+        let frame = match read_frame(reader, prefix, index, block_len) {
+            Ok(frame) => frame,
+            Err(e) => {
+                decoding.damaged(index, written, None, e)?;
+                break;
+            }
+        };
+
+        if written + frame.len as u64 > limits.max_output_size && !prefix.eos() {
+            return Err(LimitExceeded::OutputSize.into());
+        }
+
+        block.clear();
+        block.resize(frame.len, 0);
+
+        if let Err(e) = decode_frame(
+            &frame,
+            &mut block,
+            prefix,
+            &mut code,
+            tree,
+            limits,
+            &mut report.timings,
+        ) {
+            // Blocks ended by an end-of-stream letter have no known length to skip.
+            let len = (!prefix.eos() || !streamed).then_some(frame.len);
+
+            match decoding.damaged(index, written, len, e)? {
+                Some(len) => {
+                    block.clear();
+                    block.resize(len, 0);
+                }
+                None => break,
+            }
+        }
+
+        let block_len = block.len();
+
+        // Blocks ended by an end-of-stream letter are only known to fit once decoded.
+        if written + block_len as u64 > limits.max_output_size {
+            return Err(LimitExceeded::OutputSize.into());
+        }
+
+        if let Some(code) = code.as_ref().filter(|_| frame.has_code) {
+            report.symbol_count = report.symbol_count.max(code.len());
+            report.tree_depth = report.tree_depth.max(code.max_length());
+        }
+
+        writer.put_bytes(&block)?;
+        progress.advance(block_len as u64 * 8)?;
+        written += block_len as u64;
+        index += 1;
+
+        if (block_len as u64) < block_size {
+            break;
+        }
+    }
+
+    report.output_size = written;
+
+    debug!(
+        symbols = report.symbol_count,
+        depth = report.tree_depth,
+        "decoded blocks"
+    );
+
+    Ok(index)
+}
+
+/// Blocks at the start of an unfinished stream that decode to the input they were written
+/// for.
+#[derive(Default)]
+pub(crate) struct Kept {
+    /// Offsets of the blocks from the start of the stream.
+    pub offsets: Vec<u64>,
+    /// Offset of the end of the last of them, 0 when there are none.
+    pub end: u64,
+    /// The code stored with the first of them.
+    pub code: Option<StoredCode>,
+}
+
+/// Reads back the blocks of the unfinished stream in `output`, which starts with `prefix` and
+/// the block size and continues at `start`, keeping those that are whole and decode to the
+/// next bytes of `input` up to the first that is not. With `shared` set only the first block
+/// may store a code, the one the blocks after it are coded with.
+pub(crate) fn keep_blocks<F: Read, R: Read>(
+    output: F,
+    mut input: R,
+    prefix: &Prefix,
+    start: u64,
+    block_size: u64,
+    tree: Option<&Tree>,
+    shared: bool,
+) -> Result<Kept, std::io::Error> {
+    let mut reader = BitReader::endian(Counter::new(output), BigEndian);
+    let limits = Limits::unlimited();
+    let mut kept = Kept::default();
+    let mut code = None;
+    let mut block = Vec::new();
+    let mut original = Vec::new();
+    let mut position = start;
+    let mut written = 0;
+    let mut index = 0;
+
+    while written < prefix.file_size {
+        let block_len = block_size.min(prefix.file_size - written) as usize;
+        let Ok(frame) = read_frame(&mut reader, prefix, index, block_len) else {
+            break;
+        };
+
+        if shared && index != 0 && frame.has_code {
+            break;
+        }
+
+        block.clear();
+        block.resize(frame.len, 0);
+        let decoded = decode_frame(
+            &frame,
+            &mut block,
+            prefix,
+            &mut code,
+            tree,
+            &limits,
+            &mut Timings::default(),
+        );
+
+        original.resize(block.len(), 0);
+
+        if decoded.is_err() || input.read_exact(&mut original).is_err() || original != block {
+            break;
+        }
+
+        if index == 0 {
+            kept.code = code.clone();
+        }
+
+        // Frames are whole bytes, so the reader is aligned after every one.
+        kept.offsets.push(position);
+        position = start + reader.reader().unwrap().count();
+        kept.end = position;
+        written += block.len() as u64;
+        index += 1;
+    }
+
+    debug!(blocks = index, "kept blocks");
+
+    Ok(kept)
+}
+
+/// Reads the block index at the end of `input`, a stream starting at `start` with `blocks`
+/// blocks, checking that it fits there.
+fn read_index<R: Read + Seek>(
+    input: &mut R,
+    start: u64,
+    blocks: u64,
+) -> Result<Vec<u64>, std::io::Error> {
+    let invalid = || std::io::Error::new(ErrorKind::InvalidData, "invalid block index");
+
+    let end = input.seek(SeekFrom::End(-8)).map_err(|_| invalid())?;
+    let mut position = [0; 8];
+    input.read_exact(&mut position)?;
+    let position = start
+        .checked_add(u64::from_be_bytes(position))
+        .ok_or_else(invalid)?;
+
+    if end.checked_sub(position) != blocks.checked_mul(8) {
+        return Err(invalid());
+    }
+
+    input.seek(SeekFrom::Start(position))?;
+    let mut index = vec![0; blocks as usize * 8];
+    input.read_exact(&mut index)?;
+
+    let offsets = index
+        .chunks_exact(8)
+        .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()))
+        .collect::<Vec<_>>();
+
+    if offsets.iter().any(|&offset| offset >= position - start) {
+        return Err(invalid());
+    }
+
+    Ok(offsets)
+}
+
+/// Reads past the checksum of output that damaged blocks were skipped in, which it cannot
+/// match.
+fn skip_digest<R: BitSource>(reader: &mut R, len: usize) -> Result<(), std::io::Error> {
+    reader.align();
+
+    for _ in 0..len {
+        reader.get_bits(8)?;
+    }
+
+    Ok(())
+}
+
+/// Reads past the block index stored after the checksum: the offset of every block and the
+/// offset of the index itself, all as big endian `u64`s.
+fn skip_index<R: BitSource>(reader: &mut R, blocks: u64) -> Result<(), std::io::Error> {
+    for _ in 0..=blocks {
+        reader.get_u64()?;
+    }
+
+    Ok(())
+}
+
+/// What decoding does with a damaged block, one that fails its checksum or does not decode.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BadBlocks {
+    /// Fail with the error of the block.
+    #[default]
+    Fail,
+    /// End the output before the block, keeping what the blocks before it decoded to.
+    Stop,
+    /// Write zeros in place of the block and go on with the next one. Blocks whose frame
+    /// cannot be read, or whose length is only known from an end-of-stream letter, still end
+    /// the output.
+    Skip,
+}
+
+/// A damaged block, as passed to the callback given with [`Decompressor::bad_blocks`].
+#[derive(Debug)]
+pub struct BadBlock {
+    /// Number of the stream among streams written one after another, from 0.
+    pub stream: u64,
+    /// Number of the block in its stream, from 0.
+    pub index: u64,
+    /// Offset of the block in the output.
+    pub offset: u64,
+    /// Bytes of zeros written in place of the block, `None` when the output ends before it.
+    pub skipped: Option<u64>,
+    /// What is wrong with the block.
+    pub error: std::io::Error,
+}
+
+type BadBlockCallback<'a> = Option<Box<dyn FnMut(BadBlock) + 'a>>;
+
+/// What decoding a stream goes by besides the stream itself, and the damaged blocks found in
+/// it.
+struct Decoding<'d, 'a> {
+    tree: Option<&'d Tree>,
+    limits: &'d Limits,
+    bad_blocks: BadBlocks,
+    on_bad_block: &'d mut BadBlockCallback<'a>,
+    stream: u64,
+    /// Offset of the output of the stream in the whole output.
+    start: u64,
+    damaged: u64,
+    /// Whether a damaged block ended the output.
+    stopped: bool,
+}
+
+impl Decoding<'_, '_> {
+    /// Handles `error` in block `index` at `offset` of the output of the stream, returning
+    /// the error unless damaged blocks are stopped at or skipped. Returns the number of zeros
+    /// to write in place of the block, `None` when the output ends before it. Blocks are
+    /// skipped only when their length `len` is known.
+    fn damaged(
+        &mut self,
+        index: u64,
+        offset: u64,
+        len: Option<usize>,
+        error: std::io::Error,
+    ) -> Result<Option<usize>, std::io::Error> {
+        let corrupt = matches!(
+            error.kind(),
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof
+        );
+
+        if self.bad_blocks == BadBlocks::Fail
+            || !corrupt
+            || LimitExceeded::from_error(&error).is_some()
+        {
+            return Err(error);
+        }
+
+        let len = len.filter(|_| self.bad_blocks == BadBlocks::Skip);
+        self.damaged += 1;
+        self.stopped = len.is_none();
+
+        if let Some(callback) = self.on_bad_block {
+            callback(BadBlock {
+                stream: self.stream,
+                index,
+                offset: self.start + offset,
+                skipped: len.map(|len| len as u64),
+                error,
+            });
+        }
+
+        Ok(len)
+    }
+}
+
+#[derive(Default)]
+pub struct Decompressor<'a> {
+    table: Option<CodeTable>,
+    password: Option<&'a [u8]>,
+    progress: Callback<'a>,
+    cancel: Option<&'a AtomicBool>,
+    limits: Option<Limits>,
+    bad_blocks: BadBlocks,
+    on_bad_block: BadBlockCallback<'a>,
+}
+
+impl<'a> Decompressor<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes input produced by a `Compressor` configured with the same static table.
+    pub fn code_table(mut self, table: CodeTable) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    /// Registers a callback receiving `(bytes_written, original_size)` periodically while decoding.
+    pub fn progress<F: FnMut(u64, u64) + 'a>(mut self, callback: F) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Checks `flag` periodically and aborts with a [`Cancelled`](crate::Cancelled) error
+    /// once it is set.
+    pub fn cancel_flag(mut self, flag: &'a AtomicBool) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Decrypts input compressed with the same password. Input that is not encrypted is
+    /// read as usual.
+    pub fn password(mut self, password: &'a [u8]) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Rejects input breaking `limits` with a [`LimitExceeded`] error instead of decoding it.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Stops at or skips blocks that fail their checksum or do not decode, as `handling`
+    /// says, instead of failing, and calls `callback` with every such block. The checksum of
+    /// the whole output is not checked once a block was skipped, and nothing after a block
+    /// decoding stopped at is read.
+    pub fn bad_blocks<F: FnMut(BadBlock) + 'a>(mut self, handling: BadBlocks, callback: F) -> Self {
+        self.bad_blocks = handling;
+        self.on_bad_block = Some(Box::new(callback));
+        self
+    }
+
+    fn static_tree(&self) -> Result<Option<Tree>, std::io::Error> {
+        match &self.table {
+            Some(table) => match Tree::from_code_table(table) {
+                Some(tree) => Ok(Some(tree)),
+                None => Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "code table is not a complete prefix code",
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes `input` into `output`. Streams written one after another, as by `cat`, are all
+    /// decoded in turn, so the output is their originals one after another.
+    pub fn decompress<R: Read, W: Write>(
+        &mut self,
+        input: R,
+        output: W,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let now = Instant::now();
+        let _span = info_span!("decompress").entered();
+        let tree = self.static_tree()?;
+
+        let limits = self.limits.unwrap_or_else(Limits::unlimited);
+        let mut input = Counter::new(input);
+        let mut output = output;
+        let mut report = CompressionReport::default();
+
+        for member in 0.. {
+            let mut opener = Opener::new(&mut input);
+            let mut reader = BitReader::endian(&mut opener, BigEndian);
+
+            let prefix = match member {
+                0 => match reader.get_bits(8)? as u8 {
+                    first if BASELINE_LETTER_SIZES.contains(&first) => {
+                        let mut writer = BitWriter::endian(&mut output, BigEndian);
+                        let mut progress = Progress::new(&mut self.progress, self.cancel, 0);
+                        decode_baseline(
+                            &mut reader,
+                            &mut writer,
+                            first,
+                            &limits,
+                            &mut progress,
+                            &mut report,
+                        )?;
+                        progress.finish();
+                        continue;
+                    }
+                    first => read_prefix_after(&mut reader, first)?,
+                },
+                _ => match next_prefix(&mut reader)? {
+                    Some(prefix) => prefix,
+                    None => break,
+                },
+            };
+
+            if let Some(encryption) = prefix.encryption() {
+                let password = self.password.ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "input is encrypted, a password is needed",
+                    )
+                })?;
+                // The prefix is whole bytes, so the reader is aligned.
+                reader.reader().unwrap().start(encryption, password)?;
+            }
+
+            let mut hashing = Hashing::new(&mut output, prefix.checksum)?;
+            let mut writer = BitWriter::endian(&mut hashing, BigEndian);
+
+            let mut progress = Progress::new(&mut self.progress, self.cancel, 0);
+            let mut member_report = CompressionReport::default();
+            let limits = limits.max_output_size(limits.max_output_size - report.output_size);
+
+            let mut decoding = Decoding {
+                tree: tree.as_ref(),
+                limits: &limits,
+                bad_blocks: self.bad_blocks,
+                on_bad_block: &mut self.on_bad_block,
+                stream: member,
+                start: report.output_size,
+                damaged: 0,
+                stopped: false,
+            };
+
+            let blocks = decode_blocks(
+                &mut reader,
+                &mut writer,
+                &prefix,
+                &mut decoding,
+                &mut progress,
+                &mut member_report,
+            )?;
+
+            report.letter_size = member_report.letter_size;
+            report.output_size += member_report.output_size;
+            report.symbol_count = report.symbol_count.max(member_report.symbol_count);
+            report.tree_depth = report.tree_depth.max(member_report.tree_depth);
+            report.timings.header += member_report.timings.header;
+            report.timings.coding += member_report.timings.coding;
+
+            if decoding.stopped {
+                progress.finish();
+                break;
+            }
+
+            match decoding.damaged {
+                0 => check_digest(&mut reader, &hashing.finish())?,
+                _ => skip_digest(&mut reader, prefix.checksum.len())?,
+            }
+
+            if prefix.indexed() {
+                skip_index(&mut reader, blocks)?;
+            }
+
+            opener.finish()?;
+            progress.finish();
+        }
+
+        report.input_size = input.count();
+        report.elapsed = now.elapsed();
+
+        Ok(report)
+    }
+
+    /// Decodes `input` straight into `output` without any intermediate buffering, returning
+    /// the number of bytes written. `output` must hold at least [`decompressed_size`] bytes.
+    /// Only the first of several streams written one after another is decoded.
+    pub fn decompress_slice(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, std::io::Error> {
+        let tree = self.static_tree()?;
+
+        let mut reader = SliceReader::new(input);
+        let prefix = read_prefix(&mut reader)?;
+
+        if prefix.encrypted {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "encrypted input cannot be decompressed into a slice",
+            ));
+        }
+
+        let mut hasher = prefix.checksum.hasher()?;
+        let mut writer = SliceWriter::new(output);
+
+        let mut progress = Progress::new(&mut self.progress, self.cancel, 0);
+        let mut decoding = Decoding {
+            tree: tree.as_ref(),
+            limits: &self.limits.unwrap_or_else(Limits::unlimited),
+            bad_blocks: self.bad_blocks,
+            on_bad_block: &mut self.on_bad_block,
+            stream: 0,
+            start: 0,
+            damaged: 0,
+            stopped: false,
+        };
+
+        let blocks = decode_blocks(
+            &mut reader,
+            &mut writer,
+            &prefix,
+            &mut decoding,
+            &mut progress,
+            &mut CompressionReport::default(),
+        )?;
+
+        let written = writer.written();
+
+        if decoding.stopped {
+            progress.finish();
+            return Ok(written);
+        }
+
+        match decoding.damaged {
+            0 => {
+                hasher.update(&output[..written]);
+                check_digest(&mut reader, &hasher.finish())?;
+            }
+            _ => skip_digest(&mut reader, prefix.checksum.len())?,
+        }
+
+        if prefix.indexed() {
+            skip_index(&mut reader, blocks)?;
+        }
+
+        progress.finish();
+
+        Ok(written)
+    }
+
+    /// Decodes `length` bytes of the original data starting at `offset` into `output`,
+    /// reading only the blocks holding them, and returns the number of bytes written. The
+    /// range is cut short at the end of the data. `input` must hold a single stream that
+    /// ends with a block index.
+    pub fn extract<R: Read + Seek, W: Write>(
+        &mut self,
+        mut input: R,
+        mut output: W,
+        offset: u64,
+        length: u64,
+    ) -> Result<u64, std::io::Error> {
+        let _span = info_span!("extract", offset, length).entered();
+        let tree = self.static_tree()?;
+        let limits = self.limits.unwrap_or_else(Limits::unlimited);
+
+        let start = input.stream_position()?;
+        let mut reader = BitReader::endian(&mut input, BigEndian);
+        let prefix = read_prefix(&mut reader)?;
+
+        if prefix.encrypted {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "ranges cannot be extracted from encrypted input",
+            ));
+        }
+
+        if !prefix.indexed() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "input has no block index, decompress all of it instead",
+            ));
+        }
+
+        let block_size = read_block_size(&mut reader, &prefix, &limits)?;
+        let blocks = prefix.file_size.div_ceil(block_size);
+        let offsets = read_index(&mut input, start, blocks)?;
+
+        let end = offset.saturating_add(length).min(prefix.file_size);
+
+        if offset >= end {
+            return Ok(0);
+        }
+
+        let (first, last) = (offset / block_size, (end - 1) / block_size);
+        let block_len = |index: u64| block_size.min(prefix.file_size - index * block_size);
+        let mut progress = Progress::new(&mut self.progress, self.cancel, end - offset);
+        let mut timings = Timings::default();
+        let mut code = None;
+
+        // The first block may use a code stored with an earlier one.
+        for index in (0..first).rev() {
+            input.seek(SeekFrom::Start(start + offsets[index as usize]))?;
+            let mut reader = BitReader::endian(&mut input, BigEndian);
+
+            if reader.get_bits(8)? & 1 != 0 {
+                input.seek(SeekFrom::Start(start + offsets[index as usize]))?;
+                let mut reader = BitReader::endian(&mut input, BigEndian);
+                let frame = read_frame(&mut reader, &prefix, index, block_len(index) as usize)?;
+                let mut payload = SliceReader::new(&frame.payload);
+
+                // Past the length of the filtered block.
+                if !prefix.filters().is_empty() {
+                    payload.get_bits(32)?;
+                }
+
+                code = Some(read_stored_code(&mut payload, &prefix, &limits)?);
+                break;
+            }
+        }
+
+        let mut written = 0;
+        let mut block = Vec::new();
+
+        for index in first..=last {
+            input.seek(SeekFrom::Start(start + offsets[index as usize]))?;
+            let mut reader = BitReader::endian(&mut input, BigEndian);
+            let frame = read_frame(&mut reader, &prefix, index, block_len(index) as usize)?;
+
+            block.clear();
+            block.resize(block_len(index) as usize, 0);
+            decode_frame(
+                &frame,
+                &mut block,
+                &prefix,
+                &mut code,
+                tree.as_ref(),
+                &limits,
+                &mut timings,
+            )?;
+
+            let block_start = index * block_size;
+            let from = offset.max(block_start) - block_start;
+            let to = end.min(block_start + block.len() as u64) - block_start;
+
+            output.write_all(&block[from as usize..to as usize])?;
+            written += to - from;
+            progress.advance((to - from) * 8)?;
+        }
+
+        progress.finish();
+
+        Ok(written)
+    }
+
+    pub fn decompress_file<P: AsRef<Path>>(
+        &mut self,
+        input_path: P,
+        output_path: P,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let fin = File::open(input_path)?;
+        let reader = BufReader::with_capacity(32 * 1024, fin);
+
+        let fout = File::create(&output_path)?;
+        let writer = BufWriter::with_capacity(32 * 1024, fout);
+
+        self.decompress(reader, writer)
+    }
+}
+
+pub fn decompress<R: Read, W: Write>(
+    input: R,
+    output: W,
+) -> Result<CompressionReport, std::io::Error> {
+    Decompressor::new().decompress(input, output)
+}
+
 pub fn decompress_file<P: AsRef<Path>>(
     input_path: P,
     output_path: P,
-) -> Result<(), std::io::Error> {
-    let fin = File::open(input_path)?;
-    let reader = BufReader::with_capacity(32 * 1024, fin);
-    let mut reader = BitReader::endian(reader, BigEndian);
+) -> Result<CompressionReport, std::io::Error> {
+    Decompressor::new().decompress_file(input_path, output_path)
+}
+
+/// Decodes attacker-controlled `input` into memory, failing with a [`LimitExceeded`] error
+/// as soon as it breaks one of `limits`.
+pub fn decompress_untrusted<R: Read>(input: R, limits: Limits) -> Result<Vec<u8>, std::io::Error> {
+    let mut output = Vec::new();
+    Decompressor::new()
+        .limits(limits)
+        .decompress(input, &mut output)?;
+
+    Ok(output)
+}
+
+pub fn decompress_slice(input: &[u8], output: &mut [u8]) -> Result<usize, std::io::Error> {
+    Decompressor::new().decompress_slice(input, output)
+}
+
+pub fn decompressed_size(input: &[u8]) -> Result<u64, std::io::Error> {
+    let mut reader = SliceReader::new(input);
+    read_prefix(&mut reader).map(|prefix| prefix.file_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bits::BitBuffer;
+    use crate::encode::Compressor;
+
+    /// Text in 4 blocks of 1000 bytes and a shorter last one.
+    fn compressed() -> (Vec<u8>, Vec<u8>) {
+        let data = b"pack my box with five dozen liquor jugs. ".repeat(100);
+        let mut compressed = Vec::new();
+        Compressor::new(8)
+            .block_size(1000)
+            .compress(Cursor::new(&data), &mut compressed)
+            .unwrap();
+
+        (data, compressed)
+    }
+
+    fn extract(compressed: &[u8], offset: u64, length: u64) -> Vec<u8> {
+        let mut output = Vec::new();
+        Decompressor::new()
+            .extract(Cursor::new(compressed), &mut output, offset, length)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn rejects_header_without_symbols() {
+        let mut buffer = BitBuffer::default();
+        buffer.put_bits(6, 3).unwrap();
+        buffer.put_bit(false).unwrap();
+
+        for _ in 0..3 {
+            buffer.put_bits(9, 0).unwrap();
+        }
+
+        let bytes = buffer.into_bytes();
+        let limits = Limits::default();
+        let error = read_header(&mut SliceReader::new(&bytes), 8, &limits, true).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_checksums_left_out_of_the_build() {
+        let (_, compressed) = compressed();
+        // The checksum is in bits 5 and 6 of the letter size byte after the magic and version.
+        let position = MAGIC.len() + 1;
+        let unsupported = Checksum::ALL
+            .into_iter()
+            .filter(|checksum| !checksum.is_supported());
+
+        for checksum in unsupported {
+            let mut patched = compressed.clone();
+            patched[position] = patched[position] & !0b110_0000 | checksum.id() << 5;
+
+            let error = Decompressor::new()
+                .decompress(&patched[..], &mut Vec::new())
+                .unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+            let mut output = vec![0; decompressed_size(&patched).unwrap() as usize];
+            let error = decompress_slice(&patched, &mut output).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn decompresses_baseline_streams() {
+        let baseline = |size: u64, tree: &[(u32, u32)], codes: &[bool]| {
+            let mut buffer = BitBuffer::default();
+            buffer.put_bits(8, 8).unwrap();
+            buffer.put_bits(32, (size >> 32) as u32).unwrap();
+            buffer.put_bits(32, size as u32).unwrap();
+
+            for (bits, value) in tree
+                .iter()
+                .copied()
+                .chain(codes.iter().map(|&bit| (1, bit as u32)))
+            {
+                buffer.put_bits(bits, value).unwrap();
+            }
+
+            buffer.into_bytes()
+        };
 
-    let fout = File::create(&output_path)?;
-    let writer = BufWriter::with_capacity(32 * 1024, fout);
-    let mut writer = BitWriter::endian(writer, BigEndian);
+        let cases = [
+            (baseline(0, &[], &[]), &b""[..]),
+            (baseline(3, &[(9, b'a' as u32)], &[]), b"aaa"),
+            (
+                baseline(
+                    4,
+                    &[(1, 1), (9, b'a' as u32), (9, b'b' as u32)],
+                    &[false, true, true, false],
+                ),
+                b"abba",
+            ),
+        ];
 
-    decompress(&mut reader, &mut writer)
+        for (compressed, data) in cases {
+            let mut output = Vec::new();
+            Decompressor::new()
+                .decompress(&compressed[..], &mut output)
+                .unwrap();
+            assert_eq!(output, data);
+
+            let error = decompress_slice(&compressed, &mut [0; 4]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn decompresses_into_slice() {
+        let (data, compressed) = compressed();
+        assert_eq!(decompressed_size(&compressed).unwrap(), data.len() as u64);
+
+        let mut output = vec![0; data.len()];
+        assert_eq!(
+            decompress_slice(&compressed, &mut output).unwrap(),
+            data.len()
+        );
+        assert_eq!(output, data);
+
+        let mut larger = vec![0; data.len() + 1];
+        assert_eq!(
+            decompress_slice(&compressed, &mut larger).unwrap(),
+            data.len()
+        );
+        assert_eq!(larger[..data.len()], data);
+
+        let mut smaller = vec![0; data.len() - 1];
+        assert!(decompress_slice(&compressed, &mut smaller).is_err());
+        assert!(decompress_slice(&compressed[..compressed.len() - 1], &mut output).is_err());
+    }
+
+    #[test]
+    fn extracts_ranges() {
+        let (data, compressed) = compressed();
+        let len = data.len() as u64;
+
+        for (offset, length) in [
+            (0, 0),
+            (0, 1),
+            (0, len),
+            (999, 2),
+            (1000, 1000),
+            (1, 3998),
+            (len - 1, 1),
+            (len - 1, u64::MAX),
+            (3000, u64::MAX),
+        ] {
+            let end = offset.saturating_add(length).min(len) as usize;
+            assert_eq!(
+                extract(&compressed, offset, length),
+                data[offset as usize..end],
+                "{} {}",
+                offset,
+                length
+            );
+        }
+
+        assert!(extract(&compressed, len, 1).is_empty());
+        assert!(extract(&compressed, u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn extract_needs_an_index() {
+        let mut compressed = Vec::new();
+        Compressor::new(8)
+            .block_trees(true)
+            .compress_stream(&b"no index"[..], &mut compressed)
+            .unwrap();
+
+        let error = Decompressor::new()
+            .extract(Cursor::new(compressed), &mut Vec::new(), 0, 1)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,124 @@
+use std::io::{ErrorKind, Read, Write};
+
+use bitstream_io::{BigEndian, BitReader};
+
+use crate::bits::BitBuffer;
+use crate::decode::read_header;
+use crate::encode::{build_code, check_letter_size, write_header};
+use crate::histogram::Histogram;
+use crate::limits::Limits;
+use crate::tree::{CanonicalCode, CodeTable};
+
+/// Version of the dictionary layout.
+pub const DICTIONARY_VERSION: u8 = 1;
+
+/// Largest letter size a dictionary can be trained for, as it holds a code for every letter.
+pub const MAX_DICTIONARY_LETTER_SIZE: u8 = 16;
+
+const MAGIC: &[u8; 4] = b"HUFD";
+
+/// A code trained on a corpus of similar inputs, so each of them can be compressed with it
+/// instead of storing a code of its own and counting its letters first. Every possible
+/// letter gets a code, so inputs may hold letters the corpus did not.
+///
+/// Layout: the magic `HUFD`, the version byte, the letter size byte, then the code lengths
+/// as they are stored with blocks, padded to a whole byte.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Dictionary {
+    letter_size: u8,
+    code: CanonicalCode,
+}
+
+impl Dictionary {
+    /// Builds the dictionary for a corpus whose letters were counted as `histogram`.
+    pub fn train(histogram: &Histogram, letter_size: u8) -> Result<Self, std::io::Error> {
+        check_letter_size(letter_size)?;
+
+        if letter_size > MAX_DICTIONARY_LETTER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "dictionaries hold a code for every letter, so letters can be at most {} bits",
+                    MAX_DICTIONARY_LETTER_SIZE
+                ),
+            ));
+        }
+
+        let mut frequencies = histogram.clone();
+
+        for letter in 0..1 << letter_size {
+            frequencies.add(letter);
+        }
+
+        // Codes longer than 32 bits would not fit a code table.
+        let code = build_code(frequencies, Some(32))?.unwrap_or_default();
+
+        Ok(Dictionary { letter_size, code })
+    }
+
+    pub fn letter_size(&self) -> u8 {
+        self.letter_size
+    }
+
+    pub fn code(&self) -> &CanonicalCode {
+        &self.code
+    }
+
+    /// The table to hand to [`Compressor::code_table`](crate::encode::Compressor::code_table)
+    /// and [`Decompressor::code_table`](crate::decode::Decompressor::code_table).
+    pub fn table(&self) -> CodeTable {
+        self.code.table()
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        let mut lengths = BitBuffer::default();
+        write_header(&mut lengths, &self.code, self.letter_size, false)?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[DICTIONARY_VERSION, self.letter_size])?;
+        writer.write_all(&lengths.into_bytes())
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let mut start = [0; 6];
+        reader.read_exact(&mut start)?;
+
+        if &start[..4] != MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a huffman-code dictionary",
+            ));
+        }
+
+        if start[4] != DICTIONARY_VERSION {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "dictionary version {} is not supported, expected {}",
+                    start[4], DICTIONARY_VERSION
+                ),
+            ));
+        }
+
+        let letter_size = start[5];
+
+        if check_letter_size(letter_size).is_err() || letter_size > MAX_DICTIONARY_LETTER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid letter size",
+            ));
+        }
+
+        let mut reader = BitReader::endian(reader, BigEndian);
+        let code = read_header(&mut reader, letter_size, &Limits::unlimited(), false)?;
+
+        if code.len() != 1 << letter_size {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "dictionary does not hold a code for every letter",
+            ));
+        }
+
+        Ok(Dictionary { letter_size, code })
+    }
+}
@@ -1,203 +1,1870 @@
-use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom};
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bitstream_io::{BigEndian, BitQueue, BitRead, BitReader, BitWrite, BitWriter, Endianness};
+use bitstream_io::BitWrite;
+#[cfg(feature = "parallel")]
+use rayon::ThreadPool;
+use tracing::{debug, debug_span, info_span};
 
-#[derive(Debug, Eq, PartialEq)]
-enum NodePayload {
-    Leaf(u32),
-    Joint(Box<Node>, Box<Node>),
+use crate::adaptive::AdaptiveCode;
+use crate::arithmetic::{self, Coder, Model, MAX_ARITHMETIC_LETTER_SIZE};
+use crate::bits::{BitBuffer, BitSink, BitSource, SliceReader};
+use crate::checksum::Checksum;
+use crate::context::{self, Contexts};
+use crate::crypto::{Encryption, Sealer};
+use crate::decode::{keep_blocks, read_prefix, Kept, StoredCode};
+use crate::filter::Filters;
+use crate::format::{
+    ADAPTIVE, AES_GCM, ARITHMETIC, CODED_LENGTHS, EOS, FORMAT_VERSION, INDEXED, LETTER_SIZES,
+    LSB_FIRST, LZ77, MAGIC, MODE, MORE_FLAGS, MTIME, ORDER1, PREFIX_SIZE, UNKNOWN_SIZE,
+};
+use crate::histogram::{count, Histogram};
+use crate::lz77;
+use crate::parallel::Pool;
+#[cfg(feature = "parallel")]
+use crate::parallel::Threads;
+use crate::progress::{Callback, Progress};
+use crate::report::{CompressionReport, Counter, Timings};
+use crate::tree::{CanonicalCode, Code, CodeTable, Tree};
+
+pub(crate) fn check_letter_size(letter_size: u8) -> Result<(), std::io::Error> {
+    if !LETTER_SIZES.contains(&letter_size) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "letter size must be between {} and {}",
+                LETTER_SIZES.start(),
+                LETTER_SIZES.end()
+            ),
+        ));
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Eq, PartialEq)]
-struct Node {
-    freq: usize,
-    payload: NodePayload,
+pub(crate) fn encode_symbol<S: Hash + Eq, W: BitSink>(
+    writer: &mut W,
+    table: &CodeTable<S>,
+    symbol: &S,
+) -> Result<(), std::io::Error> {
+    match table.get(symbol) {
+        Some(code) => writer.put_bits(code.length, code.value),
+        None => Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "symbol is missing from the code table",
+        )),
+    }
+}
+
+pub fn write_symbol<S: Hash + Eq, W: BitWrite>(
+    writer: &mut W,
+    table: &CodeTable<S>,
+    symbol: &S,
+) -> Result<(), std::io::Error> {
+    encode_symbol(writer, table, symbol)
 }
 
-impl Ord for Node {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.freq.cmp(&self.freq)
+pub fn write_symbols<S: Hash + Eq, W: BitWrite, I: IntoIterator<Item = S>>(
+    writer: &mut W,
+    table: &CodeTable<S>,
+    symbols: I,
+) -> Result<(), std::io::Error> {
+    for symbol in symbols {
+        write_symbol(writer, table, &symbol)?;
     }
+
+    Ok(())
+}
+
+/// Maps each symbol to its code, for working on symbol streams without a bit writer.
+pub fn encode_symbols<'a, S: Hash + Eq, I>(
+    table: &'a CodeTable<S>,
+    symbols: I,
+) -> impl Iterator<Item = Result<Code, std::io::Error>> + 'a
+where
+    I: IntoIterator<Item = S>,
+    I::IntoIter: 'a,
+{
+    symbols.into_iter().map(|symbol| {
+        table.get(&symbol).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "symbol is missing from the code table",
+            )
+        })
+    })
 }
 
-impl PartialOrd for Node {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// Sizes in bits of the sparse and dense code length lists for `code`.
+fn header_sizes(code: &CanonicalCode, letter_size: u8) -> (u64, u64) {
+    let letter_size = letter_size as u64;
+    let width = 32 - code.max_length().leading_zeros();
+
+    let sparse = code.max_length() as u64 * (letter_size + 1) + code.len() as u64 * letter_size;
+    let dense = (1 << letter_size) * width as u64;
+
+    (sparse, dense)
+}
+
+/// Size in bits of the header [`write_header`] writes for `code` with coded lists allowed.
+fn header_size(code: &CanonicalCode, letter_size: u8) -> u64 {
+    match code.max_length() {
+        0 => 6 + letter_size as u64,
+        _ => {
+            let (sparse, dense) = header_sizes(code, letter_size);
+            let symbols = length_symbols(code, letter_size);
+            let coded = coded_size(&symbols, &length_code(&symbols), code.max_length());
+            6 + (1 + sparse).min(2 + dense.min(coded))
+        }
     }
 }
 
-fn count_frequency<P: AsRef<Path>>(
-    input_path: P,
-    letter_size: u8,
-) -> Result<Vec<Node>, std::io::Error> {
-    let file = File::open(input_path)?;
-    let reader = BufReader::with_capacity(32 * 1024, file);
-    let mut reader = BitReader::endian(reader, BigEndian);
-    let mut nodes = HashMap::new();
-
-    loop {
-        match reader.read::<u32>(letter_size as u32) {
-            Err(e) => match e.kind() {
-                ErrorKind::UnexpectedEof => break,
-                _ => return Err(e),
-            },
-            Ok(code) => {
-                let node = nodes.entry(code).or_insert(Node {
-                    freq: 0,
-                    payload: NodePayload::Leaf(code),
-                });
+/// Longest code of the code for code lengths.
+const MAX_LENGTH_CODE_LENGTH: u32 = 7;
+
+/// Bits of every code length of the code for code lengths.
+pub(crate) const LENGTH_CODE_BITS: u32 = 3;
+
+/// Bits of the number of trailing bits after an end-of-stream letter.
+pub(crate) const TRAILING_BITS: u32 = 5;
+
+/// Runs of the code lengths of every possible letter as symbols of the code for code lengths,
+/// with the number of extra bits after each and their value. Symbols up to the longest length
+/// stand for themselves, the next one repeats the previous length 3 to 6 times with 2 extra
+/// bits, then one for 3 to 10 zeros with 3 and one for 11 or more zeros with `letter_size`.
+fn length_symbols(code: &CanonicalCode, letter_size: u8) -> Vec<(u32, u32, u32)> {
+    let max_length = code.max_length();
+    let (repeat, zeros, long_zeros) = (max_length + 1, max_length + 2, max_length + 3);
+    let letters = 1u32 << letter_size;
+
+    let mut lengths = code.lengths().collect::<Vec<_>>();
+    lengths.sort_unstable();
+
+    // Equal lengths of consecutive letters and the zeros of the letters between them.
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    let mut push = |length: u32, count: u32| match runs.last_mut() {
+        Some((last, run)) if *last == length => *run += count,
+        _ => runs.push((length, count)),
+    };
+    let mut next = 0;
+
+    for (symbol, length) in lengths {
+        if symbol > next {
+            push(0, symbol - next);
+        }
+
+        push(length, 1);
+        next = symbol + 1;
+    }
+
+    if next < letters {
+        push(0, letters - next);
+    }
+
+    let mut symbols = Vec::new();
 
-                node.freq += 1;
+    for (length, run) in runs {
+        match (length, run) {
+            (0, 0..=2) => symbols.extend(std::iter::repeat_n((0, 0, 0), run as usize)),
+            (0, 3..=10) => symbols.push((zeros, 3, run - 3)),
+            (0, _) => symbols.push((long_zeros, letter_size as u32, run - 11)),
+            _ => {
+                symbols.push((length, 0, 0));
+                let mut rest = run - 1;
+
+                while rest >= 3 {
+                    let count = rest.min(6);
+                    symbols.push((repeat, 2, count - 3));
+                    rest -= count;
+                }
+
+                symbols.extend(std::iter::repeat_n((length, 0, 0), rest as usize));
             }
         }
     }
 
-    Ok(nodes.into_values().collect())
+    symbols
 }
 
-fn create_tree(nodes: Vec<Node>) -> Option<Node> {
-    let mut nodes = BinaryHeap::from(nodes);
+/// The code `symbols` of [`length_symbols`] are coded with, of at least two symbols so that
+/// every code takes a bit.
+fn length_code(symbols: &[(u32, u32, u32)]) -> CanonicalCode {
+    let mut frequencies = Histogram::new();
 
-    while nodes.len() > 1 {
-        let left = nodes.pop().unwrap();
-        let right = nodes.pop().unwrap();
+    for &(symbol, _, _) in symbols {
+        frequencies.add(symbol);
+    }
 
-        nodes.push(Node {
-            freq: left.freq + right.freq,
-            payload: NodePayload::Joint(Box::new(left), Box::new(right)),
-        });
+    for symbol in 0..2 {
+        if frequencies.len() < 2 && frequencies.get(&symbol) == 0 {
+            frequencies.add(symbol);
+        }
     }
 
-    nodes.pop()
+    CanonicalCode::length_limited(frequencies, MAX_LENGTH_CODE_LENGTH)
 }
 
-fn create_table(tree: &Node) -> HashMap<u32, (u32, u32)> {
-    fn walk(root: &Node, table: &mut HashMap<u32, (u32, u32)>, current_path: &mut Vec<bool>) {
-        match &root.payload {
-            NodePayload::Leaf(code) => {
-                let mut queue = BitQueue::<BigEndian, u32>::new();
+/// Size in bits of the coded list of `symbols` coded with `length_code`.
+fn coded_size(symbols: &[(u32, u32, u32)], length_code: &CanonicalCode, max_length: u32) -> u64 {
+    let mut lengths = HashMap::with_capacity(length_code.len());
+    lengths.extend(length_code.lengths());
 
-                for bit in current_path {
-                    match *bit {
-                        false => queue.push(1, 0),
-                        true => queue.push(1, 1),
-                    }
-                }
+    let table = (max_length as u64 + 4) * LENGTH_CODE_BITS as u64;
+    let codes = symbols
+        .iter()
+        .map(|&(symbol, bits, _)| (lengths[&symbol] + bits) as u64)
+        .sum::<u64>();
+
+    table + codes
+}
+
+/// Writes the code lengths of `code`: the longest length in 6 bits, then for a single symbol
+/// the symbol itself. Otherwise a flag bit picks the smallest of the lists. The sparse list
+/// (0) gives the number of codes of every length from 1 up in `letter_size + 1` bits, then the
+/// symbols in canonical order. The dense list (1) gives the code length of every possible
+/// letter, 0 for letters that do not occur, in as few bits as the longest length needs. When
+/// `coded` allows it, a second bit after the first 1 picks between the dense list (0) and the
+/// coded list (1), which gives the lengths of the code for [`length_symbols`] in 3 bits each,
+/// then those symbols coded with it, each followed by its extra bits, as DEFLATE does.
+pub(crate) fn write_header<W: BitSink>(
+    writer: &mut W,
+    code: &CanonicalCode,
+    letter_size: u8,
+    coded: bool,
+) -> Result<(), std::io::Error> {
+    let max_length = code.max_length();
+    writer.put_bits(6, max_length)?;
 
-                table.insert(*code, (queue.len(), queue.value()));
+    if max_length == 0 {
+        return writer.put_bits(letter_size as u32, code.symbols()[0]);
+    }
+
+    let (sparse, dense) = header_sizes(code, letter_size);
+
+    if coded {
+        let symbols = length_symbols(code, letter_size);
+        let length_code = length_code(&symbols);
+
+        if coded_size(&symbols, &length_code, max_length) < sparse.min(dense) {
+            writer.put_bit(true)?;
+            writer.put_bit(true)?;
+
+            let mut lengths = HashMap::with_capacity(length_code.len());
+            lengths.extend(length_code.lengths());
+
+            for symbol in 0..max_length + 4 {
+                writer.put_bits(LENGTH_CODE_BITS, lengths.get(&symbol).copied().unwrap_or(0))?;
             }
-            NodePayload::Joint(left, right) => {
-                current_path.push(false);
-                walk(left, table, current_path);
-                current_path.pop();
 
-                current_path.push(true);
-                walk(right, table, current_path);
-                current_path.pop();
+            let table = length_code.table();
+
+            for (symbol, bits, extra) in symbols {
+                encode_symbol(writer, &table, &symbol)?;
+                writer.put_bits(bits, extra)?;
             }
+
+            return Ok(());
         }
     }
 
-    let mut path = Vec::new();
-    let mut table = HashMap::new();
-    walk(tree, &mut table, &mut path);
+    if dense < sparse {
+        writer.put_bit(true)?;
+
+        if coded {
+            writer.put_bit(false)?;
+        }
+
+        let mut lengths = code.lengths().collect::<Vec<_>>();
+        lengths.sort_unstable();
+        let mut lengths = lengths.into_iter().peekable();
+
+        let width = 32 - max_length.leading_zeros();
+        for letter in 0..1u32 << letter_size {
+            let length = lengths.next_if(|&(symbol, _)| symbol == letter);
+            writer.put_bits(width, length.map_or(0, |(_, length)| length))?;
+        }
+    } else {
+        writer.put_bit(false)?;
+
+        for &count in &code.counts()[1..] {
+            writer.put_bits(letter_size as u32 + 1, count)?;
+        }
+
+        for &symbol in code.symbols() {
+            writer.put_bits(letter_size as u32, symbol)?;
+        }
+    }
 
-    table
+    Ok(())
 }
 
-fn write_header<W: BitWrite>(
+pub(crate) fn encode<R: BitSource, W: BitSink>(
+    reader: &mut R,
     writer: &mut W,
-    tree: &Node,
+    table: &CodeTable,
+    file_size: u64,
     letter_size: u8,
+    progress: &mut Progress,
 ) -> Result<(), std::io::Error> {
-    match &tree.payload {
-        NodePayload::Leaf(code) => {
-            writer.write_bit(false)?;
-            writer.write(letter_size as u32, *code)?;
+    let letters = file_size * 8 / letter_size as u64;
+    let remaining = file_size * 8 - letters * letter_size as u64;
+
+    for _ in 0..letters {
+        let symbol = reader.get_bits(letter_size as u32)?;
+        encode_symbol(writer, table, &symbol)?;
+        progress.advance(letter_size as u64)?;
+    }
+
+    if remaining != 0 {
+        let value = reader.get_bits(remaining as u32)?;
+        writer.put_bits(remaining as u32, value)?;
+        progress.advance(remaining)?;
+    }
+
+    Ok(())
+}
+
+/// Size in bytes of the output [`compress`] produces for `file_size` bytes of input whose
+/// letters occur as counted in `histogram`, leaving out the checksums.
+pub fn estimate_size(histogram: &Histogram, file_size: u64, letter_size: u8) -> u64 {
+    let mut bits = PREFIX_SIZE * 8;
+
+    // The block size, the flags and payload length of every block and the block index.
+    let blocks = file_size.div_ceil(default_block_bytes(letter_size) as u64);
+    bits += 32 + blocks * 40 + (blocks + 1) * 64;
+
+    let mut frequencies = histogram
+        .iter()
+        .map(|(symbol, count)| (*symbol, count))
+        .collect::<Vec<_>>();
+    frequencies.sort_unstable();
+
+    if let Some(tree) = Tree::from_frequencies(frequencies) {
+        let code = CanonicalCode::from_tree(&tree);
+        bits += header_size(&code, letter_size);
+        bits += code
+            .lengths()
+            .map(|(symbol, length)| histogram.get(&symbol) as u64 * length as u64)
+            .sum::<u64>();
+    }
+
+    bits += file_size * 8 % letter_size as u64;
+    bits.div_ceil(8)
+}
+
+/// Counts `input` at each of the `candidates` letter sizes and returns the one giving the
+/// smallest output along with that output's size. `input` is left where it started.
+pub fn best_letter_size<R: Read + Seek>(
+    mut input: R,
+    candidates: &[u8],
+) -> Result<(u8, u64), std::io::Error> {
+    let start = input.stream_position()?;
+    let file_size = input.seek(SeekFrom::End(0))? - start;
+
+    let mut best = None;
+
+    for &letter_size in candidates {
+        check_letter_size(letter_size)?;
+
+        input.seek(SeekFrom::Start(start))?;
+        let histogram = count(&mut input, letter_size, &mut Progress::disabled())?;
+        let size = estimate_size(&histogram, file_size, letter_size);
+
+        if best.is_none_or(|(_, best_size)| size < best_size) {
+            best = Some((letter_size, size));
+        }
+    }
+
+    input.seek(SeekFrom::Start(start))?;
+
+    best.ok_or_else(|| {
+        std::io::Error::new(ErrorKind::InvalidInput, "no letter sizes to choose from")
+    })
+}
+
+/// Letters per block when no block size is given.
+const BLOCK_LETTERS: usize = 64 * 1024;
+
+/// Largest block in bytes, small enough for the encoded block to fit its 32 bit length field
+/// at any letter size.
+pub const MAX_BLOCK_SIZE: usize = 256 << 20;
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn default_block_bytes(letter_size: u8) -> usize {
+    BLOCK_LETTERS * letter_size as usize / 8
+}
+
+/// Bytes per block: `size` rounded down to whole letters, or 64Ki letters when not given.
+pub(crate) fn block_bytes(size: Option<usize>, letter_size: u8) -> Result<usize, std::io::Error> {
+    // The smallest number of bytes holding a whole number of letters.
+    let unit = letter_size as usize / gcd(letter_size as usize, 8);
+
+    match size {
+        Some(size) if size > MAX_BLOCK_SIZE => Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "block size must be at most 256 MiB",
+        )),
+        Some(size) => Ok((size / unit).max(1) * unit),
+        None => Ok(default_block_bytes(letter_size)),
+    }
+}
+
+/// The canonical code for letters counted as `frequencies`, `None` when there are none. Its
+/// codes are limited to `max_length` bits when given.
+pub(crate) fn build_code(
+    frequencies: Histogram,
+    max_length: Option<u32>,
+) -> Result<Option<CanonicalCode>, std::io::Error> {
+    // Letters of equal counts are paired in the order they come, so they come in order of
+    // the letters for the same input to give the same output.
+    let mut frequencies = frequencies.into_iter().collect::<Vec<_>>();
+    frequencies.sort_unstable();
+
+    let code = Tree::from_frequencies(frequencies.iter().copied())
+        .map(|tree| CanonicalCode::from_tree(&tree));
+
+    match (code, max_length) {
+        (Some(code), Some(max_length)) if code.max_length() > max_length => {
+            if code.len() as u64 > 1 << max_length.min(63) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} distinct letters do not fit in codes of at most {} bits",
+                        code.len(),
+                        max_length
+                    ),
+                ));
+            }
+
+            Ok(Some(CanonicalCode::length_limited(frequencies, max_length)))
         }
-        NodePayload::Joint(left, right) => {
-            writer.write_bit(true)?;
-            write_header(writer, left, letter_size)?;
-            write_header(writer, right, letter_size)?;
+        (code, _) => Ok(code),
+    }
+}
+
+/// Encodes `block` into whole bytes: the code lengths of `code` when given, over letters one
+/// bit wider when `eos` is set, then the letters coded with `table` and the bits of a trailing
+/// partial letter as they are. With `end` set the letters are followed by the end-of-stream
+/// letter and the number of trailing bits in 5 bits before those bits.
+fn encode_block(
+    block: &[u8],
+    letter_size: u8,
+    table: &CodeTable,
+    code: Option<&CanonicalCode>,
+    eos: bool,
+    end: bool,
+    header: &mut Duration,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut reader = SliceReader::new(block);
+    let mut buffer = BitBuffer::default();
+
+    if let Some(code) = code {
+        let phase = Instant::now();
+        write_header(&mut buffer, code, letter_size + eos as u8, true)?;
+        *header += phase.elapsed();
+    }
+
+    if !end {
+        encode(
+            &mut reader,
+            &mut buffer,
+            table,
+            block.len() as u64,
+            letter_size,
+            &mut Progress::disabled(),
+        )?;
+
+        return Ok(buffer.into_bytes());
+    }
+
+    let bits = block.len() as u64 * 8;
+    let letters = bits / letter_size as u64;
+
+    for _ in 0..letters {
+        encode_symbol(&mut buffer, table, &reader.get_bits(letter_size as u32)?)?;
+    }
+
+    encode_symbol(&mut buffer, table, &(1 << letter_size))?;
+
+    let remaining = (bits - letters * letter_size as u64) as u32;
+    buffer.put_bits(TRAILING_BITS, remaining)?;
+
+    if remaining != 0 {
+        buffer.put_bits(remaining, reader.get_bits(remaining)?)?;
+    }
+
+    Ok(buffer.into_bytes())
+}
+
+/// Encodes `block` with an [`AdaptiveCode`] of its own, then the bits of a trailing partial
+/// letter as they are.
+fn encode_adaptive(block: &[u8], letter_size: u8) -> Result<Vec<u8>, std::io::Error> {
+    let mut reader = SliceReader::new(block);
+    let mut buffer = BitBuffer::default();
+    let mut code = AdaptiveCode::new(letter_size);
+
+    let bits = block.len() as u64 * 8;
+    let letters = bits / letter_size as u64;
+
+    for _ in 0..letters {
+        code.encode(&mut buffer, reader.get_bits(letter_size as u32)?)?;
+    }
+
+    let remaining = (bits - letters * letter_size as u64) as u32;
+
+    if remaining != 0 {
+        buffer.put_bits(remaining, reader.get_bits(remaining)?)?;
+    }
+
+    Ok(buffer.into_bytes())
+}
+
+/// Where the blocks of a stream get their code from.
+enum BlockCodes<'a> {
+    /// A table agreed on ahead of time, never stored.
+    Static(&'a CodeTable),
+    /// One code for the whole input, stored with the first block.
+    Shared(Option<CanonicalCode>, CodeTable),
+    /// A code for every block built from its own letters and stored with it.
+    PerBlock,
+    /// An adaptive code started afresh in every block, never stored.
+    Adaptive,
+    /// LZ77 matches and literals with codes of their own stored with every block.
+    Lz77,
+    /// One model for the whole input, stored with the first block, for arithmetic coding.
+    SharedModel(Model),
+    /// A code for every context of one letter, built for the whole input and stored with the
+    /// first block.
+    SharedContexts(Contexts),
+    /// A model for every block built from its own letters and stored with it, for arithmetic
+    /// coding.
+    ModelPerBlock,
+}
+
+impl BlockCodes<'_> {
+    /// Whether the blocks after the first are coded with the code stored with it.
+    fn shared(&self) -> bool {
+        matches!(
+            self,
+            BlockCodes::Shared(..) | BlockCodes::SharedModel(..) | BlockCodes::SharedContexts(..)
+        )
+    }
+
+    /// Whether `stored`, read back from the first block of a stream, is the code these store
+    /// with it.
+    fn stores(&self, stored: Option<&StoredCode>) -> bool {
+        match (self, stored) {
+            (BlockCodes::Shared(code, _), Some(StoredCode::Canonical(stored))) => {
+                code.as_ref() == Some(stored)
+            }
+            (BlockCodes::Shared(code, _), None) => code.is_none(),
+            (BlockCodes::SharedModel(model), Some(StoredCode::Model(stored))) => model == stored,
+            (BlockCodes::SharedModel(model), None) => model.is_empty(),
+            (BlockCodes::SharedContexts(contexts), Some(StoredCode::Contexts(stored))) => {
+                contexts == stored
+            }
+            (BlockCodes::SharedContexts(contexts), None) => contexts.is_empty(),
+            _ => false,
         }
     }
+}
 
-    Ok(())
+/// What is known of the input of a stream before its blocks are written.
+enum Size {
+    /// Input of this many bytes, the blocks at the start of which may be kept from an earlier
+    /// run.
+    Known(u64, Kept),
+    /// Input read until it ends.
+    Unknown,
 }
 
-fn compress<R: Read + Seek, W: BitWrite, E: Endianness>(
-    mut reader: BitReader<R, E>,
-    mut writer: W,
-    nodes: Vec<Node>,
-    file_size: u64,
+struct EncodedBlock {
+    payload: Vec<u8>,
+    /// Symbols and longest code length of the code stored with the block.
+    code: Option<(usize, u32)>,
+    digest: Vec<u8>,
+    /// Whether the payload is the block as it is, for blocks coding would not make smaller.
+    stored: bool,
+    /// Time spent writing the code stored with the block.
+    header: Duration,
+}
+
+impl EncodedBlock {
+    fn stored(block: &[u8], digest: Vec<u8>) -> Self {
+        EncodedBlock {
+            payload: block.to_vec(),
+            code: None,
+            digest,
+            stored: true,
+            header: Duration::ZERO,
+        }
+    }
+}
+
+/// Appends `block` coded arithmetically with `model` to `payload`, which the model is stored
+/// in when `store` is set.
+fn encode_with_model(
+    mut payload: Vec<u8>,
+    block: &[u8],
     letter_size: u8,
+    model: &Model,
+    store: bool,
+    digest: Vec<u8>,
+) -> Result<EncodedBlock, std::io::Error> {
+    let mut header = Duration::ZERO;
+    payload.extend(arithmetic::encode_block(
+        block,
+        letter_size,
+        model,
+        store,
+        &mut header,
+    )?);
+
+    Ok(EncodedBlock {
+        payload,
+        code: store.then_some((model.len(), 0)),
+        digest,
+        stored: false,
+        header,
+    })
+}
+
+/// Frames `block` as a flags byte, bit 0 marking a stored code and bit 1 a stored block, the
+/// original length of the block when the stream does not
+/// say it, the payload length, the block digest and the payload.
+fn write_frame<W: Write>(
+    output: &mut W,
+    block: &EncodedBlock,
+    original_len: Option<usize>,
 ) -> Result<(), std::io::Error> {
-    if !(2..=16).contains(&letter_size) {
-        return Err(std::io::Error::new(
-            ErrorKind::InvalidInput,
-            "letter size must be between 2 and 16",
-        ));
+    output.write_all(&[block.code.is_some() as u8 | (block.stored as u8) << 1])?;
+
+    if let Some(len) = original_len {
+        output.write_all(&(len as u32).to_be_bytes())?;
     }
 
-    writer.write(8, letter_size)?;
-    writer.write(64, file_size)?;
+    output.write_all(&(block.payload.len() as u32).to_be_bytes())?;
+    output.write_all(&block.digest)?;
+    output.write_all(&block.payload)
+}
 
-    let tree = match create_tree(nodes) {
-        Some(root) => root,
-        _ => return Ok(()),
-    };
+pub struct Compressor<'a> {
+    letter_size: u8,
+    checksum: Checksum,
+    password: Option<&'a [u8]>,
+    encryption: Encryption,
+    table: Option<CodeTable>,
+    coder: Coder,
+    progress: Callback<'a>,
+    cancel: Option<&'a AtomicBool>,
+    #[cfg(feature = "parallel")]
+    threads: Threads,
+    block_size: Option<usize>,
+    block_trees: bool,
+    adaptive: bool,
+    lz77: bool,
+    rle: bool,
+    bwt: bool,
+    order1: bool,
+    eos: bool,
+    lsb_first: bool,
+    max_code_length: Option<u32>,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+}
 
-    let table = create_table(&tree);
-    let mut written = 0;
+impl<'a> Compressor<'a> {
+    pub fn new(letter_size: u8) -> Self {
+        Compressor {
+            letter_size,
+            checksum: Checksum::default(),
+            password: None,
+            encryption: Encryption::default(),
+            table: None,
+            coder: Coder::default(),
+            progress: None,
+            cancel: None,
+            #[cfg(feature = "parallel")]
+            threads: Threads::default(),
+            block_size: None,
+            block_trees: false,
+            adaptive: false,
+            lz77: false,
+            rle: false,
+            bwt: false,
+            order1: false,
+            eos: false,
+            lsb_first: false,
+            max_code_length: None,
+            mtime: None,
+            mode: None,
+        }
+    }
 
-    write_header(&mut writer, &tree, letter_size)?;
+    /// Runs the frequency and encoding passes on `pool` instead of rayon's global pool.
+    #[cfg(feature = "parallel")]
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.threads = Threads::Pool(pool);
+        self
+    }
 
-    loop {
-        match reader.read::<u32>(letter_size as u32) {
-            Err(e) => match e.kind() {
-                ErrorKind::UnexpectedEof => break,
-                _ => return Err(e),
-            },
-            Ok(code) => {
-                let (length, value) = table[&code];
-                writer.write(length, value)?;
-                written += letter_size as usize;
+    /// Runs the frequency and encoding passes on a dedicated pool of `count` threads;
+    /// a count of 1 keeps everything on the calling thread.
+    #[cfg(feature = "parallel")]
+    pub fn threads(mut self, count: usize) -> Self {
+        self.threads = Threads::Count(count);
+        self
+    }
+
+    /// Splits the input into blocks of about `size` bytes, rounded down to whole letters and
+    /// at most [`MAX_BLOCK_SIZE`]. Blocks are compressed in parallel and decompressed one at a
+    /// time, so smaller ones spread better over many threads and need less memory to decode,
+    /// larger ones cost less to frame and schedule.
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.block_size = Some(size);
+        self
+    }
+
+    /// Builds a code for every block from its own letters instead of one for the whole
+    /// input. Suits data whose letters change along the way and skips the frequency pass,
+    /// at the cost of storing a code with every block.
+    pub fn block_trees(mut self, enabled: bool) -> Self {
+        self.block_trees = enabled;
+        self
+    }
+
+    /// Codes every block with an adaptive Huffman code that both sides update as letters go
+    /// by, so there is no frequency pass and no code is stored. Every block starts afresh,
+    /// which keeps blocks independent at the cost of learning the letters again.
+    pub fn adaptive(mut self, enabled: bool) -> Self {
+        self.adaptive = enabled;
+        self
+    }
+
+    /// Codes every block as DEFLATE does, as literals and matches of earlier bytes within 32
+    /// KiB, with codes for them built from the block. Repeated strings cost a few bits each
+    /// rather than a few bits a byte. Needs 8 bit letters, and cannot be combined with
+    /// adaptive coding or a code table.
+    pub fn lz77(mut self, enabled: bool) -> Self {
+        self.lz77 = enabled;
+        self
+    }
+
+    /// Codes every letter with a code of its own for the letter before it, or for the top 8
+    /// bits of it for longer letters, built from the whole input. Text compresses much better
+    /// for the cost of storing up to 256 codes. Cannot be combined with adaptive coding, LZ77,
+    /// arithmetic coding, codes per block or a code table.
+    pub fn order1(mut self, enabled: bool) -> Self {
+        self.order1 = enabled;
+        self
+    }
+
+    /// Ends the last block with an end-of-stream letter coded like the others, so readers
+    /// find the end of the data from the letters rather than from lengths stored ahead of
+    /// them, and blocks of streamed input are framed without their original length. Needs
+    /// Huffman codes built from counted letters, so cannot be combined with adaptive coding,
+    /// LZ77, arithmetic coding, order-1 contexts, run-length coding, the Burrows-Wheeler
+    /// transform or a code table.
+    pub fn eos(mut self, enabled: bool) -> Self {
+        self.eos = enabled;
+        self
+    }
+
+    /// Packs the bits of every coded block into bytes from the lowest bit up, as DEFLATE and
+    /// many hardware decoders expect, instead of from the highest. Every byte of the payload
+    /// of such a block is reversed, the length of a filtered block it starts with and the
+    /// output of the range coder included. Blocks stored as they are and the fields framing
+    /// the payloads are left as they are.
+    pub fn lsb_first(mut self, enabled: bool) -> Self {
+        self.lsb_first = enabled;
+        self
+    }
+
+    /// Run-length codes every block before coding its letters, so long runs of the same
+    /// byte, as in sparse binaries and images, take a few bytes each.
+    pub fn rle(mut self, enabled: bool) -> Self {
+        self.rle = enabled;
+        self
+    }
+
+    /// Puts every block through the Burrows-Wheeler transform and move-to-front coding before
+    /// coding its letters, as bzip2 does. Text compresses much better, at the cost of sorting
+    /// every block. Best with byte letters and large blocks.
+    pub fn bwt(mut self, enabled: bool) -> Self {
+        self.bwt = enabled;
+        self
+    }
+
+    /// Keeps every code at most `length` bits long, so decoders can look codes up in tables
+    /// of a fixed size. Codes that come out longer are replaced by the best code within the
+    /// limit. Adaptive codes and code tables are left as they are.
+    pub fn max_code_length(mut self, length: u32) -> Self {
+        self.max_code_length = Some(length);
+        self
+    }
+
+    /// Stores `time` as the modification time of the input, in whole seconds, so it can be
+    /// restored on decompression. Times before the Unix epoch are left out.
+    pub fn mtime(mut self, time: SystemTime) -> Self {
+        self.mtime = Some(time);
+        self
+    }
+
+    /// Stores `mode`, the Unix permission bits of the input, so they can be restored on
+    /// decompression.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Stores a `checksum` of the input after the payload, verified on decompression. CRC32
+    /// by default, [`Checksum::None`] leaves it out.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Encrypts everything after the letter size and original size with a key derived from
+    /// `password`.
+    pub fn password(mut self, password: &'a [u8]) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Cipher used with a [`password`](Self::password), ChaCha20-Poly1305 by default.
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Codes the counted letters with a range coder instead of Huffman codes, which gets
+    /// closer to their entropy, above all for a few very common letters, at some cost in
+    /// speed. Needs letters of at most [`MAX_ARITHMETIC_LETTER_SIZE`] bits, and cannot be
+    /// combined with adaptive coding, LZ77 or a code table.
+    pub fn coder(mut self, coder: Coder) -> Self {
+        self.coder = coder;
+        self
+    }
+
+    /// Encodes with a table both sides agreed on ahead of time instead of one built from the
+    /// input, skipping the frequency pass and leaving the tree out of the output.
+    pub fn code_table(mut self, table: CodeTable) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    /// Registers a callback receiving `(bytes_processed, total)` periodically during both
+    /// the frequency pass and the encoding pass, so `total` is twice the input size. Static
+    /// tables and codes built per block skip the frequency pass and report the input size.
+    pub fn progress<F: FnMut(u64, u64) + 'a>(mut self, callback: F) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Checks `flag` periodically and aborts with a [`Cancelled`](crate::Cancelled) error
+    /// once it is set.
+    pub fn cancel_flag(mut self, flag: &'a AtomicBool) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    pub fn compress<R: Read + Seek, W: Write>(
+        &mut self,
+        input: R,
+        output: W,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let mut callback = self.progress.take();
+        let result = self.compress_seekable(&mut callback, input, |_, _, _| {
+            Ok((output, Kept::default()))
+        });
+        self.progress = callback;
+
+        result
+    }
+
+    /// Finishes the stream that compressing `input` into `output` with the same settings left
+    /// unfinished. The blocks at its start that are whole and decode to the input are kept,
+    /// the rest of the input is compressed after the last of them, and the stream is finished
+    /// there, so `output` has to be cut to the [`output_size`](CompressionReport::output_size)
+    /// of the report when it was longer. Output that does not start as compressing `input`
+    /// would, and encrypted output, whose key is new every time, is written afresh.
+    pub fn resume<R: Read + Seek, F: Read + Write + Seek>(
+        &mut self,
+        input: R,
+        mut output: F,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let mut callback = self.progress.take();
+        let result = self.compress_seekable(&mut callback, input, |input, file_size, codes| {
+            let kept = self.kept_blocks(input, &mut output, file_size, codes)?;
+            output.seek(SeekFrom::Start(kept.end))?;
+            Ok((output, kept))
+        });
+        self.progress = callback;
+
+        result
+    }
+
+    /// Compresses `input` in a single pass without seeking, so it can be a pipe or a live
+    /// stream whose size is not known ahead. Needs [`adaptive`](Self::adaptive) coding,
+    /// [codes per block](Self::block_trees) or a [code table](Self::code_table). The original
+    /// size is stored as [`UNKNOWN_SIZE`] and the stream has no block index.
+    pub fn compress_stream<R: Read, W: Write>(
+        &mut self,
+        input: R,
+        output: W,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let now = Instant::now();
+        check_letter_size(self.letter_size)?;
+        let _span = info_span!("compress_stream", letter_size = self.letter_size).entered();
+
+        if self.block_codes()?.shared() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "compressing without seeking needs adaptive coding, codes per block or a code table",
+            ));
+        }
+
+        let pool = self.pool()?;
+        let mut callback = self.progress.take();
+        let result = self.write_blocks(
+            input,
+            output,
+            Size::Unknown,
+            self.block_codes()?,
+            &pool,
+            &mut Progress::new(&mut callback, self.cancel, 0),
+        );
+        self.progress = callback;
+
+        let mut report = result?;
+        report.elapsed = now.elapsed();
+
+        Ok(report)
+    }
+
+    /// Compresses `input` into the output `open` gives once the codes are built, with the
+    /// input at its start, its size and the codes, along with the blocks kept in it.
+    fn compress_seekable<R, W, O>(
+        &self,
+        callback: &mut Callback<'a>,
+        mut input: R,
+        open: O,
+    ) -> Result<CompressionReport, std::io::Error>
+    where
+        R: Read + Seek,
+        W: Write,
+        O: FnOnce(&mut R, u64, &BlockCodes) -> Result<(W, Kept), std::io::Error>,
+    {
+        let now = Instant::now();
+        let letter_size = self.letter_size;
+        check_letter_size(letter_size)?;
+
+        let start = input.stream_position()?;
+        let file_size = input.seek(SeekFrom::End(0))? - start;
+        input.seek(SeekFrom::Start(start))?;
+
+        let _span = info_span!("compress", letter_size, file_size).entered();
+
+        let pool = self.pool()?;
+        let codes = self.block_codes()?;
+        let passes = match codes.shared() {
+            true => 2,
+            false => 1,
+        };
+        let mut progress = Progress::new(callback, self.cancel, file_size * passes);
+        let mut timings = Timings::default();
+
+        let codes = match codes {
+            codes if codes.shared() => {
+                let block_size = block_bytes(self.block_size, letter_size)?;
+                let phase = Instant::now();
+                let frequencies = debug_span!("count").in_scope(|| {
+                    let filters = self.filters();
+                    let order1 = self.order1;
+                    pool.count(
+                        &mut input,
+                        letter_size,
+                        block_size,
+                        filters,
+                        order1,
+                        &mut progress,
+                    )
+                })?;
+                input.seek(SeekFrom::Start(start))?;
+                timings.counting = phase.elapsed();
+                debug!(symbols = frequencies.len(), "counted letters");
+
+                let phase = Instant::now();
+                let codes = match self.coder {
+                    Coder::Huffman if self.order1 => {
+                        let contexts = debug_span!("build_tree").in_scope(|| {
+                            Contexts::build(frequencies, letter_size, self.max_code_length)
+                        })?;
+                        BlockCodes::SharedContexts(contexts)
+                    }
+                    Coder::Huffman => {
+                        let mut frequencies = frequencies;
+
+                        if self.eos {
+                            frequencies.add(1 << letter_size);
+                        }
+
+                        let code = debug_span!("build_tree")
+                            .in_scope(|| build_code(frequencies, self.max_code_length))?;
+                        let table = code.as_ref().map(CanonicalCode::table).unwrap_or_default();
+                        BlockCodes::Shared(code, table)
+                    }
+                    Coder::Arithmetic => {
+                        BlockCodes::SharedModel(Model::from_histogram(&frequencies))
+                    }
+                };
+                timings.tree = phase.elapsed();
+
+                codes
             }
+            codes => codes,
+        };
+
+        let (output, kept) = open(&mut input, file_size, &codes)?;
+        input.seek(SeekFrom::Start(start))?;
+        let size = Size::Known(file_size, kept);
+        let mut report = self.write_blocks(input, output, size, codes, &pool, &mut progress)?;
+        report.timings.counting = timings.counting;
+        report.timings.tree = timings.tree;
+        report.elapsed = now.elapsed();
+
+        Ok(report)
+    }
+
+    fn pool(&self) -> Result<Pool, std::io::Error> {
+        #[cfg(feature = "parallel")]
+        return self.threads.resolve();
+        #[cfg(not(feature = "parallel"))]
+        return Ok(Pool::Sequential);
+    }
+
+    fn filters(&self) -> Filters {
+        Filters {
+            bwt: self.bwt,
+            rle: self.rle,
         }
     }
 
-    let remaining = file_size * 8 - written as u64;
+    fn block_codes(&self) -> Result<BlockCodes<'_>, std::io::Error> {
+        if self.lz77 && (self.table.is_some() || self.adaptive) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "LZ77 coding stores codes of its own and cannot be combined with adaptive coding or a code table",
+            ));
+        }
 
-    if remaining != 0 {
-        reader.seek_bits(SeekFrom::Current(-(remaining as i64)))?;
-        let value = reader.read::<u32>(remaining as u32)?;
+        if self.lz77 && self.letter_size != 8 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "LZ77 coding works on bytes, so letters must be 8 bits",
+            ));
+        }
+
+        let arithmetic = self.coder == Coder::Arithmetic;
+
+        if arithmetic && (self.table.is_some() || self.adaptive || self.lz77) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "arithmetic coding cannot be combined with adaptive coding, LZ77 or a code table",
+            ));
+        }
+
+        if arithmetic && self.letter_size > MAX_ARITHMETIC_LETTER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "arithmetic coding needs letters of at most {} bits",
+                    MAX_ARITHMETIC_LETTER_SIZE
+                ),
+            ));
+        }
+
+        let others = self.table.is_some() || self.adaptive || self.lz77 || self.block_trees;
+
+        if self.order1 && (others || arithmetic) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "order-1 contexts share Huffman codes for the whole input and cannot be combined with adaptive coding, LZ77, arithmetic coding, codes per block or a code table",
+            ));
+        }
+
+        let counted = !(self.table.is_some() || self.adaptive || self.lz77 || arithmetic);
+
+        if self.eos && !(counted && !self.order1 && !self.rle && !self.bwt) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "an end-of-stream letter needs Huffman codes built from counted letters and cannot be combined with adaptive coding, LZ77, arithmetic coding, order-1 contexts, run-length coding, the Burrows-Wheeler transform or a code table",
+            ));
+        }
 
-        writer.write(remaining as u32, value)?;
+        Ok(match (&self.table, self.adaptive, self.block_trees) {
+            _ if self.lz77 => BlockCodes::Lz77,
+            _ if self.order1 => BlockCodes::SharedContexts(Contexts::default()),
+            _ if arithmetic && self.block_trees => BlockCodes::ModelPerBlock,
+            _ if arithmetic => BlockCodes::SharedModel(Model::default()),
+            (Some(table), _, _) => BlockCodes::Static(table),
+            (None, true, _) => BlockCodes::Adaptive,
+            (None, false, true) => BlockCodes::PerBlock,
+            (None, false, false) => BlockCodes::Shared(None, CodeTable::default()),
+        })
     }
 
-    writer.byte_align()?;
+    /// The fields a stream of `file_size` bytes of input, or of a size not known ahead, coded
+    /// as `codes` says starts with, up to where encryption starts, and its flags.
+    fn prefix(&self, file_size: Option<u64>, codes: &BlockCodes) -> (Vec<u8>, u16) {
+        let mtime = self
+            .mtime
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        let mut flags = 0;
 
-    Ok(())
+        // Offsets into encrypted chunks would be of no use, and the number of blocks of
+        // streamed input is not known to a reader, so only plain sized streams get an index.
+        if self.password.is_none() && file_size.is_some() {
+            flags |= INDEXED;
+        }
+
+        if mtime.is_some() {
+            flags |= MTIME;
+        }
+
+        if self.mode.is_some() {
+            flags |= MODE;
+        }
+
+        match codes {
+            BlockCodes::Adaptive => flags |= ADAPTIVE,
+            BlockCodes::Lz77 => flags |= LZ77 | CODED_LENGTHS,
+            BlockCodes::SharedModel(..) | BlockCodes::ModelPerBlock => flags |= ARITHMETIC,
+            BlockCodes::SharedContexts(..) => flags |= ORDER1 | CODED_LENGTHS,
+            BlockCodes::Shared(..) | BlockCodes::PerBlock => flags |= CODED_LENGTHS,
+            BlockCodes::Static(..) => {}
+        }
+
+        flags |= self.filters().flags();
+
+        if self.eos {
+            flags |= EOS;
+        }
+
+        if self.lsb_first {
+            flags |= LSB_FIRST;
+        }
+
+        if self.password.is_some() && self.encryption == Encryption::Aes256Gcm {
+            flags |= AES_GCM;
+        }
+
+        if flags > 0xff {
+            flags |= MORE_FLAGS;
+        }
+
+        let encrypted = (self.password.is_some() as u8) << 7;
+        let mut prefix = MAGIC.to_vec();
+        prefix.extend([
+            FORMAT_VERSION,
+            encrypted | self.checksum.id() << 5 | self.letter_size,
+            flags as u8,
+        ]);
+
+        if flags & MORE_FLAGS != 0 {
+            prefix.push((flags >> 8) as u8);
+        }
+
+        prefix.extend(file_size.unwrap_or(UNKNOWN_SIZE).to_be_bytes());
+
+        if let Some(mtime) = mtime {
+            prefix.extend(mtime.to_be_bytes());
+        }
+
+        if let Some(mode) = self.mode {
+            prefix.extend(mode.to_be_bytes());
+        }
+
+        (prefix, flags)
+    }
+
+    /// The blocks at the start of `output` that compressing `input` of `file_size` bytes with
+    /// `codes` wrote in an earlier run, none when it starts differently or is encrypted.
+    fn kept_blocks<R: Read, F: Read + Seek>(
+        &self,
+        input: &mut R,
+        output: &mut F,
+        file_size: u64,
+        codes: &BlockCodes,
+    ) -> Result<Kept, std::io::Error> {
+        if self.password.is_some() {
+            return Ok(Kept::default());
+        }
+
+        let block_size = block_bytes(self.block_size, self.letter_size)?;
+        let (mut header, _) = self.prefix(Some(file_size), codes);
+        header.extend((block_size as u32).to_be_bytes());
+
+        let mut stored = vec![0; header.len()];
+        output.seek(SeekFrom::Start(0))?;
+
+        match output.read_exact(&mut stored) {
+            Ok(()) if stored == header => {}
+            Ok(()) => return Ok(Kept::default()),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Kept::default()),
+            Err(e) => return Err(e),
+        }
+
+        let tree = match &self.table {
+            Some(table) => Some(Tree::from_code_table(table).ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "code table is not a complete prefix code",
+                )
+            })?),
+            None => None,
+        };
+        let kept = keep_blocks(
+            BufReader::new(output),
+            input,
+            &read_prefix(&mut SliceReader::new(&header))?,
+            header.len() as u64,
+            block_size as u64,
+            tree.as_ref(),
+            codes.shared(),
+        )?;
+
+        // The blocks after those kept are coded with the code built now, so it has to be the
+        // one stored with the first block.
+        match codes.shared() && !codes.stores(kept.code.as_ref()) {
+            true => Ok(Kept::default()),
+            false => Ok(kept),
+        }
+    }
+
+    /// Writes the stream for `input` of the size `size` gives, with its blocks coded as
+    /// `codes` says. Blocks kept from an earlier run are already in `output`, which goes on
+    /// after them.
+    fn write_blocks<R: Read, W: Write>(
+        &self,
+        mut input: R,
+        output: W,
+        size: Size,
+        codes: BlockCodes,
+        pool: &Pool,
+        progress: &mut Progress,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let letter_size = self.letter_size;
+        let block_size = block_bytes(self.block_size, letter_size)?;
+        let mut hasher = self.checksum.hasher()?;
+        let (file_size, kept) = match size {
+            Size::Known(size, kept) => (Some(size), kept),
+            Size::Unknown => (None, Kept::default()),
+        };
+        let (prefix, flags) = self.prefix(file_size, &codes);
+        let mut output = Sealer::new(Counter::new(output));
+
+        if kept.offsets.is_empty() {
+            output.write_all(&prefix)?;
+
+            if let Some(password) = self.password {
+                output.start(self.encryption, password)?;
+            }
+
+            output.write_all(&(block_size as u32).to_be_bytes())?;
+        }
+
+        let phase = Instant::now();
+        let checksum = self.checksum;
+        let max_code_length = self.max_code_length;
+        // Offsets count from the start of the stream, where the output starts unless blocks
+        // were kept.
+        let kept_end = kept.end;
+        let mut offsets = kept.offsets;
+        let kept_blocks = offsets.len();
+        let mut input_size = 0;
+        let mut last_len = block_size;
+        let mut header = Duration::ZERO;
+        let (mut symbol_count, mut tree_depth) = match &codes {
+            BlockCodes::Static(table) => (
+                table.len(),
+                table.iter().map(|(_, code)| code.length).max().unwrap_or(0),
+            ),
+            _ => (0, 0),
+        };
+        let block_digest = |block: &[u8]| match checksum {
+            Checksum::None => Vec::new(),
+            _ => crc32fast::hash(block).to_be_bytes().to_vec(),
+        };
+        // Payloads of filtered blocks start with their filtered length.
+        let filters = self.filters();
+        let filtered_len = |len: usize| match filters.is_empty() {
+            true => Vec::new(),
+            false => (len as u32).to_be_bytes().to_vec(),
+        };
+
+        let shares_code = codes.shared();
+        let eos = self.eos;
+        let lsb_first = self.lsb_first;
+        // The end-of-stream letter ends the last block of sized input, and the first block
+        // of streamed input shorter than the others.
+        let last_index = file_size.map(|size| size.saturating_sub(1) / block_size as u64);
+        let ends = |index: usize, block: &[u8]| {
+            eos && (block.len() < block_size || Some(index as u64) == last_index)
+        };
+        let encode = |index: usize, block: &[u8]| -> Result<EncodedBlock, std::io::Error> {
+            let digest = block_digest(block);
+            let end = ends(index, block);
+            let filtered = filters.apply(block);
+            let block = &filtered[..];
+            let mut payload = filtered_len(block.len());
+            let mut header = Duration::ZERO;
+
+            let built;
+            let (table, code) = match &codes {
+                BlockCodes::Static(table) => (*table, None),
+                BlockCodes::Shared(code, table) => (table, code.as_ref().filter(|_| index == 0)),
+                BlockCodes::PerBlock => {
+                    let mut frequencies = count(block, letter_size, &mut Progress::disabled())?;
+
+                    if end {
+                        frequencies.add(1 << letter_size);
+                    }
+
+                    built = build_code(frequencies, max_code_length)?
+                        .map(|code| (code.table(), code))
+                        .unwrap_or_default();
+                    (&built.0, Some(&built.1).filter(|code| !code.is_empty()))
+                }
+                BlockCodes::SharedContexts(contexts) => {
+                    let store = index == 0 && !contexts.is_empty();
+                    payload.extend(context::encode_block(
+                        block,
+                        letter_size,
+                        contexts,
+                        store,
+                        &mut header,
+                    )?);
+                    return Ok(EncodedBlock {
+                        payload,
+                        code: store.then(|| (contexts.len(), contexts.max_length())),
+                        digest,
+                        stored: false,
+                        header,
+                    });
+                }
+                BlockCodes::SharedModel(model) => {
+                    let store = index == 0 && !model.is_empty();
+                    return encode_with_model(payload, block, letter_size, model, store, digest);
+                }
+                BlockCodes::ModelPerBlock => {
+                    let frequencies = count(block, letter_size, &mut Progress::disabled())?;
+                    let model = Model::from_histogram(&frequencies);
+                    let store = !model.is_empty();
+                    return encode_with_model(payload, block, letter_size, &model, store, digest);
+                }
+                BlockCodes::Lz77 => {
+                    payload.extend(lz77::encode_block(block, max_code_length, &mut header)?);
+                    return Ok(EncodedBlock {
+                        payload,
+                        code: None,
+                        digest,
+                        stored: false,
+                        header,
+                    });
+                }
+                BlockCodes::Adaptive => {
+                    payload.extend(encode_adaptive(block, letter_size)?);
+                    return Ok(EncodedBlock {
+                        payload,
+                        code: None,
+                        digest,
+                        stored: false,
+                        header,
+                    });
+                }
+            };
+
+            payload.extend(encode_block(
+                block,
+                letter_size,
+                table,
+                code,
+                eos,
+                end,
+                &mut header,
+            )?);
+            Ok(EncodedBlock {
+                payload,
+                code: code.map(|code| (code.len(), code.max_length())),
+                digest,
+                stored: false,
+                header,
+            })
+        };
+
+        let code_block = |index: usize, block: &[u8]| -> Result<EncodedBlock, std::io::Error> {
+            let mut encoded = encode(index, block)?;
+
+            // Blocks that coding made no smaller are stored as they are, unless they carry
+            // the code of the blocks after them or the end-of-stream letter.
+            if encoded.payload.len() >= block.len()
+                && (index != 0 || !shares_code)
+                && !ends(index, block)
+            {
+                return Ok(EncodedBlock::stored(block, encoded.digest));
+            }
+
+            // Payloads are coded from the highest bit of every byte down, so the whole
+            // payload is reversed.
+            if lsb_first {
+                let payload = &mut encoded.payload;
+                payload
+                    .iter_mut()
+                    .for_each(|byte| *byte = byte.reverse_bits());
+            }
+
+            Ok(encoded)
+        };
+
+        // The input of the blocks kept is only hashed.
+        let mut block = Vec::with_capacity(block_size);
+
+        for _ in 0..kept_blocks {
+            block.clear();
+            input
+                .by_ref()
+                .take(block_size as u64)
+                .read_to_end(&mut block)?;
+            hasher.update(&block);
+            input_size += block.len() as u64;
+            last_len = block.len();
+            progress.advance(block.len() as u64 * 8)?;
+        }
+
+        debug_span!("encode").in_scope(|| {
+            pool.map_blocks(
+                &mut input,
+                block_size,
+                progress,
+                |index, block| code_block(kept_blocks + index, block),
+                |block, encoded| {
+                    if let Some((symbols, depth)) = encoded.code {
+                        symbol_count = symbol_count.max(symbols);
+                        tree_depth = tree_depth.max(depth);
+                    }
+
+                    header += encoded.header;
+
+                    hasher.update(block);
+                    input_size += block.len() as u64;
+                    last_len = block.len();
+                    offsets.push(kept_end + output.get_ref().count());
+
+                    let original_len = (file_size.is_none() && !eos).then_some(block.len());
+                    write_frame(&mut output, &encoded, original_len)
+                },
+            )
+        })?;
+
+        // Streamed input ends with a block shorter than the others, empty if need be, which
+        // holds only the end-of-stream letter when there is one and is stored otherwise.
+        if file_size.is_none() && last_len == block_size {
+            let end = code_block(offsets.len(), &[])?;
+            write_frame(&mut output, &end, (!eos).then_some(0))?;
+        }
+
+        output.write_all(&hasher.finish())?;
+
+        if flags & INDEXED != 0 {
+            let position = kept_end + output.get_ref().count();
+
+            for offset in offsets {
+                output.write_all(&offset.to_be_bytes())?;
+            }
+
+            output.write_all(&position.to_be_bytes())?;
+        }
+
+        output.finish()?;
+        progress.finish();
+        let output_size = kept_end + output.get_ref().count();
+        debug!(output_size, kept_blocks, "compressed");
+
+        Ok(CompressionReport {
+            letter_size,
+            input_size,
+            output_size,
+            symbol_count,
+            tree_depth,
+            // Blocks are coded on several threads, so the time spent writing their codes is
+            // summed over all of them.
+            timings: Timings {
+                header,
+                coding: phase.elapsed().saturating_sub(header),
+                ..Timings::default()
+            },
+            ..CompressionReport::default()
+        })
+    }
+
+    pub fn compress_file<P: AsRef<Path>>(
+        &mut self,
+        input_path: P,
+        output_path: P,
+    ) -> Result<CompressionReport, std::io::Error> {
+        let fin = File::open(&input_path)?;
+        let reader = BufReader::with_capacity(32 * 1024, fin);
+
+        let fout = File::create(&output_path)?;
+        let writer = BufWriter::with_capacity(32 * 1024, fout);
+
+        self.compress(reader, writer)
+    }
+}
+
+/// Streaming front end for a [`Compressor`]. Input is buffered until [`Encoder::finish`],
+/// since the tree is built from the whole input. Dropping an unfinished encoder finishes it,
+/// ignoring any error.
+pub struct Encoder<'a, W: Write> {
+    compressor: Compressor<'a>,
+    buffer: Vec<u8>,
+    inner: Option<W>,
+}
+
+impl<'a, W: Write> Encoder<'a, W> {
+    pub fn new(inner: W, letter_size: u8) -> Self {
+        Self::with_compressor(inner, Compressor::new(letter_size))
+    }
+
+    pub fn with_compressor(inner: W, compressor: Compressor<'a>) -> Self {
+        Encoder {
+            compressor,
+            buffer: Vec::new(),
+            inner: Some(inner),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Compresses everything written so far, flushes the final partial byte and returns
+    /// the inner writer.
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        self.write_output()
+    }
+
+    fn write_output(&mut self) -> Result<W, std::io::Error> {
+        let mut inner = self.inner.take().unwrap();
+        let buffer = std::mem::take(&mut self.buffer);
+
+        self.compressor.compress(Cursor::new(buffer), &mut inner)?;
+        inner.flush()?;
+
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for Encoder<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Encoder<'_, W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_output();
+        }
+    }
+}
+
+pub fn compress<R: Read + Seek, W: Write>(
+    input: R,
+    output: W,
+    letter_size: u8,
+) -> Result<CompressionReport, std::io::Error> {
+    Compressor::new(letter_size).compress(input, output)
 }
 
 pub fn compress_file<P: AsRef<Path>>(
     input_path: P,
     output_path: P,
     letter_size: u8,
-) -> Result<(), std::io::Error> {
-    let fin = File::open(&input_path)?;
-    let reader = BufReader::with_capacity(32 * 1024, fin);
-    let reader = BitReader::endian(reader, BigEndian);
+) -> Result<CompressionReport, std::io::Error> {
+    Compressor::new(letter_size).compress_file(input_path, output_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::Decompressor;
+    use crate::limits::Limits;
+
+    /// Text with a run of zeros and every byte value, long enough for several blocks.
+    fn sample() -> Vec<u8> {
+        let mut data = b"the quick brown fox jumps over the lazy dog. ".repeat(60);
+        data.extend([0; 700]);
+        data.extend(0..=255);
+        data
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        Decompressor::new()
+            .decompress(compressed, &mut output)
+            .unwrap();
+        output
+    }
+
+    type Configure = fn(Compressor<'static>) -> Compressor<'static>;
 
-    let fout = File::create(&output_path)?;
-    let writer = BufWriter::with_capacity(32 * 1024, fout);
-    let writer = BitWriter::endian(writer, BigEndian);
+    /// Every way of coding blocks with every combination of filters, end-of-stream letter and
+    /// bit order.
+    fn configurations() -> Vec<(String, Compressor<'static>)> {
+        let coders: [(&str, Configure); 7] = [
+            ("shared", |c| c),
+            ("block trees", |c| c.block_trees(true)),
+            ("adaptive", |c| c.adaptive(true)),
+            ("lz77", |c| c.lz77(true)),
+            ("order1", |c| c.order1(true)),
+            ("arithmetic", |c| c.coder(Coder::Arithmetic)),
+            ("arithmetic per block", |c| {
+                c.coder(Coder::Arithmetic).block_trees(true)
+            }),
+        ];
+        let mut configurations = Vec::new();
 
-    let nodes = count_frequency(&input_path, letter_size)?;
-    let file_size = std::fs::metadata(input_path)?.len();
-    compress(reader, writer, nodes, file_size, letter_size)
+        for letter_size in [3, 8, 13] {
+            for (name, coder) in coders {
+                for options in 0..16 {
+                    let compressor = coder(Compressor::new(letter_size))
+                        .block_size(1000)
+                        .rle(options & 1 != 0)
+                        .bwt(options & 2 != 0)
+                        .eos(options & 4 != 0)
+                        .lsb_first(options & 8 != 0);
+
+                    if compressor.block_codes().is_ok() {
+                        let name =
+                            format!("{} letter size {} options {}", name, letter_size, options);
+                        configurations.push((name, compressor));
+                    }
+                }
+            }
+        }
+
+        configurations
+    }
+
+    #[test]
+    fn round_trips_every_combination() {
+        let data = sample();
+        let configurations = configurations();
+        assert!(configurations.len() > 100);
+
+        for (name, mut compressor) in configurations {
+            for len in [0, 1, 2, 999, 1000, 1001, data.len()] {
+                let mut compressed = Vec::new();
+                compressor
+                    .compress(Cursor::new(&data[..len]), &mut compressed)
+                    .unwrap_or_else(|e| panic!("{} of {} bytes: {}", name, len, e));
+
+                assert_eq!(
+                    decompress(&compressed),
+                    &data[..len],
+                    "{} of {} bytes",
+                    name,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn resumes_unfinished_output() {
+        let data = sample();
+        let resume = |compressor: &mut Compressor, unfinished: &[u8]| {
+            let mut output = Cursor::new(unfinished.to_vec());
+            let report = compressor.resume(Cursor::new(&data), &mut output).unwrap();
+            let mut output = output.into_inner();
+            output.truncate(report.output_size as usize);
+            output
+        };
+
+        for (name, mut compressor) in configurations() {
+            let mut compressed = Vec::new();
+            compressor
+                .compress(Cursor::new(&data), &mut compressed)
+                .unwrap();
+
+            for cut in [0, 20, compressed.len() / 2, compressed.len()] {
+                let mut unfinished = compressed[..cut].to_vec();
+                unfinished.extend(b"left over");
+
+                let resumed = resume(&mut compressor, &unfinished);
+                assert_eq!(resumed, compressed, "{} cut at {}", name, cut);
+            }
+        }
+
+        // Blocks storing codes of their own are not kept for a code shared by all of them.
+        let mut unfinished = Vec::new();
+        Compressor::new(8)
+            .block_size(1000)
+            .block_trees(true)
+            .compress(Cursor::new(&data), &mut unfinished)
+            .unwrap();
+        unfinished.truncate(unfinished.len() / 2);
+
+        let resumed = resume(&mut Compressor::new(8).block_size(1000), &unfinished);
+        assert_eq!(decompress(&resumed), data);
+    }
+
+    #[test]
+    fn round_trips_streamed_input() {
+        let data = sample();
+
+        for (name, mut compressor) in configurations() {
+            for len in [0, 1, 1000, data.len()] {
+                let mut compressed = Vec::new();
+
+                match compressor.compress_stream(&data[..len], &mut compressed) {
+                    Err(e) if e.kind() == ErrorKind::InvalidInput => break,
+                    result => result.unwrap_or_else(|e| panic!("{}: {}", name, e)),
+                };
+
+                assert_eq!(
+                    decompress(&compressed),
+                    &data[..len],
+                    "{} of {} bytes",
+                    name,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_checksums_and_ciphers() {
+        let data = sample();
+        let checksums = Checksum::ALL
+            .into_iter()
+            .filter(|checksum| checksum.is_supported());
+        let encryptions = Encryption::ALL
+            .into_iter()
+            .filter(|_| Encryption::is_supported())
+            .map(Some);
+
+        for checksum in checksums {
+            for encryption in encryptions.clone().chain([None]) {
+                let mut compressor = Compressor::new(8)
+                    .block_size(1000)
+                    .checksum(checksum)
+                    .mtime(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+                    .mode(0o640);
+
+                if let Some(encryption) = encryption {
+                    compressor = compressor.password(b"secret").encryption(encryption);
+                }
+
+                let mut compressed = Vec::new();
+                compressor
+                    .compress(Cursor::new(&data), &mut compressed)
+                    .unwrap();
+
+                let mut output = Vec::new();
+                Decompressor::new()
+                    .password(b"secret")
+                    .decompress(&compressed[..], &mut output)
+                    .unwrap();
+
+                assert_eq!(output, data, "{:?} {:?}", checksum, encryption);
+
+                let format = crate::detect(&compressed[..]).unwrap();
+                assert_eq!(
+                    format.mtime,
+                    Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+                );
+                assert_eq!(format.mode, Some(0o640));
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_options_fail_early() {
+        let unsupported = Checksum::ALL
+            .into_iter()
+            .filter(|checksum| !checksum.is_supported());
+
+        for checksum in unsupported {
+            let mut compressed = Vec::new();
+            let error = Compressor::new(8)
+                .checksum(checksum)
+                .compress(Cursor::new(b"data"), &mut compressed)
+                .unwrap_err();
+
+            assert_eq!(error.kind(), ErrorKind::Unsupported);
+            assert!(compressed.is_empty());
+        }
+
+        if !Encryption::is_supported() {
+            let error = Compressor::new(8)
+                .password(b"secret")
+                .compress(Cursor::new(b"data"), &mut Vec::new())
+                .unwrap_err();
+
+            assert_eq!(error.kind(), ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn code_lengths_round_trip() {
+        let mut frequencies = Histogram::new();
+
+        for symbol in 0..40u32 {
+            for _ in 0..=symbol * symbol {
+                frequencies.add(symbol * 3);
+            }
+        }
+
+        let code = build_code(frequencies, None).unwrap().unwrap();
+
+        for coded in [false, true] {
+            let mut buffer = BitBuffer::default();
+            write_header(&mut buffer, &code, 8, coded).unwrap();
+
+            let bytes = buffer.into_bytes();
+            let read = crate::decode::read_header(
+                &mut SliceReader::new(&bytes),
+                8,
+                &Limits::default(),
+                coded,
+            )
+            .unwrap();
+
+            assert_eq!(read.symbols(), code.symbols());
+            assert_eq!(read.counts(), code.counts());
+        }
+    }
 }
@@ -0,0 +1,124 @@
+use std::io::{Cursor, ErrorKind};
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+use crate::decode::decompress;
+use crate::encode::compress;
+
+pub const HUFFMAN_OK: c_int = 0;
+pub const HUFFMAN_INVALID_ARGUMENT: c_int = 1;
+pub const HUFFMAN_INVALID_DATA: c_int = 2;
+pub const HUFFMAN_ERROR: c_int = 3;
+
+fn error_code(e: &std::io::Error) -> c_int {
+    match e.kind() {
+        ErrorKind::InvalidInput => HUFFMAN_INVALID_ARGUMENT,
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => HUFFMAN_INVALID_DATA,
+        _ => HUFFMAN_ERROR,
+    }
+}
+
+unsafe fn input_slice<'a>(input: *const u8, input_len: usize) -> Option<&'a [u8]> {
+    if input_len == 0 {
+        Some(&[])
+    } else if input.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(input, input_len))
+    }
+}
+
+unsafe fn store_output(buffer: Vec<u8>, output: *mut *mut u8, output_len: *mut usize) {
+    let buffer = buffer.into_boxed_slice();
+    *output_len = buffer.len();
+    *output = Box::into_raw(buffer) as *mut u8;
+}
+
+/// Compresses `input_len` bytes from `input` into a newly allocated buffer.
+///
+/// On success the buffer is stored in `output`/`output_len` and must be released
+/// with `huffman_free`.
+///
+/// # Safety
+///
+/// `input` must point to `input_len` readable bytes (or may be null when `input_len` is 0),
+/// and `output`/`output_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn huffman_compress(
+    input: *const u8,
+    input_len: usize,
+    letter_size: u8,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> c_int {
+    if output.is_null() || output_len.is_null() {
+        return HUFFMAN_INVALID_ARGUMENT;
+    }
+
+    let input = match input_slice(input, input_len) {
+        Some(input) => input,
+        None => return HUFFMAN_INVALID_ARGUMENT,
+    };
+
+    let mut buffer = Vec::new();
+
+    match compress(Cursor::new(input), &mut buffer, letter_size) {
+        Err(e) => error_code(&e),
+        Ok(_) => {
+            store_output(buffer, output, output_len);
+            HUFFMAN_OK
+        }
+    }
+}
+
+/// Decompresses `input_len` bytes from `input` into a newly allocated buffer.
+///
+/// On success the buffer is stored in `output`/`output_len` and must be released
+/// with `huffman_free`.
+///
+/// # Safety
+///
+/// `input` must point to `input_len` readable bytes (or may be null when `input_len` is 0),
+/// and `output`/`output_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn huffman_decompress(
+    input: *const u8,
+    input_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> c_int {
+    if output.is_null() || output_len.is_null() {
+        return HUFFMAN_INVALID_ARGUMENT;
+    }
+
+    let input = match input_slice(input, input_len) {
+        Some(input) => input,
+        None => return HUFFMAN_INVALID_ARGUMENT,
+    };
+
+    let mut buffer = Vec::new();
+
+    match decompress(input, &mut buffer) {
+        Err(e) => error_code(&e),
+        Ok(_) => {
+            store_output(buffer, output, output_len);
+            HUFFMAN_OK
+        }
+    }
+}
+
+/// Releases a buffer returned by `huffman_compress` or `huffman_decompress`.
+///
+/// # Safety
+///
+/// `buffer` and `len` must be exactly as returned by this library, and the buffer
+/// must not be freed twice. Passing a null pointer is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn huffman_free(buffer: *mut u8, len: usize) {
+    if buffer.is_null() {
+        return;
+    }
+
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len)));
+}
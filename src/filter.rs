@@ -0,0 +1,249 @@
+use std::borrow::Cow;
+
+use crate::format::{BWT, RLE};
+
+/// Bytes of a run written out before its remaining length.
+const RUN_START: usize = 4;
+
+/// Bytes of the row number a transformed block starts with.
+const PRIMARY_SIZE: usize = 4;
+
+/// Pre-filters applied to every block before its letters are coded and undone after they
+/// are decoded, the Burrows-Wheeler transform and move-to-front coding first, then run-length
+/// coding. Each is marked by a flag of the stream.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub(crate) struct Filters {
+    pub bwt: bool,
+    pub rle: bool,
+}
+
+impl Filters {
+    pub fn from_flags(flags: u16) -> Self {
+        Filters {
+            bwt: flags & BWT != 0,
+            rle: flags & RLE != 0,
+        }
+    }
+
+    pub fn flags(self) -> u16 {
+        ((self.bwt as u16) * BWT) | ((self.rle as u16) * RLE)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Filters::default()
+    }
+
+    /// Length of a block of `block_len` bytes after the transform.
+    fn transformed_len(self, block_len: usize) -> usize {
+        match self.bwt {
+            true => block_len + PRIMARY_SIZE,
+            false => block_len,
+        }
+    }
+
+    /// Longest filtered form of a block of `block_len` bytes.
+    pub fn max_len(self, block_len: usize) -> usize {
+        let len = self.transformed_len(block_len);
+
+        match self.rle {
+            true => len + len / RUN_START,
+            false => len,
+        }
+    }
+
+    pub fn apply(self, block: &[u8]) -> Cow<'_, [u8]> {
+        let mut data = Cow::Borrowed(block);
+
+        if self.bwt {
+            data = Cow::Owned(transform(&data));
+        }
+
+        if self.rle {
+            data = Cow::Owned(encode_runs(&data));
+        }
+
+        data
+    }
+
+    /// Turns `data` back into the block it was made from, which must fill `block` exactly.
+    /// Returns `None` when it does not.
+    pub fn undo(self, data: &[u8], block: &mut [u8]) -> Option<()> {
+        let mut data = Cow::Borrowed(data);
+
+        if self.rle {
+            let mut runs = vec![0; self.transformed_len(block.len())];
+            decode_runs(&data, &mut runs)?;
+            data = Cow::Owned(runs);
+        }
+
+        if data.len() != self.transformed_len(block.len()) {
+            return None;
+        }
+
+        match self.bwt {
+            true => untransform(&data, block),
+            false => {
+                block.copy_from_slice(&data);
+                Some(())
+            }
+        }
+    }
+}
+
+/// Indices of the rotations of `data` in sorted order, found by prefix doubling: rotations
+/// are ranked by their first `k` bytes, then by pairs of those ranks for `2k` bytes.
+fn sort_rotations(data: &[u8]) -> Vec<usize> {
+    let n = data.len();
+    let mut order = (0..n).collect::<Vec<_>>();
+    let mut rank = data.iter().map(|&byte| byte as usize).collect::<Vec<_>>();
+    let mut next = vec![0; n];
+    let mut k = 1;
+
+    while k < n {
+        let key = |i: usize| (rank[i], rank[(i + k) % n]);
+        order.sort_unstable_by_key(|&i| key(i));
+
+        next[order[0]] = 0;
+        for pair in order.windows(2) {
+            next[pair[1]] = next[pair[0]] + (key(pair[0]) != key(pair[1])) as usize;
+        }
+
+        std::mem::swap(&mut rank, &mut next);
+
+        if rank[order[n - 1]] == n - 1 {
+            break;
+        }
+
+        k *= 2;
+    }
+
+    order
+}
+
+/// The Burrows-Wheeler transform of `block` as the big endian `u32` row of the block itself
+/// among its sorted rotations, then the last byte of every rotation, move-to-front coded.
+fn transform(block: &[u8]) -> Vec<u8> {
+    let n = block.len();
+    let order = match n {
+        0 => Vec::new(),
+        _ => sort_rotations(block),
+    };
+    let primary = order.iter().position(|&i| i == 0).unwrap_or(0);
+
+    let mut output = Vec::with_capacity(n + PRIMARY_SIZE);
+    output.extend((primary as u32).to_be_bytes());
+    output.extend(order.iter().map(|&i| block[(i + n - 1) % n]));
+    move_to_front(&mut output[PRIMARY_SIZE..]);
+
+    output
+}
+
+fn untransform(data: &[u8], block: &mut [u8]) -> Option<()> {
+    let (primary, last) = data.split_at(PRIMARY_SIZE);
+    let mut row = u32::from_be_bytes(primary.try_into().unwrap()) as usize;
+    let mut last = last.to_vec();
+    move_from_front(&mut last);
+
+    if row >= last.len().max(1) {
+        return None;
+    }
+
+    // Row `i` continues at `next[i]`, the row starting with its last byte.
+    let mut starts = [0; 256];
+    for &byte in &last {
+        starts[byte as usize] += 1;
+    }
+    let mut total = 0;
+    for start in &mut starts {
+        (*start, total) = (total, total + *start);
+    }
+
+    let next = last
+        .iter()
+        .map(|&byte| {
+            let row = starts[byte as usize];
+            starts[byte as usize] += 1;
+            row
+        })
+        .collect::<Vec<_>>();
+
+    for position in (0..block.len()).rev() {
+        block[position] = last[row];
+        row = next[row];
+    }
+
+    Some(())
+}
+
+/// Replaces every byte with its position in a list of all bytes, then moves it to the front.
+fn move_to_front(data: &mut [u8]) {
+    let mut list = std::array::from_fn::<u8, 256, _>(|i| i as u8);
+
+    for byte in data {
+        let position = list.iter().position(|&entry| entry == *byte).unwrap();
+        list.copy_within(..position, 1);
+        list[0] = *byte;
+        *byte = position as u8;
+    }
+}
+
+fn move_from_front(data: &mut [u8]) {
+    let mut list = std::array::from_fn::<u8, 256, _>(|i| i as u8);
+
+    for byte in data {
+        let position = *byte as usize;
+        let value = list[position];
+        list.copy_within(..position, 1);
+        list[0] = value;
+        *byte = value;
+    }
+}
+
+/// Run-length codes `block` as bzip2 does before sorting: runs of 4 to 259 equal bytes are
+/// written as 4 of them and a byte counting the rest, everything else as it is.
+fn encode_runs(block: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(block.len());
+    let mut rest = block;
+
+    while let Some(&byte) = rest.first() {
+        let run = rest
+            .iter()
+            .take(RUN_START + u8::MAX as usize)
+            .take_while(|&&next| next == byte)
+            .count();
+
+        output.extend(std::iter::repeat_n(byte, run.min(RUN_START)));
+
+        if run >= RUN_START {
+            output.push((run - RUN_START) as u8);
+        }
+
+        rest = &rest[run..];
+    }
+
+    output
+}
+
+fn decode_runs(data: &[u8], block: &mut [u8]) -> Option<()> {
+    let mut position = 0;
+    let mut written = 0;
+
+    while let Some(&byte) = data.get(position) {
+        let mut run = data[position..]
+            .iter()
+            .take(RUN_START)
+            .take_while(|&&next| next == byte)
+            .count();
+        position += run;
+
+        if run == RUN_START {
+            run += *data.get(position)? as usize;
+            position += 1;
+        }
+
+        block.get_mut(written..written + run)?.fill(byte);
+        written += run;
+    }
+
+    (written == block.len()).then_some(())
+}
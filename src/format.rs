@@ -0,0 +1,302 @@
+use std::io::{ErrorKind, Read};
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+use bitstream_io::{BigEndian, BitReader};
+
+use crate::arithmetic::Coder;
+use crate::bits::{BitSource, SliceReader};
+use crate::checksum::Checksum;
+use crate::crypto::Encryption;
+use crate::decode::{max_payload, read_header, read_prefix};
+use crate::limits::Limits;
+use crate::tree::Tree;
+
+/// Version of the layout written by this crate: [`MAGIC`], this version byte, a letter size
+/// byte whose bits 5 and 6 name the checksum and whose top bit marks encryption, a flags byte
+/// and with its [`MORE_FLAGS`] flag a second one, the original size as a big endian `u64`,
+/// the block size as a big endian `u32`, the blocks, then the checksum of the original data.
+/// Every block is framed with a flags byte, its payload length and, unless the checksum is
+/// left out, a CRC32 of its original bytes. Its payload holds the code lengths of a canonical
+/// code when bit 0 of its flags is set, then the codes. Blocks that coding would not make
+/// smaller set bit 1 instead and their payload is the original block.
+/// With the [`MTIME`] flag the original size is followed by the modification time of the
+/// input in seconds since the Unix epoch as a big endian `u64`. With the [`INDEXED`] flag the
+/// checksum is followed by the offset of every block from the
+/// start of the stream and then the offset of that index, all as big endian `u64`s. With the
+/// [`ADAPTIVE`] flag no block stores a code, each is coded with an adaptive code of its own.
+/// With the [`LZ77`] flag every payload holds the code lengths of a code for the byte values
+/// and 29 match length codes as 9 bit letters, those of a code for 30 distance codes as 5 bit
+/// letters, then the literals and matches of the block coded as in DEFLATE, back to at most
+/// 32 KiB before them within the block. With the [`ARITHMETIC`] flag the payloads store
+/// letter frequencies instead of code lengths and the letters are range coded with them.
+/// With the [`CODED_LENGTHS`] flag code lengths may also be stored run-length coded with a
+/// code of their own. With the [`ORDER1`] flag the payload of the first block starts with a
+/// bit for each of the contexts the letter before a letter gives, set for those with a code,
+/// then the code lengths of those codes, and every letter is coded with the code of its
+/// context, the first one of a block with that of context 0. With the [`EOS`] flag codes
+/// are stored for letters one bit wider, the letter `1 << letter_size` ending the data: the
+/// last block, which is never stored as it is, ends its letters with it, then the number of
+/// bits of a trailing partial letter in 5 bits and those bits. Blocks of streams that store
+/// [`UNKNOWN_SIZE`] carry no original length and end with the first one shorter than the
+/// block size, which holds only the letter when the data ends on a whole block. With the
+/// [`LSB_FIRST`] flag every byte of every payload that is not a stored block, including the
+/// length of a filtered block that starts it, has its bits reversed, so they are packed from
+/// the lowest bit up.
+/// With the [`BWT`] flag every block was first replaced by the big endian `u32` row of the
+/// block among its sorted rotations and the move-to-front codes of their last bytes. With the
+/// [`RLE`] flag every block, or what the transform made of it, was then run-length coded,
+/// runs of 4 to 259 equal bytes becoming 4 of them and a count of the rest. The payloads of
+/// filtered blocks start with the length of the result as a big endian `u32`. Streams that
+/// store [`UNKNOWN_SIZE`] as their original size frame every block with its original length
+/// as a big endian `u32` after the flags byte. With the [`MODE`] flag the original size, or
+/// the modification time, is followed by the Unix permission bits of the input as a big
+/// endian `u32`. Encrypted streams hold everything after those fields in chunks sealed with
+/// ChaCha20-Poly1305, or AES-256-GCM with the [`AES_GCM`] flag.
+///
+/// Streams of earlier versions cannot be read. Baseline streams, written before there was a
+/// version, start with their letter size byte instead and are read by
+/// [`Decompressor::decompress`](crate::decode::Decompressor::decompress) alone.
+pub const FORMAT_VERSION: u8 = 5;
+
+/// Versions this build can read.
+pub const FORMAT_VERSIONS: RangeInclusive<u8> = FORMAT_VERSION..=FORMAT_VERSION;
+
+/// First bytes of every compressed stream.
+pub const MAGIC: &[u8; 4] = b"HUFF";
+
+/// Bytes taken by the fixed fields at the start of streams of the current version.
+pub const PREFIX_SIZE: u64 = 15;
+
+/// Flag marking a block index at the end of the stream. Only unencrypted streams carry one.
+pub const INDEXED: u16 = 0x0001;
+
+/// Flag marking a stored modification time.
+pub const MTIME: u16 = 0x0002;
+
+/// Flag marking blocks coded with an adaptive code, which store no code of their own.
+pub const ADAPTIVE: u16 = 0x0004;
+
+/// Flag marking encrypted streams sealed with AES-256-GCM rather than ChaCha20-Poly1305.
+pub const AES_GCM: u16 = 0x0008;
+
+/// Flag marking blocks run-length coded before their letters were.
+pub const RLE: u16 = 0x0010;
+
+/// Flag marking blocks put through the Burrows-Wheeler transform and move-to-front coding
+/// before their letters were coded.
+pub const BWT: u16 = 0x0020;
+
+/// Flag marking blocks coded as LZ77 matches and literals, which store codes of their own
+/// that the block flags do not count. Such streams have 8 bit letters.
+pub const LZ77: u16 = 0x0040;
+
+/// Flag marking blocks coded with a range coder from letter frequencies, stored where codes
+/// would be, rather than with Huffman codes. Such streams have letters of at most
+/// [`MAX_ARITHMETIC_LETTER_SIZE`](crate::MAX_ARITHMETIC_LETTER_SIZE) bits.
+pub const ARITHMETIC: u16 = 0x0100;
+
+/// Flag marking code lengths that may be stored as a coded list, run-length coded and coded
+/// with a code of their own.
+pub const CODED_LENGTHS: u16 = 0x0200;
+
+/// Flag marking letters coded with a code for the letter before them, or its top 8 bits, of
+/// which the first block stores one for every context that occurs.
+pub const ORDER1: u16 = 0x0400;
+
+/// Flag marking streams whose last block ends with an end-of-stream letter, so streams of
+/// unknown size need not frame blocks with their original length.
+pub const EOS: u16 = 0x0800;
+
+/// Flag marking payloads whose bits are packed from the lowest bit of every byte up.
+pub const LSB_FIRST: u16 = 0x1000;
+
+/// Flag marking stored Unix permission bits.
+pub const MODE: u16 = 0x2000;
+
+/// Flag marking a second flags byte after the first, holding the flags from 0x0100 on.
+pub const MORE_FLAGS: u16 = 0x0080;
+
+/// Flags this build can read.
+pub const KNOWN_FLAGS: u16 = INDEXED
+    | MTIME
+    | ADAPTIVE
+    | AES_GCM
+    | RLE
+    | BWT
+    | LZ77
+    | MORE_FLAGS
+    | ARITHMETIC
+    | CODED_LENGTHS
+    | ORDER1
+    | EOS
+    | LSB_FIRST
+    | MODE;
+
+/// Original size stored by streams written without knowing it. Their blocks carry their
+/// original length, and the first one shorter than the block size ends the stream.
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// Letter sizes in bits the format can store. Letters are counted and coded sparsely, so
+/// sizes above 16 bits cost memory for the letters that occur, not for every possible one.
+pub const LETTER_SIZES: RangeInclusive<u8> = 2..=24;
+
+/// Letter sizes of baseline streams, which start with their letter size byte instead of
+/// [`MAGIC`].
+pub(crate) const BASELINE_LETTER_SIZES: RangeInclusive<u8> = 2..=16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FormatInfo {
+    pub version: u8,
+    pub letter_size: u8,
+    pub checksum: Checksum,
+    pub encrypted: bool,
+    /// Cipher of encrypted streams.
+    pub encryption: Option<Encryption>,
+    /// Whether the stream ends with a block index, so byte ranges can be extracted.
+    pub indexed: bool,
+    /// Whether the blocks are coded with an adaptive code.
+    pub adaptive: bool,
+    /// Whether the blocks are coded as LZ77 matches and literals.
+    pub lz77: bool,
+    /// What the letters are coded with.
+    pub coder: Coder,
+    /// Whether every letter is coded with a code for the letter before it.
+    pub order1: bool,
+    /// Whether the data ends with an end-of-stream letter.
+    pub eos: bool,
+    /// Whether the bits of payloads are packed from the lowest bit of every byte up.
+    pub lsb_first: bool,
+    /// Whether the blocks were run-length coded first.
+    pub rle: bool,
+    /// Whether the blocks were put through the Burrows-Wheeler transform and move-to-front
+    /// coding first.
+    pub bwt: bool,
+    /// [`UNKNOWN_SIZE`] when the stream was written without knowing it.
+    pub original_size: u64,
+    /// Modification time of the original input, when it was stored.
+    pub mtime: Option<SystemTime>,
+    /// Unix permission bits of the original input, when they were stored.
+    pub mode: Option<u32>,
+}
+
+/// Reads the start of `reader` and reports its format if it is output of this crate in a
+/// version this build can read.
+pub fn detect<R: Read>(reader: R) -> Option<FormatInfo> {
+    let prefix = read_prefix(&mut BitReader::endian(reader, BigEndian)).ok()?;
+
+    Some(prefix.format_info())
+}
+
+/// What the header of a compressed stream says about it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StreamInfo {
+    pub format: FormatInfo,
+    /// Letters of the code of the first block, `None` when it cannot be read: the stream is
+    /// encrypted, has no single code table, or its first block stores none.
+    pub symbol_count: Option<usize>,
+    pub tree_depth: Option<u32>,
+}
+
+/// Reads the fixed fields and the tree header of a compressed stream without decoding the
+/// payload.
+pub fn inspect<R: Read>(reader: R) -> Result<StreamInfo, std::io::Error> {
+    let (format, tree) = read_first_tree(reader)?;
+    let empty = format.original_size.saturating_mul(8) < format.letter_size as u64;
+
+    Ok(StreamInfo {
+        format,
+        symbol_count: tree.as_ref().map(Tree::leaf_count).or(empty.then_some(0)),
+        tree_depth: tree.as_ref().map(Tree::depth).or(empty.then_some(0)),
+    })
+}
+
+/// Reads the fixed fields and the tree of a compressed stream, the one stored with the first
+/// block for blocked streams. The tree is `None` when the input was too short to hold a
+/// single letter, its first block stores no code or it is encrypted. Streams that are
+/// arithmetic coded, adaptive, LZ77 coded or have a code for every context store no single
+/// code table and fail with [`ErrorKind::Unsupported`].
+pub fn read_tree<R: Read>(reader: R) -> Result<(FormatInfo, Option<Tree>), std::io::Error> {
+    let (format, tree) = read_first_tree(reader)?;
+
+    if !single_code(&format) {
+        return Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "this file stores no Huffman code table",
+        ));
+    }
+
+    Ok((format, tree))
+}
+
+/// Whether the letters of a stream are coded with one code table, stored with its first block.
+fn single_code(format: &FormatInfo) -> bool {
+    format.coder == Coder::Huffman && !format.adaptive && !format.lz77 && !format.order1
+}
+
+/// Reads what [`read_tree`] does, with no tree for streams without a single code table.
+fn read_first_tree<R: Read>(reader: R) -> Result<(FormatInfo, Option<Tree>), std::io::Error> {
+    let mut reader = BitReader::endian(reader, BigEndian);
+    let prefix = read_prefix(&mut reader)?;
+    let (letter_size, original_size) = (prefix.letter_size, prefix.file_size);
+    let format = prefix.format_info();
+
+    if !single_code(&format)
+        || prefix.encrypted
+        || original_size.saturating_mul(8) < letter_size as u64
+    {
+        return Ok((format, None));
+    }
+
+    // Block size, then the flags, original length when the size is unknown and payload
+    // length of the first block.
+    let block_size = reader.get_bits(32)? as usize;
+    let flags = reader.get_bits(8)?;
+
+    if prefix.framed() {
+        reader.get_bits(32)?;
+    }
+
+    let payload_len = reader.get_bits(32)? as usize;
+
+    if !prefix.checksum.is_empty() {
+        reader.get_bits(32)?;
+    }
+
+    if flags & 1 == 0 {
+        return Ok((format, None));
+    }
+
+    if payload_len > max_payload(block_size) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid frame for block 0",
+        ));
+    }
+
+    let mut payload = vec![0; payload_len];
+    reader.get_bytes(&mut payload)?;
+
+    if format.lsb_first {
+        payload
+            .iter_mut()
+            .for_each(|byte| *byte = byte.reverse_bits());
+    }
+
+    let mut reader = SliceReader::new(&payload);
+
+    // The length of the filtered block comes before the code.
+    if format.rle || format.bwt {
+        reader.get_bits(32)?;
+    }
+
+    let letter_size = letter_size + format.eos as u8;
+    let limits = Limits::unlimited();
+    let tree = read_header(&mut reader, letter_size, &limits, prefix.coded_lengths())?
+        .tree()
+        .ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidData, "code is not a complete prefix code")
+        })?;
+
+    Ok((format, Some(tree)))
+}
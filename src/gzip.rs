@@ -0,0 +1,445 @@
+use std::io::{Read, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::encode::build_code;
+use crate::histogram::Histogram;
+use crate::lz77::{
+    self, Token, DISTANCE_BASES, DISTANCE_EXTRA, LENGTH_BASES, LENGTH_EXTRA, WINDOW,
+};
+use crate::report::{CompressionReport, Timings};
+
+/// Input bytes coded as one DEFLATE block, with the smallest of a code of their own, the
+/// fixed code or none.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Most bytes a stored block holds.
+const MAX_STORED: usize = 65535;
+
+const MAX_CODE_LENGTH: u32 = 15;
+const MAX_LENGTH_CODE_LENGTH: u32 = 7;
+
+/// Literal/length codes: the byte values, the end of a block and 29 match length codes.
+const LITERALS: usize = 286;
+const DISTANCES: usize = 30;
+const END_OF_BLOCK: usize = 256;
+
+/// Order the code lengths of the code for code lengths are stored in.
+const LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Operating system byte of streams not written for any one.
+const UNKNOWN_OS: u8 = 255;
+
+/// Writes bits least significant first, as DEFLATE packs them.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn put_bits(&mut self, bits: u32, value: u32) {
+        self.buffer |= (value as u64) << self.bits;
+        self.bits += bits;
+
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits != 0 {
+            self.put_bits(8 - self.bits, 0);
+        }
+    }
+
+    /// Takes the whole bytes written so far.
+    fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+/// A DEFLATE code: the code length of every symbol, 0 for unused ones, and the codes
+/// bit-reversed, as DEFLATE writes them starting from their first bit.
+struct Code {
+    lengths: Vec<u32>,
+    codes: Vec<u32>,
+}
+
+impl Code {
+    /// The code for symbols counted as `frequencies`, of at least two symbols so that every
+    /// code takes a bit.
+    fn build(frequencies: &[usize], max_length: u32) -> Result<Self, std::io::Error> {
+        let mut histogram = Histogram::new();
+
+        for (symbol, &count) in frequencies.iter().enumerate() {
+            if count != 0 {
+                histogram.add_count(symbol as u32, count);
+            }
+        }
+
+        for symbol in 0..2 {
+            if histogram.len() < 2 && histogram.get(&symbol) == 0 {
+                histogram.add(symbol);
+            }
+        }
+
+        let mut lengths = vec![0; frequencies.len()];
+
+        if let Some(code) = build_code(histogram, Some(max_length))? {
+            for (symbol, length) in code.lengths() {
+                lengths[symbol as usize] = length;
+            }
+        }
+
+        Ok(Self::from_lengths(lengths))
+    }
+
+    fn from_lengths(lengths: Vec<u32>) -> Self {
+        let mut counts = [0u32; MAX_CODE_LENGTH as usize + 1];
+
+        for &length in &lengths {
+            counts[length as usize] += 1;
+        }
+
+        counts[0] = 0;
+
+        let mut next = [0u32; MAX_CODE_LENGTH as usize + 1];
+
+        for length in 1..next.len() {
+            next[length] = (next[length - 1] + counts[length - 1]) << 1;
+        }
+
+        let mut codes = vec![0; lengths.len()];
+
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                codes[symbol] = next[length as usize].reverse_bits() >> (32 - length);
+                next[length as usize] += 1;
+            }
+        }
+
+        Code { lengths, codes }
+    }
+
+    /// The fixed literal/length and distance codes.
+    fn fixed() -> (Self, Self) {
+        let literals = (0..LITERALS)
+            .map(|symbol| match symbol {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            })
+            .collect();
+
+        (
+            Self::from_lengths(literals),
+            Self::from_lengths(vec![5; DISTANCES]),
+        )
+    }
+
+    /// Bits taken by symbols counted as `frequencies`.
+    fn cost(&self, frequencies: &[usize]) -> u64 {
+        self.lengths
+            .iter()
+            .zip(frequencies)
+            .map(|(&length, &count)| length as u64 * count as u64)
+            .sum()
+    }
+
+    fn put(&self, writer: &mut BitWriter, symbol: usize) {
+        writer.put_bits(self.lengths[symbol], self.codes[symbol]);
+    }
+}
+
+/// Extra bits after code length symbol `symbol`, which repeat the previous length or zeros.
+fn repeat_bits(symbol: usize) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Run-length codes `lengths` as the code length symbols of DEFLATE and their extra bits.
+fn code_length_symbols(lengths: &[u32]) -> Vec<(usize, u32)> {
+    let mut symbols = Vec::new();
+    let mut position = 0;
+
+    while position < lengths.len() {
+        let length = lengths[position];
+        let run = lengths[position..]
+            .iter()
+            .take_while(|&&next| next == length)
+            .count();
+
+        if length == 0 && run >= 3 {
+            let run = run.min(138);
+            symbols.push(match run {
+                3..=10 => (17, run as u32 - 3),
+                _ => (18, run as u32 - 11),
+            });
+            position += run;
+        } else if run >= 4 {
+            symbols.push((length as usize, 0));
+            position += 1;
+
+            let mut rest = run - 1;
+
+            while rest >= 3 {
+                let repeat = rest.min(6);
+                symbols.push((16, repeat as u32 - 3));
+                rest -= repeat;
+                position += repeat;
+            }
+        } else {
+            symbols.push((length as usize, 0));
+            position += 1;
+        }
+    }
+
+    symbols
+}
+
+/// How a block with codes of its own stores them.
+struct DynamicHeader {
+    literals: usize,
+    distances: usize,
+    symbols: Vec<(usize, u32)>,
+    code: Code,
+    /// Code lengths of the code for code lengths that are stored, in [`LENGTH_ORDER`].
+    stored: usize,
+}
+
+impl DynamicHeader {
+    fn new(literals: &Code, distances: &Code) -> Result<Self, std::io::Error> {
+        let used = |code: &Code| code.lengths.iter().rposition(|&length| length != 0);
+        let literal_count = used(literals).map_or(0, |last| last + 1).max(257);
+        let distance_count = used(distances).map_or(0, |last| last + 1).max(1);
+
+        let lengths = literals.lengths[..literal_count]
+            .iter()
+            .chain(&distances.lengths[..distance_count])
+            .copied()
+            .collect::<Vec<_>>();
+        let symbols = code_length_symbols(&lengths);
+
+        let mut frequencies = vec![0; LENGTH_ORDER.len()];
+
+        for &(symbol, _) in &symbols {
+            frequencies[symbol] += 1;
+        }
+
+        let code = Code::build(&frequencies, MAX_LENGTH_CODE_LENGTH)?;
+        let stored = LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| code.lengths[symbol] != 0)
+            .map_or(0, |last| last + 1)
+            .max(4);
+
+        Ok(DynamicHeader {
+            literals: literal_count,
+            distances: distance_count,
+            symbols,
+            code,
+            stored,
+        })
+    }
+
+    /// Bits taken by the block header and the code lengths.
+    fn cost(&self) -> u64 {
+        let lengths = self
+            .symbols
+            .iter()
+            .map(|&(symbol, _)| (self.code.lengths[symbol] + repeat_bits(symbol)) as u64)
+            .sum::<u64>();
+
+        3 + 14 + 3 * self.stored as u64 + lengths
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.put_bits(5, self.literals as u32 - 257);
+        writer.put_bits(5, self.distances as u32 - 1);
+        writer.put_bits(4, self.stored as u32 - 4);
+
+        for &symbol in &LENGTH_ORDER[..self.stored] {
+            writer.put_bits(3, self.code.lengths[symbol]);
+        }
+
+        for &(symbol, extra) in &self.symbols {
+            self.code.put(writer, symbol);
+            writer.put_bits(repeat_bits(symbol), extra);
+        }
+    }
+}
+
+/// Writes `block` as stored blocks of at most [`MAX_STORED`] bytes.
+fn write_stored(writer: &mut BitWriter, block: &[u8], last: bool) {
+    let count = block.len().div_ceil(MAX_STORED).max(1);
+
+    for index in 0..count {
+        let piece = &block[index * MAX_STORED..block.len().min((index + 1) * MAX_STORED)];
+
+        writer.put_bits(1, (last && index + 1 == count) as u32);
+        writer.put_bits(2, 0);
+        writer.align();
+        writer.put_bits(16, piece.len() as u32);
+        writer.put_bits(16, !piece.len() as u32 & 0xffff);
+        writer.bytes.extend_from_slice(piece);
+    }
+}
+
+/// Writes the bytes of `data` from `start` on as DEFLATE blocks, matching back into the
+/// bytes before `start`.
+fn write_blocks(
+    writer: &mut BitWriter,
+    data: &[u8],
+    start: usize,
+    last: bool,
+) -> Result<(), std::io::Error> {
+    let tokens = lz77::tokens(data, start);
+    let mut literals = vec![0; LITERALS];
+    let mut distances = vec![0; DISTANCES];
+    let mut extra_bits = 0;
+
+    literals[END_OF_BLOCK] = 1;
+
+    for &token in &tokens {
+        match token {
+            Token::Literal(byte) => literals[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                let (code, bits, _) = lz77::split(length, &LENGTH_BASES, &LENGTH_EXTRA);
+                literals[END_OF_BLOCK + 1 + code as usize] += 1;
+                extra_bits += bits as u64;
+
+                let (code, bits, _) = lz77::split(distance, &DISTANCE_BASES, &DISTANCE_EXTRA);
+                distances[code as usize] += 1;
+                extra_bits += bits as u64;
+            }
+        }
+    }
+
+    let dynamic_literals = Code::build(&literals, MAX_CODE_LENGTH)?;
+    let dynamic_distances = Code::build(&distances, MAX_CODE_LENGTH)?;
+    let header = DynamicHeader::new(&dynamic_literals, &dynamic_distances)?;
+    let (fixed_literals, fixed_distances) = Code::fixed();
+
+    let dynamic = header.cost()
+        + dynamic_literals.cost(&literals)
+        + dynamic_distances.cost(&distances)
+        + extra_bits;
+    let fixed = 3 + fixed_literals.cost(&literals) + fixed_distances.cost(&distances) + extra_bits;
+
+    // Every stored block takes its header bits, at most 7 to align and 32 of lengths.
+    let block = &data[start..];
+    let stored = block.len().div_ceil(MAX_STORED).max(1) as u64 * 42 + block.len() as u64 * 8;
+
+    if stored < dynamic.min(fixed) {
+        write_stored(writer, block, last);
+        return Ok(());
+    }
+
+    writer.put_bits(1, last as u32);
+
+    let (literal_code, distance_code) = if fixed <= dynamic {
+        writer.put_bits(2, 1);
+        (fixed_literals, fixed_distances)
+    } else {
+        writer.put_bits(2, 2);
+        header.write(writer);
+        (dynamic_literals, dynamic_distances)
+    };
+
+    for token in tokens {
+        match token {
+            Token::Literal(byte) => literal_code.put(writer, byte as usize),
+            Token::Match { length, distance } => {
+                let (code, bits, extra) = lz77::split(length, &LENGTH_BASES, &LENGTH_EXTRA);
+                literal_code.put(writer, END_OF_BLOCK + 1 + code as usize);
+                writer.put_bits(bits, extra);
+
+                let (code, bits, extra) = lz77::split(distance, &DISTANCE_BASES, &DISTANCE_EXTRA);
+                distance_code.put(writer, code as usize);
+                writer.put_bits(bits, extra);
+            }
+        }
+    }
+
+    literal_code.put(writer, END_OF_BLOCK);
+
+    Ok(())
+}
+
+/// Compresses `input` into a gzip stream that any gzip implementation reads, in a single pass
+/// without seeking. Every 256 KiB of input becomes a DEFLATE block coded with a code of its
+/// own, the fixed code or stored as it is, whichever is smallest. `mtime` is stored in the
+/// header when given and fits. The report counts no letters.
+pub fn compress<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    mtime: Option<SystemTime>,
+) -> Result<CompressionReport, std::io::Error> {
+    let now = Instant::now();
+    let mtime = mtime
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .and_then(|time| u32::try_from(time.as_secs()).ok())
+        .unwrap_or(0);
+
+    let mut header = vec![0x1f, 0x8b, 8, 0];
+    header.extend(mtime.to_le_bytes());
+    header.extend([0, UNKNOWN_OS]);
+    output.write_all(&header)?;
+
+    let mut output_size = header.len() as u64;
+    let mut input_size = 0u64;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut writer = BitWriter::default();
+    let mut data = Vec::with_capacity(WINDOW + CHUNK_SIZE);
+
+    loop {
+        // The last 32 KiB of the previous chunk stay for matches to reach back into.
+        data.drain(..data.len().saturating_sub(WINDOW));
+        let start = data.len();
+        let read = (&mut input)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut data)?;
+        let last = read < CHUNK_SIZE;
+
+        hasher.update(&data[start..]);
+        input_size += read as u64;
+
+        write_blocks(&mut writer, &data, start, last)?;
+
+        let bytes = writer.take_bytes();
+        output.write_all(&bytes)?;
+        output_size += bytes.len() as u64;
+
+        if last {
+            break;
+        }
+    }
+
+    writer.align();
+
+    let mut trailer = writer.take_bytes();
+    trailer.extend(hasher.finalize().to_le_bytes());
+    trailer.extend((input_size as u32).to_le_bytes());
+    output.write_all(&trailer)?;
+    output_size += trailer.len() as u64;
+
+    Ok(CompressionReport {
+        letter_size: 8,
+        input_size,
+        output_size,
+        symbol_count: 0,
+        tree_depth: 0,
+        elapsed: now.elapsed(),
+        timings: Timings::default(),
+    })
+}
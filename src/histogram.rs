@@ -0,0 +1,136 @@
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{ErrorKind, Read};
+
+use bitstream_io::{BigEndian, BitReader};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::bits::BitSource;
+use crate::encode::check_letter_size;
+use crate::progress::Progress;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Histogram<S: Hash + Eq = u32> {
+    frequencies: HashMap<S, usize>,
+}
+
+impl<S: Hash + Eq> Default for Histogram<S> {
+    fn default() -> Self {
+        Histogram {
+            frequencies: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Hash + Eq> Histogram<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, symbol: S) {
+        self.add_count(symbol, 1);
+    }
+
+    pub fn add_count(&mut self, symbol: S, count: usize) {
+        *self.frequencies.entry(symbol).or_insert(0) += count;
+    }
+
+    pub fn get(&self, symbol: &S) -> usize {
+        self.frequencies.get(symbol).copied().unwrap_or(0)
+    }
+
+    /// Adds every count of `other` into this histogram, e.g. to combine shards counted in parallel.
+    pub fn merge(&mut self, other: Histogram<S>) {
+        for (symbol, count) in other {
+            self.add_count(symbol, count);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&S, usize)> + '_ {
+        self.frequencies
+            .iter()
+            .map(|(symbol, count)| (symbol, *count))
+    }
+
+    pub fn total(&self) -> usize {
+        self.frequencies.values().sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frequencies.len()
+    }
+
+    /// Shannon entropy in bits per symbol, the lower bound on the average code length.
+    pub fn entropy(&self) -> f64 {
+        let total = self.total() as f64;
+
+        self.frequencies
+            .values()
+            .filter(|&&count| count != 0)
+            .map(|&count| {
+                let probability = count as f64 / total;
+                probability * (1.0 / probability).log2()
+            })
+            // Not `sum`, which gives -0 for an empty histogram.
+            .fold(0.0, |entropy, bits| entropy + bits)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frequencies.is_empty()
+    }
+}
+
+impl<S: Hash + Eq> FromIterator<S> for Histogram<S> {
+    fn from_iter<I: IntoIterator<Item = S>>(symbols: I) -> Self {
+        let mut histogram = Histogram::new();
+
+        for symbol in symbols {
+            histogram.add(symbol);
+        }
+
+        histogram
+    }
+}
+
+impl<S: Hash + Eq> IntoIterator for Histogram<S> {
+    type Item = (S, usize);
+    type IntoIter = hash_map::IntoIter<S, usize>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frequencies.into_iter()
+    }
+}
+
+pub(crate) fn count<R: Read>(
+    reader: R,
+    letter_size: u8,
+    progress: &mut Progress,
+) -> Result<Histogram, std::io::Error> {
+    let mut reader = BitReader::endian(reader, BigEndian);
+    let mut histogram = Histogram::new();
+
+    loop {
+        match reader.get_bits(letter_size as u32) {
+            Err(e) => match e.kind() {
+                ErrorKind::UnexpectedEof => break,
+                _ => return Err(e),
+            },
+            Ok(code) => {
+                histogram.add(code);
+                progress.advance(letter_size as u64)?;
+            }
+        }
+    }
+
+    Ok(histogram)
+}
+
+pub fn count_frequencies<R: Read>(reader: R, letter_size: u8) -> Result<Histogram, std::io::Error> {
+    check_letter_size(letter_size)?;
+
+    count(reader, letter_size, &mut Progress::disabled())
+}
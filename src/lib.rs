@@ -0,0 +1,42 @@
+mod adaptive;
+pub mod archive;
+mod arithmetic;
+mod bits;
+pub mod checksum;
+pub mod container;
+mod context;
+mod crypto;
+pub mod decode;
+pub mod dictionary;
+pub mod encode;
+mod filter;
+pub mod format;
+pub mod gzip;
+pub mod histogram;
+pub mod limits;
+mod lz77;
+mod parallel;
+mod progress;
+pub mod report;
+pub mod tree;
+pub mod volume;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "python")]
+mod python;
+
+pub use arithmetic::{Coder, MAX_ARITHMETIC_LETTER_SIZE};
+pub use checksum::Checksum;
+pub use crypto::Encryption;
+pub use format::{
+    detect, inspect, read_tree, FormatInfo, StreamInfo, FORMAT_VERSION, FORMAT_VERSIONS,
+    LETTER_SIZES, MAGIC, PREFIX_SIZE, UNKNOWN_SIZE,
+};
+pub use limits::{LimitExceeded, Limits};
+pub use progress::Cancelled;
+pub use report::{CompressionReport, Timings};
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+
+/// Bounds enforced while decoding input that cannot be trusted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum number of nodes (leaves and joints) in the tree header.
+    pub max_header_nodes: usize,
+    /// Maximum decompressed size in bytes.
+    pub max_output_size: u64,
+    /// Maximum depth of the tree, i.e. the longest code in bits.
+    pub max_tree_depth: u32,
+    /// Maximum bytes allocated for the tree read from the header.
+    pub max_memory: usize,
+}
+
+impl Limits {
+    pub fn unlimited() -> Self {
+        Limits {
+            max_header_nodes: usize::MAX,
+            max_output_size: u64::MAX,
+            max_tree_depth: u32::MAX,
+            max_memory: usize::MAX,
+        }
+    }
+
+    pub fn max_header_nodes(mut self, nodes: usize) -> Self {
+        self.max_header_nodes = nodes;
+        self
+    }
+
+    pub fn max_output_size(mut self, size: u64) -> Self {
+        self.max_output_size = size;
+        self
+    }
+
+    pub fn max_tree_depth(mut self, depth: u32) -> Self {
+        self.max_tree_depth = depth;
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+}
+
+/// Enough for any tree over 16 bit letters with codes fitting in 32 bits, and at most
+/// 1 GiB of output.
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_nodes: 2 * (1 << 16) - 1,
+            max_output_size: 1 << 30,
+            max_tree_depth: 32,
+            max_memory: 16 << 20,
+        }
+    }
+}
+
+/// Error payload returned (wrapped in an `std::io::Error` of kind `InvalidData`) when
+/// input breaks one of the [`Limits`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LimitExceeded {
+    HeaderNodes,
+    OutputSize,
+    TreeDepth,
+    Memory,
+}
+
+impl LimitExceeded {
+    /// Returns the exceeded limit if `e` was caused by one.
+    pub fn from_error(e: &std::io::Error) -> Option<LimitExceeded> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<LimitExceeded>())
+            .copied()
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::HeaderNodes => write!(f, "tree header has too many nodes"),
+            LimitExceeded::OutputSize => write!(f, "decompressed size is too large"),
+            LimitExceeded::TreeDepth => write!(f, "tree is too deep"),
+            LimitExceeded::Memory => write!(f, "tree needs more memory than allowed"),
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
+
+impl From<LimitExceeded> for std::io::Error {
+    fn from(limit: LimitExceeded) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, limit)
+    }
+}
@@ -0,0 +1,280 @@
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::bits::{BitBuffer, BitSink, BitSource};
+use crate::decode::{decode_canonical, read_header};
+use crate::encode::{build_code, encode_symbol, write_header};
+use crate::histogram::Histogram;
+use crate::limits::Limits;
+
+/// Furthest back a match may start.
+pub(crate) const WINDOW: usize = 32 * 1024;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Earlier positions with the same hash tried for every match.
+const MAX_CHAIN: usize = 128;
+
+const HASH_BITS: u32 = 15;
+
+/// Bits of the literal/length alphabet, the 256 byte values followed by the length codes.
+const LITERAL_BITS: u8 = 9;
+
+/// Bits of the distance alphabet.
+const DISTANCE_BITS: u8 = 5;
+
+/// Shortest length of every length code and the extra bits that follow it, as in DEFLATE.
+pub(crate) const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Shortest distance of every distance code and the extra bits that follow it.
+pub(crate) const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Token {
+    Literal(u8),
+    Match { length: usize, distance: usize },
+}
+
+/// The code of `value` among codes starting at `bases`, with the extra bits that follow it.
+pub(crate) fn split(value: usize, bases: &[u16], extra: &[u8]) -> (u32, u32, u32) {
+    let code = bases.partition_point(|&base| base as usize <= value) - 1;
+    let offset = value - bases[code] as usize;
+
+    (code as u32, extra[code] as u32, offset as u32)
+}
+
+/// Earlier positions of a block, chained by the hash of the three bytes starting there.
+struct Matcher {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl Matcher {
+    fn new(len: usize) -> Self {
+        Matcher {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            previous: vec![usize::MAX; len],
+        }
+    }
+
+    fn hash(block: &[u8], position: usize) -> usize {
+        let bytes =
+            u32::from_be_bytes([0, block[position], block[position + 1], block[position + 2]]);
+        (bytes.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, block: &[u8], position: usize) {
+        if position + MIN_MATCH <= block.len() {
+            let hash = Self::hash(block, position);
+            self.previous[position] = self.head[hash];
+            self.head[hash] = position;
+        }
+    }
+
+    /// The longest match for the bytes at `position` as its length and distance, a length of
+    /// 0 when there is none.
+    fn find(&self, block: &[u8], position: usize) -> (usize, usize) {
+        if position + MIN_MATCH > block.len() {
+            return (0, 0);
+        }
+
+        let limit = (block.len() - position).min(MAX_MATCH);
+        let mut best = (0, 0);
+        let mut candidate = self.head[Self::hash(block, position)];
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || position - candidate > WINDOW {
+                break;
+            }
+
+            let length = block[candidate..]
+                .iter()
+                .zip(&block[position..position + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if length > best.0 {
+                best = (length, position - candidate);
+
+                if length == limit {
+                    break;
+                }
+            }
+
+            candidate = self.previous[candidate];
+        }
+
+        match best.0 >= MIN_MATCH {
+            true => best,
+            false => (0, 0),
+        }
+    }
+}
+
+/// Splits `block` from `start` on into literals and matches, putting a match off by a byte
+/// when the next position has a longer one. Matches may reach back before `start`.
+pub(crate) fn tokens(block: &[u8], start: usize) -> Vec<Token> {
+    let mut matcher = Matcher::new(block.len());
+    let mut tokens = Vec::new();
+    let mut position = start;
+
+    for earlier in start.saturating_sub(WINDOW)..start {
+        matcher.insert(block, earlier);
+    }
+
+    while position < block.len() {
+        let (length, distance) = matcher.find(block, position);
+        matcher.insert(block, position);
+
+        if length == 0 || matcher.find(block, position + 1).0 > length {
+            tokens.push(Token::Literal(block[position]));
+            position += 1;
+            continue;
+        }
+
+        for next in position + 1..position + length {
+            matcher.insert(block, next);
+        }
+
+        tokens.push(Token::Match { length, distance });
+        position += length;
+    }
+
+    tokens
+}
+
+/// Encodes `block` as DEFLATE does: back-references to the last 32 KiB and literals,
+/// with one code for literals and match lengths and another for distances. The code lengths
+/// of both come first, then the tokens, each length and distance code followed by its extra
+/// bits. Empty blocks encode to nothing.
+pub(crate) fn encode_block(
+    block: &[u8],
+    max_code_length: Option<u32>,
+    header: &mut Duration,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut buffer = BitBuffer::default();
+
+    if block.is_empty() {
+        return Ok(buffer.into_bytes());
+    }
+
+    let tokens = tokens(block, 0);
+    let mut literals = Histogram::new();
+    let mut distances = Histogram::new();
+
+    for &token in &tokens {
+        match token {
+            Token::Literal(byte) => literals.add(byte as u32),
+            Token::Match { length, distance } => {
+                literals.add(256 + split(length, &LENGTH_BASES, &LENGTH_EXTRA).0);
+                distances.add(split(distance, &DISTANCE_BASES, &DISTANCE_EXTRA).0);
+            }
+        }
+    }
+
+    // Blocks without matches still store a distance code, of a single unused distance.
+    if distances.is_empty() {
+        distances.add(0);
+    }
+
+    let literals = build_code(literals, max_code_length)?.unwrap_or_default();
+    let distances = build_code(distances, max_code_length)?.unwrap_or_default();
+    let phase = Instant::now();
+    write_header(&mut buffer, &literals, LITERAL_BITS, true)?;
+    write_header(&mut buffer, &distances, DISTANCE_BITS, true)?;
+    *header += phase.elapsed();
+
+    let (literal_table, distance_table) = (literals.table(), distances.table());
+
+    for token in tokens {
+        match token {
+            Token::Literal(byte) => encode_symbol(&mut buffer, &literal_table, &(byte as u32))?,
+            Token::Match { length, distance } => {
+                let (code, bits, extra) = split(length, &LENGTH_BASES, &LENGTH_EXTRA);
+                encode_symbol(&mut buffer, &literal_table, &(256 + code))?;
+                buffer.put_bits(bits, extra)?;
+
+                let (code, bits, extra) = split(distance, &DISTANCE_BASES, &DISTANCE_EXTRA);
+                encode_symbol(&mut buffer, &distance_table, &code)?;
+                buffer.put_bits(bits, extra)?;
+            }
+        }
+    }
+
+    Ok(buffer.into_bytes())
+}
+
+/// The length or distance of code `symbol`, reading its extra bits from `reader`.
+fn read_value<R: BitSource>(
+    reader: &mut R,
+    symbol: usize,
+    bases: &[u16],
+    extra: &[u8],
+) -> Result<usize, std::io::Error> {
+    match (bases.get(symbol), extra.get(symbol)) {
+        (Some(&base), Some(&bits)) => Ok(base as usize + reader.get_bits(bits as u32)? as usize),
+        _ => Err(invalid_match()),
+    }
+}
+
+fn invalid_match() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "invalid match in LZ77 data")
+}
+
+/// Decodes what [`encode_block`] made of a block of the length of `block` into it. Its code
+/// lengths may be coded when `coded` is set.
+pub(crate) fn decode_block<R: BitSource>(
+    reader: &mut R,
+    block: &mut [u8],
+    limits: &Limits,
+    coded: bool,
+) -> Result<(), std::io::Error> {
+    if block.is_empty() {
+        return Ok(());
+    }
+
+    let literals = read_header(reader, LITERAL_BITS, limits, coded)?;
+    let distances = read_header(reader, DISTANCE_BITS, limits, coded)?;
+    let mut position = 0;
+
+    while position < block.len() {
+        let symbol = decode_canonical(reader, &literals)? as usize;
+
+        if symbol < 256 {
+            block[position] = symbol as u8;
+            position += 1;
+            continue;
+        }
+
+        let length = read_value(reader, symbol - 256, &LENGTH_BASES, &LENGTH_EXTRA)?;
+        let symbol = decode_canonical(reader, &distances)? as usize;
+        let distance = read_value(reader, symbol, &DISTANCE_BASES, &DISTANCE_EXTRA)?;
+
+        if distance > position || length > block.len() - position {
+            return Err(invalid_match());
+        }
+
+        // Matches may overlap the bytes they produce, so they are copied a byte at a time.
+        for next in position..position + length {
+            block[next] = block[next - distance];
+        }
+
+        position += length;
+    }
+
+    Ok(())
+}
@@ -1,108 +1,4655 @@
-mod decode;
-mod encode;
-
-use clap::{arg, command, value_parser, Command};
-use decode::decompress_file;
-use encode::compress_file;
-use std::{
-    path::{Path, PathBuf},
-    time::Instant,
+use anstyle::{AnsiColor, Style};
+use clap::builder::{FalseyValueParser, PossibleValuesParser, TypedValueParser};
+use clap::{value_parser, ArgAction, Args, ColorChoice, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use glob::Pattern;
+use huffman_code::archive::{ArchiveEntry, ArchiveReader, ArchiveWriter, ARCHIVE_VERSION};
+use huffman_code::container::{
+    ContainerReader, ContainerWriter, StreamEntry, CONTAINER_MAGIC, CONTAINER_VERSION,
 };
+use huffman_code::decode::{BadBlock, BadBlocks, Decompressor};
+use huffman_code::dictionary::{Dictionary, MAX_DICTIONARY_LETTER_SIZE};
+use huffman_code::encode::{best_letter_size, Compressor};
+use huffman_code::gzip;
+use huffman_code::histogram::{count_frequencies, Histogram};
+use huffman_code::tree::{CodeTable, Tree};
+use huffman_code::volume::{volume_path, VolumeReader, VolumeWriter, VOLUME_MAGIC, VOLUME_VERSION};
+use huffman_code::{
+    detect, inspect, read_tree, Checksum, Coder, CompressionReport, Encryption, FormatInfo, Limits,
+    StreamInfo, FORMAT_VERSION, FORMAT_VERSIONS, LETTER_SIZES, PREFIX_SIZE, UNKNOWN_SIZE,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::{Display, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    self, BufRead, BufReader, BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write,
+};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+const SUFFIX: &str = "huff";
+
+/// Extension of gzip outputs, unless another is given.
+const GZIP_SUFFIX: &str = "gz";
+
+/// Extension of self-extracting outputs, unless another is given. Windows runs only `.exe`
+/// files.
+#[cfg(windows)]
+const SFX_SUFFIX: &str = "exe";
+#[cfg(not(windows))]
+const SFX_SUFFIX: &str = "sfx";
+
+/// Last bytes of self-extracting outputs, after the name of the original file, its length as
+/// a big endian `u16` and the offset of the compressed stream as a big endian `u64`.
+const SFX_MAGIC: &[u8; 4] = b"HSX1";
+
+/// Bytes taken by the name length, the stream offset and the magic.
+const SFX_TRAILER_SIZE: u64 = 14;
+
+/// Accepts a file extension with or without its leading dot, such as `.hz`.
+fn parse_suffix(value: &str) -> Result<String, String> {
+    let suffix = value.strip_prefix('.').unwrap_or(value);
+
+    if suffix.is_empty() || suffix.contains(['.', '/', '\\']) {
+        return Err(format!(
+            "expected a single extension such as .hz, got {}",
+            value
+        ));
+    }
+
+    Ok(suffix.to_string())
+}
+
+/// `file.txt` becomes `file.txt.huff`.
+fn compressed_name(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+
+    Ok(PathBuf::from(name))
+}
+
+/// `file.txt.huff` becomes `file.txt`, as does its first volume `file.txt.huff.001`.
+fn decompressed_name(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let path = match path.extension() {
+        Some(extension) if extension == "001" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+
+    match path.extension() {
+        Some(extension) if extension == suffix => Ok(path.with_extension("")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown suffix, expected .{} or --output", suffix),
+        )),
+    }
+}
+
+/// Process exit codes, so scripts can tell failures apart.
+const EXIT_USAGE: u8 = 1;
+const EXIT_IO: u8 = 2;
+const EXIT_CORRUPT: u8 = 3;
+const EXIT_EXISTS: u8 = 4;
+
+/// Exit code of the first failure, 0 while everything succeeded.
+static EXIT_STATUS: AtomicU8 = AtomicU8::new(0);
+
+/// Records a failure. Later work still runs, but the first failure decides the exit code.
+fn fail(code: u8) {
+    let _ = EXIT_STATUS.compare_exchange(0, code, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+fn exit_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => EXIT_EXISTS,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EXIT_CORRUPT,
+        io::ErrorKind::InvalidInput => EXIT_USAGE,
+        _ => EXIT_IO,
+    }
+}
+
+/// Whether stdout and stderr are colored, decided once from `--color`.
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+
+const GOOD: Style = AnsiColor::Green.on_default();
+const WARNING: Style = AnsiColor::Yellow.on_default().bold();
+const ERROR: Style = AnsiColor::Red.on_default().bold();
+
+/// `auto` colors terminals unless `NO_COLOR` is set.
+fn set_color(choice: ColorChoice) {
+    let colored = |terminal: bool| match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && std::env::var_os("NO_COLOR").is_none(),
+    };
+
+    COLOR_STDOUT.store(colored(io::stdout().is_terminal()), Ordering::Relaxed);
+    COLOR_STDERR.store(colored(io::stderr().is_terminal()), Ordering::Relaxed);
+}
+
+fn stdout_colored() -> bool {
+    COLOR_STDOUT.load(Ordering::Relaxed)
+}
+
+fn stderr_colored() -> bool {
+    COLOR_STDERR.load(Ordering::Relaxed)
+}
+
+fn paint(text: impl Display, style: Style, colored: bool) -> String {
+    match colored {
+        true => format!("{}{}{}", style.render(), text, style.render_reset()),
+        false => text.to_string(),
+    }
+}
+
+fn error_label() -> String {
+    paint("Error", ERROR, stderr_colored())
+}
+
+/// Prints `Error <context>: <e>` and records the failure.
+fn report(context: impl Display, e: &io::Error) {
+    fail(exit_code(e));
+    eprintln!("{} {}: {}", error_label(), context, e);
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Opens `path`, or stdin for `-`. The first volume of split output is read on through the
+/// volumes after it.
+fn open_input(path: &Path) -> io::Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+
+    let mut input = BufReader::with_capacity(32 * 1024, File::open(path)?);
+
+    if !input.fill_buf()?.starts_with(VOLUME_MAGIC) {
+        return Ok(Box::new(input));
+    }
+
+    let base = path.with_extension("");
+    let volumes = VolumeReader::new(input, move |number| {
+        let file = File::open(volume_path(&base, number))?;
+        Ok(BufReader::with_capacity(32 * 1024, file))
+    })?;
+
+    Ok(Box::new(volumes))
+}
+
+/// The error for an output `path` that exists and may only be replaced with `--force`.
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} already exists, use --force to overwrite",
+            path.display()
+        ),
+    )
+}
+
+/// Asks on the terminal whether `path` may be overwritten.
+fn confirm_overwrite(path: &Path) -> io::Result<bool> {
+    eprint!("{} already exists, overwrite? [y/N] ", path.display());
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Creates `path`. Existing files are overwritten with `--force`, left alone with
+/// `--no-clobber` and otherwise overwritten only if the user agrees when asked on a terminal.
+fn create_file(path: &Path, options: &Options) -> io::Result<File> {
+    if options.force {
+        return File::create(path);
+    }
+
+    let created = OpenOptions::new().write(true).create_new(true).open(path);
+
+    match created {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+
+            if !interactive || options.no_clobber {
+                Err(already_exists(path))
+            } else if confirm_overwrite(path)? {
+                File::create(path)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} not overwritten", path.display()),
+                ))
+            }
+        }
+        created => created,
+    }
+}
+
+/// Fails the way [`create_output`] would, without creating anything.
+fn check_output(path: &Path, options: &Options) -> io::Result<()> {
+    let path = match options.split_size {
+        Some(_) => volume_path(path, 1),
+        None => path.to_path_buf(),
+    };
+
+    if !options.force && !is_stdio(&path) && path.exists() {
+        return Err(already_exists(&path));
+    }
+
+    Ok(())
+}
+
+fn create_output(path: &Path, options: &Options) -> io::Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        Ok(Box::new(BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(BufWriter::with_capacity(
+            32 * 1024,
+            create_file(path, options)?,
+        )))
+    }
+}
+
+/// Whether the summary for `output_path` goes to a colored stream.
+fn summary_colored(output_path: &Path) -> bool {
+    match is_stdio(output_path) {
+        true => stderr_colored(),
+        false => stdout_colored(),
+    }
+}
+
+/// Reports a failed write to stdout. A reader that went away, as `head` does, ends the output
+/// normally.
+fn report_output(e: &io::Error) {
+    if e.kind() != io::ErrorKind::BrokenPipe {
+        report("failed to write output", e);
+    }
+}
+
+/// Prints the summary to stderr when the data itself goes to stdout.
+fn print_summary(output_path: &Path, summary: &str) {
+    if is_stdio(output_path) {
+        eprint!("{}", summary);
+    } else {
+        print!("{}", summary);
+    }
+}
+
+const AUTO_LETTER_SIZES: [u8; 4] = [4, 8, 12, 16];
+
+/// Bytes of streamed stdin looked at to choose a letter size.
+const STREAM_SAMPLE: u64 = 1024 * 1024;
+
+/// What a `--level` preset sets. Lower levels try fewer letter sizes on a smaller sample of
+/// the input and give threads bigger blocks, higher ones search more and compress even files
+/// that look compressed already.
+#[derive(Debug)]
+struct Level {
+    letter_sizes: &'static [u8],
+    /// Bytes from the start of the input counted to pick the letter size, all of it when unset.
+    sample: Option<u64>,
+    block_size: Option<usize>,
+    skip_compressed: bool,
+}
+
+const LEVELS: [Level; 9] = [
+    Level {
+        letter_sizes: &[8],
+        sample: None,
+        block_size: Some(4 << 20),
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &[4, 8],
+        sample: Some(64 << 10),
+        block_size: Some(4 << 20),
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &[4, 8, 12],
+        sample: Some(256 << 10),
+        block_size: Some(1 << 20),
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &AUTO_LETTER_SIZES,
+        sample: Some(1 << 20),
+        block_size: Some(1 << 20),
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &AUTO_LETTER_SIZES,
+        sample: Some(4 << 20),
+        block_size: None,
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &AUTO_LETTER_SIZES,
+        sample: None,
+        block_size: None,
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &[4, 6, 8, 10, 12, 14, 16],
+        sample: None,
+        block_size: None,
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+        sample: None,
+        block_size: None,
+        skip_compressed: true,
+    },
+    Level {
+        letter_sizes: &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+        sample: None,
+        block_size: None,
+        skip_compressed: false,
+    },
+];
+
+#[derive(Debug, Clone, Copy)]
+enum LetterSize {
+    Auto,
+    Bits(u8),
+    /// Picked by a `--level` preset.
+    Level(&'static Level),
+}
+
+const DEFAULT_LETTER_SIZE: u8 = 8;
+
+fn parse_letter_size(value: &str) -> Result<LetterSize, String> {
+    match value {
+        "auto" => Ok(LetterSize::Auto),
+        _ => value
+            .parse()
+            .ok()
+            .filter(|bits| LETTER_SIZES.contains(bits))
+            .map(LetterSize::Bits)
+            .ok_or_else(|| {
+                format!(
+                    "expected a number of bits from {} to {} or auto, got {}",
+                    LETTER_SIZES.start(),
+                    LETTER_SIZES.end(),
+                    value
+                )
+            }),
+    }
+}
+
+/// Parses a byte count such as `4096`, `64KiB` or `1M`. Units are powers of 1024.
+fn parse_byte_size(value: &str) -> Result<usize, String> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = &value[digits.len()..];
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kib" => 10,
+        "m" | "mib" => 20,
+        "g" | "gib" => 30,
+        _ => return Err(format!("unknown unit {}, expected KiB, MiB or GiB", unit)),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(1 << shift))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| format!("expected a positive size such as 1MiB, got {}", value))
+}
+
+fn resolve_letter_size<R: Read + Seek>(input: &mut R, letter_size: LetterSize) -> io::Result<u8> {
+    match letter_size {
+        LetterSize::Bits(bits) => Ok(bits),
+        LetterSize::Auto => Ok(best_letter_size(input, &AUTO_LETTER_SIZES)?.0),
+        LetterSize::Level(Level {
+            letter_sizes: &[bits],
+            ..
+        }) => Ok(bits),
+        LetterSize::Level(Level {
+            letter_sizes,
+            sample: Some(sample),
+            ..
+        }) => {
+            let start = input.stream_position()?;
+            let mut head = Vec::new();
+            input.by_ref().take(*sample).read_to_end(&mut head)?;
+            input.seek(SeekFrom::Start(start))?;
+
+            Ok(best_letter_size(Cursor::new(head), letter_sizes)?.0)
+        }
+        LetterSize::Level(level) => Ok(best_letter_size(input, level.letter_sizes)?.0),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    VeryVerbose,
+}
+
+/// Settings shared by every subcommand.
+#[derive(Debug, Clone)]
+struct Options {
+    verbosity: Verbosity,
+    json: bool,
+    /// Print sizes as plain byte counts instead of KiB, MiB and GiB.
+    bytes: bool,
+    /// Write outputs to stdout (`-c`).
+    stdout: bool,
+    force: bool,
+    /// Never overwrite existing outputs, not even after asking.
+    no_clobber: bool,
+    /// Delete the input once it was compressed or decompressed successfully.
+    remove: bool,
+    /// Threads used for compression, rayon's global pool (one per core) when unset.
+    threads: Option<usize>,
+    /// Hash of the input stored in compressed outputs.
+    checksum: Checksum,
+    /// Extension of compressed files, without the dot.
+    suffix: String,
+    /// Encrypts compressed outputs and decrypts inputs.
+    password: Option<String>,
+    /// Code table compressed outputs are written with and inputs read with.
+    dictionary: Option<Dictionary>,
+    /// Cipher compressed outputs are encrypted with.
+    encryption: Encryption,
+    /// What the letters of compressed outputs are coded with.
+    coder: Coder,
+    /// Bytes the decoder may allocate for the tree of an input, unlimited when unset.
+    max_memory: Option<usize>,
+    /// What decompression does with damaged blocks of inputs.
+    bad_blocks: BadBlocks,
+    /// Bytes per block of compressed outputs, chosen from the letter size when unset.
+    block_size: Option<usize>,
+    /// Build a code for every block instead of one for the whole input.
+    block_trees: bool,
+    /// Code with an adaptive code instead of one built from counted letters.
+    adaptive: bool,
+    /// Code blocks as LZ77 matches and literals.
+    lz77: bool,
+    /// Code every letter with a code picked by the letter before it.
+    order1: bool,
+    /// End the last block with an end-of-stream letter.
+    eos: bool,
+    /// Pack the bits of coded blocks from the lowest bit of every byte up.
+    lsb_first: bool,
+    /// Run-length code blocks before coding their letters.
+    rle: bool,
+    /// Burrows-Wheeler transform and move-to-front code blocks before coding their letters.
+    bwt: bool,
+    /// Write compressed outputs as gzip streams instead.
+    gzip: bool,
+    /// Bytes per volume to split compressed outputs into, one file when unset.
+    split_size: Option<u64>,
+    /// Write compressed outputs as executables that extract themselves.
+    self_extracting: bool,
+    /// Executable that self-extracting outputs start with, this one when unset.
+    stub: Option<PathBuf>,
+    /// Go on with compressed outputs an interrupted run left unfinished instead of replacing
+    /// them.
+    resume: bool,
+    /// Longest code in bits, unlimited when unset.
+    max_code_length: Option<u32>,
+    /// Leave the modification time of inputs out of compressed outputs.
+    no_mtime: bool,
+    /// Report what would be written instead of writing it.
+    dry_run: bool,
+    /// Carry modification times from inputs over to outputs.
+    preserve_times: bool,
+    /// Carry permissions and ownership from inputs over to outputs.
+    preserve_perms: bool,
+    /// How links met while walking directories are treated.
+    symlinks: Symlinks,
+    /// Files and directories left out while walking directories.
+    exclude: Vec<Pattern>,
+}
+
+impl Options {
+    fn from_args(args: &GlobalArgs) -> Self {
+        let verbosity = match (args.quiet, args.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        };
+
+        let symlinks = if args.follow_symlinks {
+            Symlinks::Follow
+        } else if args.store_symlinks {
+            Symlinks::Store
+        } else {
+            Symlinks::Skip
+        };
+
+        Options {
+            verbosity,
+            json: args.json,
+            bytes: args.bytes,
+            stdout: args.stdout,
+            force: args.force,
+            no_clobber: args.no_clobber,
+            remove: args.rm,
+            threads: None,
+            block_size: None,
+            block_trees: false,
+            adaptive: false,
+            lz77: false,
+            order1: false,
+            eos: false,
+            lsb_first: false,
+            rle: false,
+            bwt: false,
+            gzip: false,
+            split_size: None,
+            self_extracting: false,
+            stub: None,
+            resume: false,
+            max_code_length: None,
+            no_mtime: false,
+            checksum: Checksum::default(),
+            suffix: args.suffix.clone().unwrap_or_else(|| SUFFIX.to_string()),
+            password: None,
+            dictionary: None,
+            encryption: Encryption::default(),
+            coder: Coder::default(),
+            max_memory: args.max_memory,
+            bad_blocks: BadBlocks::Fail,
+            dry_run: false,
+            preserve_times: false,
+            preserve_perms: false,
+            symlinks,
+            exclude: args.exclude.clone(),
+        }
+    }
+
+    fn quiet(&self) -> bool {
+        self.verbosity == Verbosity::Quiet
+    }
+
+    /// `size` for the summaries, e.g. `1.5 MiB`, or `1572864 bytes` with `--bytes`.
+    fn size(&self, size: u64) -> String {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if self.bytes || size < 1024 {
+            return format!("{} bytes", size);
+        }
+
+        let mut value = size as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Defaults read from the configuration file. Flags given on the command line take
+/// precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    #[serde(deserialize_with = "config_letter_size")]
+    size: Option<LetterSize>,
+    threads: Option<u16>,
+    #[serde(deserialize_with = "config_checksum")]
+    checksum: Option<Checksum>,
+    #[serde(deserialize_with = "config_suffix")]
+    suffix: Option<String>,
+    #[serde(deserialize_with = "config_patterns")]
+    exclude: Vec<Pattern>,
+}
+
+/// Accepts the letter size as a number of bits or as `"auto"`.
+fn config_letter_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<LetterSize>, D::Error> {
+    let value = match toml::Value::deserialize(deserializer)? {
+        toml::Value::Integer(bits) => bits.to_string(),
+        toml::Value::String(value) => value,
+        _ => return Err(D::Error::custom("expected a number of bits or auto")),
+    };
+
+    parse_letter_size(&value)
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn config_checksum<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Checksum>, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn config_suffix<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    parse_suffix(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn config_patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Pattern>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(D::Error::custom))
+        .collect()
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/huffman/config.toml`, falling back to `~/.config`.
+    fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+        Some(config_dir.join("huffman").join("config.toml"))
+    }
+
+    /// Reads `path`, or the default location when it is `None`. Only a missing file at the
+    /// default location is allowed.
+    fn load(path: Option<&Path>) -> io::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Config::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default())
+            }
+            result => result
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+        };
+
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+
+        let config: Config = toml::from_str(&text).map_err(|e| invalid(e.message().to_string()))?;
+
+        if config.threads == Some(0) {
+            return Err(invalid("threads must be at least 1".to_string()));
+        }
+
+        Ok(config)
+    }
+
+    /// The `--size` given on the command line or in `HUFFMAN_SIZE`, else the configured one,
+    /// else the default.
+    fn letter_size(&self, size: Option<LetterSize>) -> LetterSize {
+        size.or(self.size)
+            .unwrap_or(LetterSize::Bits(DEFAULT_LETTER_SIZE))
+    }
+}
+
+/// A bar on stderr, hidden when stderr is not a terminal or in quiet mode.
+fn progress_bar(options: &Options) -> ProgressBar {
+    if options.quiet() {
+        return ProgressBar::hidden();
+    }
+
+    let style = ProgressStyle::with_template(
+        "{msg:>8} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+    )
+    .unwrap()
+    .progress_chars("=> ");
+
+    ProgressBar::new(0).with_style(style)
+}
+
+fn compressor(
+    letter_size: u8,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+    options: &Options,
+) -> Compressor<'_> {
+    let mut compressor = Compressor::new(letter_size);
+
+    if let Some(mtime) = mtime {
+        compressor = compressor.mtime(mtime);
+    }
+
+    if let Some(mode) = mode {
+        compressor = compressor.mode(mode);
+    }
+
+    if let Some(threads) = options.threads {
+        compressor = compressor.threads(threads);
+    }
+
+    if let Some(block_size) = options.block_size {
+        compressor = compressor.block_size(block_size);
+    }
+
+    compressor = compressor.block_trees(options.block_trees);
+    compressor = compressor.adaptive(options.adaptive);
+    compressor = compressor.lz77(options.lz77);
+    compressor = compressor.order1(options.order1);
+    compressor = compressor.eos(options.eos);
+    compressor = compressor.lsb_first(options.lsb_first);
+    compressor = compressor.rle(options.rle);
+    compressor = compressor.bwt(options.bwt);
+
+    if let Some(length) = options.max_code_length {
+        compressor = compressor.max_code_length(length);
+    }
+
+    compressor = compressor.checksum(options.checksum);
+    compressor = compressor.coder(options.coder);
+
+    if let Some(dictionary) = &options.dictionary {
+        compressor = compressor.code_table(dictionary.table());
+    }
+
+    if let Some(password) = &options.password {
+        compressor = compressor
+            .password(password.as_bytes())
+            .encryption(options.encryption);
+    }
+
+    compressor
+}
+
+/// Compresses `input` with `compress`, given the compressor set up as the options say.
+fn compress_seekable<R, F>(
+    mut input: R,
+    letter_size: LetterSize,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+    options: &Options,
+    compress: F,
+) -> io::Result<CompressionReport>
+where
+    R: Read + Seek,
+    F: FnOnce(Compressor, R) -> io::Result<CompressionReport>,
+{
+    let letter_size = resolve_letter_size(&mut input, letter_size)?;
+    let bar = progress_bar(options);
+    bar.set_message("counting");
+
+    // Both passes report against twice the input size, so show each pass as its own run.
+    // Codes built per block need no counting pass.
+    let passes = if options.block_trees
+        || options.adaptive
+        || options.lz77
+        || options.dictionary.is_some()
+    {
+        1
+    } else {
+        2
+    };
+    let compressor = compressor(letter_size, mtime, mode, options).progress(|processed, total| {
+        let size = total / passes;
+        let counted = total - size;
+        bar.set_length(size);
+
+        if counted != 0 && processed <= counted {
+            bar.set_message("counting");
+            bar.set_position(processed);
+        } else {
+            bar.set_message("encoding");
+            bar.set_position(processed - counted);
+        }
+    });
+    let report = compress(compressor, input);
+
+    bar.finish_and_clear();
+    report
+}
+
+/// Compresses stdin as it arrives in a single pass, choosing the letter size from its first
+/// [`STREAM_SAMPLE`] bytes unless it is given in bits.
+fn compress_stdin(
+    output: &mut dyn Write,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    let mut stdin = io::stdin().lock();
+    let mut head = Vec::new();
+
+    let letter_size = match letter_size {
+        LetterSize::Bits(bits) => bits,
+        letter_size => {
+            stdin.by_ref().take(STREAM_SAMPLE).read_to_end(&mut head)?;
+            resolve_letter_size(&mut Cursor::new(&head), letter_size)?
+        }
+    };
+
+    let bar = progress_bar(options);
+    bar.set_message("encoding");
+
+    let report = compressor(letter_size, None, None, options)
+        .progress(|processed, _| bar.set_position(processed))
+        .compress_stream(Cursor::new(head).chain(stdin), output);
+
+    bar.finish_and_clear();
+    report
+}
+
+/// Writes `input_path` as a gzip stream, which takes one pass, so stdin is not read into
+/// memory first.
+fn compress_gzip(
+    input_path: &Path,
+    output: &mut dyn Write,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    if is_stdio(input_path) {
+        return gzip::compress(io::stdin().lock(), output, None);
+    }
+
+    let input = File::open(input_path)?;
+    let mtime = match options.no_mtime {
+        true => None,
+        false => Some(input.metadata()?.modified()?),
+    };
+
+    gzip::compress(BufReader::with_capacity(32 * 1024, input), output, mtime)
+}
+
+fn compress_path(
+    input_path: &Path,
+    output_path: &Path,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    if options.self_extracting {
+        return compress_self_extracting(input_path, output_path, letter_size, options);
+    }
+
+    // Split output goes to volumes named after `output_path`, created as they fill up.
+    if let Some(volume_size) = options.split_size {
+        if is_stdio(output_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--split-size needs an output file, not stdout",
+            ));
+        }
+
+        let mut volumes = VolumeWriter::new(volume_size, |number| {
+            let file = create_file(&volume_path(output_path, number), options)?;
+            Ok(BufWriter::with_capacity(32 * 1024, file))
+        })?;
+        let report = write_compressed(input_path, &mut volumes, letter_size, options)?;
+        volumes.finish()?;
+
+        return Ok(report);
+    }
+
+    // Outputs an interrupted run left unfinished go on after their last good block.
+    if options.resume && is_unfinished(input_path, output_path) {
+        let output = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(output_path)?;
+        return resume_compressed(input_path, output, letter_size, options);
+    }
+
+    let mut output = create_output(output_path, options)?;
+    let report = write_compressed(input_path, &mut output, letter_size, options)?;
+
+    output.flush()?;
+    Ok(report)
+}
+
+/// Compresses `input_path` into a copy of the stub, by default this binary, followed by the
+/// stream and a trailer naming the original file. The stub is the whole command line tool,
+/// not a decoder of its own, so outputs are that much larger than the stream. Run, the stub
+/// finds the trailer at the end of itself and extracts the stream, see
+/// [`run_self_extraction`]. Stubs built for other platforms make outputs for those.
+fn compress_self_extracting(
+    input_path: &Path,
+    output_path: &Path,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    let name = match is_stdio(input_path) {
+        true => output_path.file_stem(),
+        false => input_path.file_name(),
+    }
+    .and_then(|name| name.to_str())
+    .filter(|name| name.len() <= u16::MAX as usize)
+    .unwrap_or("data")
+    .to_string();
+
+    let stub_path = match &options.stub {
+        Some(stub) => stub.clone(),
+        None => std::env::current_exe()?,
+    };
+    let mut stub = File::open(&stub_path)?;
+    let mut output = create_output(output_path, options)?;
+
+    let offset = io::copy(&mut stub, &mut output)?;
+    let report = write_compressed(input_path, &mut output, letter_size, options)?;
+
+    output.write_all(name.as_bytes())?;
+    output.write_all(&(name.len() as u16).to_be_bytes())?;
+    output.write_all(&offset.to_be_bytes())?;
+    output.write_all(SFX_MAGIC)?;
+    output.flush()?;
+    drop(output);
+
+    #[cfg(unix)]
+    if !is_stdio(output_path) {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(output_path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(report)
+}
+
+/// The executable this runs as when it is a self-extracting output: the file, the range of
+/// the stream in it and the name of the original file.
+fn self_extracting_payload() -> Option<(File, u64, u64, String)> {
+    let mut exe = File::open(std::env::current_exe().ok()?).ok()?;
+    let len = exe.metadata().ok()?.len();
+    let mut trailer = [0; SFX_TRAILER_SIZE as usize];
+
+    exe.seek(SeekFrom::Start(len.checked_sub(SFX_TRAILER_SIZE)?))
+        .ok()?;
+    exe.read_exact(&mut trailer).ok()?;
+
+    if &trailer[10..] != SFX_MAGIC {
+        return None;
+    }
+
+    let name_len = u16::from_be_bytes(trailer[..2].try_into().unwrap()) as u64;
+    let offset = u64::from_be_bytes(trailer[2..10].try_into().unwrap());
+    let end = (len - SFX_TRAILER_SIZE).checked_sub(name_len)?;
+
+    if offset > end {
+        return None;
+    }
+
+    let mut name = vec![0; name_len as usize];
+    exe.seek(SeekFrom::Start(end)).ok()?;
+    exe.read_exact(&mut name).ok()?;
+
+    Some((exe, offset, end, String::from_utf8(name).ok()?))
+}
+
+/// Extracts the stream between `offset` and `end` of the self-extracting output `exe` to the
+/// path given as the only argument, or under `name` in the current directory. Existing files
+/// are left alone, and what a failed extraction wrote is removed.
+fn run_self_extraction(mut exe: File, offset: u64, end: u64, name: String) -> ExitCode {
+    let output_path = match std::env::args_os().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(&name),
+    };
+    let mut created = false;
+
+    let extract = || -> io::Result<CompressionReport> {
+        // Names come from the file, so they must not lead anywhere else.
+        if Path::new(&name).file_name() != Some(name.as_ref()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid file name {}", name),
+            ));
+        }
+
+        exe.seek(SeekFrom::Start(offset))?;
+        let mut input = BufReader::with_capacity(32 * 1024, exe.take(end - offset));
+        let encrypted = detect(input.fill_buf()?).is_some_and(|format| format.encrypted);
+        let password = match encrypted {
+            true => Some(rpassword::prompt_password("Password: ")?),
+            false => None,
+        };
+
+        let output = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&output_path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already exists, give another path to extract to",
+                        output_path.display()
+                    ),
+                ),
+                _ => e,
+            })?;
+        created = true;
+        let mut output = BufWriter::with_capacity(32 * 1024, output);
+
+        let mut decompressor = Decompressor::new();
+
+        if let Some(password) = &password {
+            decompressor = decompressor.password(password.as_bytes());
+        }
+
+        let report = decompressor.decompress(input, &mut output)?;
+        output.flush()?;
+
+        Ok(report)
+    };
+
+    match extract() {
+        Ok(report) => {
+            println!(
+                "Extracted {} ({} bytes)",
+                output_path.display(),
+                report.output_size
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!(
+                "{} failed to extract {}: {}",
+                error_label(),
+                output_path.display(),
+                e
+            );
+
+            if created {
+                let _ = fs::remove_file(&output_path);
+            }
+
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+/// Compresses `input_path` into `output` as the options say.
+fn write_compressed(
+    input_path: &Path,
+    mut output: &mut dyn Write,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    // Compression makes two passes over the input, so stdin is read into memory first
+    // unless the code adapts as it goes or every block builds its own.
+    let report = if options.gzip {
+        compress_gzip(input_path, &mut output, options)?
+    } else if is_stdio(input_path) && (options.adaptive || options.lz77 || options.block_trees) {
+        compress_stdin(&mut output, letter_size, options)?
+    } else if is_stdio(input_path) {
+        let mut input = Vec::new();
+        io::stdin().lock().read_to_end(&mut input)?;
+        compress_seekable(
+            Cursor::new(input),
+            letter_size,
+            None,
+            None,
+            options,
+            |mut compressor, input| compressor.compress(input, output),
+        )?
+    } else {
+        let (input, mtime, mode) = open_uncompressed(input_path, options)?;
+        compress_seekable(
+            input,
+            letter_size,
+            mtime,
+            mode,
+            options,
+            |mut compressor, input| compressor.compress(input, output),
+        )?
+    };
+
+    Ok(report)
+}
+
+/// Opens `input_path` to compress, with the modification time and permission bits stored with
+/// it as the options say.
+fn open_uncompressed(
+    input_path: &Path,
+    options: &Options,
+) -> io::Result<(BufReader<File>, Option<SystemTime>, Option<u32>)> {
+    let input = File::open(input_path)?;
+    let metadata = input.metadata()?;
+    let mtime = match options.no_mtime {
+        true => None,
+        false => Some(metadata.modified()?),
+    };
+    let mode = permission_bits(&metadata).filter(|_| options.preserve_perms);
+
+    Ok((BufReader::with_capacity(32 * 1024, input), mtime, mode))
+}
+
+/// Whether `output_path` starts as compressing `input_path` does, as outputs an interrupted
+/// run left unfinished do.
+fn is_unfinished(input_path: &Path, output_path: &Path) -> bool {
+    if is_stdio(input_path) || is_stdio(output_path) {
+        return false;
+    }
+
+    let size = fs::metadata(input_path).map(|metadata| metadata.len());
+    let recorded = File::open(output_path)
+        .ok()
+        .and_then(detect)
+        .map(|format| format.original_size);
+
+    recorded.is_some() && recorded == size.ok()
+}
+
+/// Compresses `input_path` into `output`, an output of it an interrupted run left unfinished,
+/// after the blocks it holds that decode to the input, and cuts it to the finished stream.
+fn resume_compressed(
+    input_path: &Path,
+    mut output: File,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    let (input, mtime, mode) = open_uncompressed(input_path, options)?;
+    let report = compress_seekable(
+        input,
+        letter_size,
+        mtime,
+        mode,
+        options,
+        |mut compressor, input| compressor.resume(input, &mut output),
+    )?;
+    output.set_len(report.output_size)?;
+
+    Ok(report)
+}
+
+fn decompress_path(
+    input_path: &Path,
+    output_path: &Path,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    let input = open_input(input_path)?;
+    let mut output = create_output(output_path, options)?;
+
+    let bar = progress_bar(options);
+    bar.set_message("decoding");
+
+    let mut damaged = 0;
+    let mut decompressor = decompressor(options).progress(|processed, total| {
+        bar.set_length(total);
+        bar.set_position(processed);
+    });
+
+    if options.bad_blocks != BadBlocks::Fail {
+        decompressor = decompressor.bad_blocks(options.bad_blocks, |block| {
+            damaged += 1;
+            bar.suspend(|| {
+                eprintln!(
+                    "{} {}: {}",
+                    paint("Warning", WARNING, stderr_colored()),
+                    input_path.display(),
+                    bad_block_message(&block)
+                )
+            });
+        });
+    }
+
+    let report = decompressor.decompress(input, &mut output);
+    drop(decompressor);
+
+    bar.finish_and_clear();
+    let report = report?;
+    output.flush()?;
+
+    // The output is kept, but is not what was compressed.
+    if damaged != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} damaged {} left out of the output",
+                damaged,
+                blocks_noun(damaged)
+            ),
+        ));
+    }
+
+    Ok(report)
+}
+
+fn blocks_noun(count: usize) -> &'static str {
+    match count {
+        1 => "block",
+        _ => "blocks",
+    }
+}
+
+/// What happened to a damaged block.
+fn bad_block_message(block: &BadBlock) -> String {
+    match block.skipped {
+        Some(len) => format!(
+            "block {} is damaged, {} zero bytes written at byte {} in its place ({})",
+            block.index, len, block.offset, block.error
+        ),
+        None => format!(
+            "block {} is damaged, the output ends at byte {} before it ({})",
+            block.index, block.offset, block.error
+        ),
+    }
+}
+
+/// What compressing `input_path` would produce. Only the letter size, the sizes and the
+/// output size estimate are filled in.
+fn plan_compression(
+    input_path: &Path,
+    output_path: &Path,
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    check_output(output_path, options)?;
+
+    let candidates = match letter_size {
+        LetterSize::Bits(bits) => vec![bits],
+        LetterSize::Auto => AUTO_LETTER_SIZES.to_vec(),
+        LetterSize::Level(level) => level.letter_sizes.to_vec(),
+    };
+
+    let (input_size, (letter_size, output_size)) = if is_stdio(input_path) {
+        let mut input = Vec::new();
+        io::stdin().lock().read_to_end(&mut input)?;
+        let input_size = input.len() as u64;
+        (
+            input_size,
+            best_letter_size(Cursor::new(input), &candidates)?,
+        )
+    } else {
+        let input = File::open(input_path)?;
+        let input_size = input.metadata()?.len();
+        let input = BufReader::with_capacity(32 * 1024, input);
+        (input_size, best_letter_size(input, &candidates)?)
+    };
+
+    Ok(CompressionReport {
+        letter_size,
+        input_size,
+        output_size: output_size + options.checksum.len() as u64,
+        ..CompressionReport::default()
+    })
+}
+
+/// What decompressing `input_path` would produce, read from its fixed header fields.
+fn plan_decompression(
+    input_path: &Path,
+    output_path: &Path,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    check_output(output_path, options)?;
+
+    let mut input = open_input(input_path)?;
+    let info = detect(&mut input)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a compressed file"))?;
+
+    let input_size = if is_stdio(input_path) {
+        PREFIX_SIZE + io::copy(&mut input, &mut io::sink())?
+    } else {
+        fs::metadata(input_path)?.len()
+    };
+
+    Ok(CompressionReport {
+        letter_size: info.letter_size,
+        input_size,
+        output_size: info.original_size,
+        ..CompressionReport::default()
+    })
+}
+
+fn print_plan(
+    operation: &str,
+    input_path: &Path,
+    output_path: &Path,
+    report: &CompressionReport,
+    options: &Options,
+) {
+    if options.quiet() {
+        return;
+    }
+
+    if options.json {
+        let summary = json!({
+            "operation": operation,
+            "dry_run": true,
+            "input": input_path.to_string_lossy(),
+            "output": output_path.to_string_lossy(),
+            "letter_size": report.letter_size,
+            "input_size": report.input_size,
+            "output_size": report.output_size,
+        });
+
+        println!("{}", summary);
+    } else {
+        println!(
+            "Would {} {} ({}) to {} ({}, {} bit letters)",
+            operation,
+            input_path.display(),
+            options.size(report.input_size),
+            output_path.display(),
+            options.size(report.output_size),
+            report.letter_size
+        );
+    }
+}
+
+/// Appends the `-v` lines for `report` to `summary`, and with `-vv` the share of the elapsed
+/// time each phase took. Phases that did not run, such as counting on decompression, are left
+/// out.
+fn write_details(summary: &mut String, report: &CompressionReport, options: &Options) {
+    if options.verbosity < Verbosity::Verbose {
+        return;
+    }
+
+    writeln!(summary, "Letter size: {} bits", report.letter_size).unwrap();
+    writeln!(summary, "Distinct symbols: {}", report.symbol_count).unwrap();
+    writeln!(summary, "Tree depth: {}", report.tree_depth).unwrap();
+
+    let timings = &report.timings;
+    let phases = [
+        ("Counting", timings.counting),
+        ("Tree construction", timings.tree),
+        ("Header", timings.header),
+        ("Coding", timings.coding),
+    ];
+
+    for (name, elapsed) in phases.into_iter().filter(|(_, elapsed)| !elapsed.is_zero()) {
+        write!(summary, "{}: {:.3} (s)", name, elapsed.as_secs_f32()).unwrap();
+        if options.verbosity >= Verbosity::VeryVerbose && !report.elapsed.is_zero() {
+            let share = elapsed.as_secs_f64() / report.elapsed.as_secs_f64();
+            write!(summary, " ({:.1} %)", share * 100.0).unwrap();
+        }
+        writeln!(summary).unwrap();
+    }
+}
+
+/// One JSON object per processed file, written on a single line.
+fn json_summary(
+    operation: &str,
+    input_path: &Path,
+    output_path: &Path,
+    report: &CompressionReport,
+) -> String {
+    let summary = json!({
+        "operation": operation,
+        "input": input_path.to_string_lossy(),
+        "output": output_path.to_string_lossy(),
+        "letter_size": report.letter_size,
+        "input_size": report.input_size,
+        "output_size": report.output_size,
+        "ratio": report.ratio(),
+        "symbol_count": report.symbol_count,
+        "tree_depth": report.tree_depth,
+        "elapsed": report.elapsed.as_secs_f64(),
+        "timings": {
+            "counting": report.timings.counting.as_secs_f64(),
+            "tree": report.timings.tree.as_secs_f64(),
+            "header": report.timings.header.as_secs_f64(),
+            "coding": report.timings.coding.as_secs_f64(),
+        },
+    });
+
+    format!("{}\n", summary)
+}
+
+/// Gives `output_path` the owner of `input_path`. Only root may give files away, so other
+/// users keep ownership of what they write.
+#[cfg(unix)]
+fn copy_owner(metadata: &fs::Metadata, output_path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::os::unix::fs::chown(output_path, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_metadata: &fs::Metadata, _output_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The permission bits `--preserve-perms` stores in compressed files.
+#[cfg(unix)]
+fn permission_bits(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn permission_bits(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// `permissions` with the permission bits stored in a compressed file.
+#[cfg(unix)]
+fn with_permission_bits(_permissions: fs::Permissions, mode: u32) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::Permissions::from_mode(mode & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn with_permission_bits(permissions: fs::Permissions, _mode: u32) -> fs::Permissions {
+    permissions
+}
+
+/// Copies the modification time (`--preserve-times`) and the permissions and owner
+/// (`--preserve-perms`) of `input_path` to `output_path`. `mtime` and `mode` replace the
+/// modification time and permission bits of `input_path` when given, as they are when a
+/// compressed file stores those of its original.
+fn copy_metadata(
+    input_path: &Path,
+    output_path: &Path,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+    options: &Options,
+) {
+    if is_stdio(input_path) || is_stdio(output_path) {
+        return;
+    }
+
+    let copy = || -> io::Result<()> {
+        let metadata = fs::metadata(input_path)?;
+
+        // Before the permissions, which may make the output read only.
+        if options.preserve_times {
+            File::options()
+                .write(true)
+                .open(output_path)?
+                .set_modified(mtime.map_or_else(|| metadata.modified(), Ok)?)?;
+        }
+
+        if options.preserve_perms {
+            let permissions = match mode {
+                Some(mode) => with_permission_bits(metadata.permissions(), mode),
+                None => metadata.permissions(),
+            };
+
+            copy_owner(&metadata, output_path)?;
+            fs::set_permissions(output_path, permissions)?;
+        }
+
+        Ok(())
+    };
+
+    if let Err(e) = copy() {
+        report(
+            format_args!("failed to copy metadata to {}", output_path.display()),
+            &e,
+        );
+    }
+}
+
+/// Deletes `path` when `--rm` was given. Stdin is never touched.
+fn remove_input(path: &Path, options: &Options) {
+    if !options.remove || is_stdio(path) {
+        return;
+    }
+
+    if let Err(e) = fs::remove_file(path) {
+        report(format_args!("failed to remove {}", path.display()), &e);
+    }
+}
+
+fn run_compression(
+    input_path: &Path,
+    output_path: &Path,
+    letter_size: LetterSize,
+    options: &Options,
+) -> Option<CompressionReport> {
+    if options.dry_run {
+        let plan = plan_compression(input_path, output_path, letter_size, options);
+        match &plan {
+            Err(e) => report("failed to compress", e),
+            Ok(report) => print_plan("compress", input_path, output_path, report, options),
+        }
+        return plan.ok();
+    }
+
+    let result = compress_path(input_path, output_path, letter_size, options);
+
+    if result.is_ok() {
+        // Volumes of split output keep metadata of their own.
+        if options.split_size.is_none() {
+            copy_metadata(input_path, output_path, None, None, options);
+        }
+        remove_input(input_path, options);
+    }
+
+    match &result {
+        Err(e) => report("failed to compress", e),
+        Ok(_) if options.quiet() => {}
+        Ok(report) if options.json => {
+            let summary = json_summary("compress", input_path, output_path, report);
+            print_summary(output_path, &summary);
+        }
+        Ok(report) => {
+            let compression_ratio = report.ratio();
+            let mut summary = String::new();
+
+            writeln!(summary, "-------------------------------------").unwrap();
+            writeln!(summary, "Compression finished").unwrap();
+            if let (LetterSize::Auto | LetterSize::Level(_), Verbosity::Normal) =
+                (letter_size, options.verbosity)
+            {
+                writeln!(summary, "Letter size: {} bits (auto)", report.letter_size).unwrap();
+            }
+            writeln!(
+                summary,
+                "Input file size: {}",
+                options.size(report.input_size)
+            )
+            .unwrap();
+            writeln!(
+                summary,
+                "Output file size: {}",
+                options.size(report.output_size)
+            )
+            .unwrap();
+            // A ratio below 1 means the output grew.
+            let style = match compression_ratio < 1.0 {
+                true => WARNING,
+                false => GOOD,
+            };
+            let ratio = format!(
+                "Compression ratio: {:.3} ({:.2} %)",
+                compression_ratio,
+                compression_ratio * 100.0
+            );
+            writeln!(
+                summary,
+                "{}",
+                paint(ratio, style, summary_colored(output_path))
+            )
+            .unwrap();
+            writeln!(summary, "Elapsed: {:.3} (s)", report.elapsed.as_secs_f32()).unwrap();
+            write_details(&mut summary, report, options);
+
+            print_summary(output_path, &summary);
+        }
+    }
+
+    result.ok()
+}
+
+fn run_decompression(
+    input_path: &Path,
+    output_path: &Path,
+    options: &Options,
+) -> Option<CompressionReport> {
+    if options.dry_run {
+        let plan = plan_decompression(input_path, output_path, options);
+        match &plan {
+            Err(e) => report("failed to decompress", e),
+            Ok(report) => print_plan("decompress", input_path, output_path, report, options),
+        }
+        return plan.ok();
+    }
+
+    let result = decompress_path(input_path, output_path, options);
+
+    if result.is_ok() {
+        // The time and permissions stored at compression outlive copies of the compressed
+        // file.
+        let format = open_input(input_path).ok().and_then(detect);
+        let mtime = format.and_then(|format| format.mtime);
+        let mode = format.and_then(|format| format.mode);
+        copy_metadata(input_path, output_path, mtime, mode, options);
+        remove_input(input_path, options);
+    }
+
+    match &result {
+        Err(e) => report("failed to decompress", e),
+        Ok(_) if options.quiet() => {}
+        Ok(report) if options.json => {
+            let summary = json_summary("decompress", input_path, output_path, report);
+            print_summary(output_path, &summary);
+        }
+        Ok(report) => {
+            let mut summary = String::new();
+
+            writeln!(summary, "-------------------------------------").unwrap();
+            writeln!(summary, "Decompression finished").unwrap();
+            writeln!(
+                summary,
+                "Input file size: {}",
+                options.size(report.input_size)
+            )
+            .unwrap();
+            writeln!(
+                summary,
+                "Output file size: {}",
+                options.size(report.output_size)
+            )
+            .unwrap();
+            writeln!(summary, "Elapsed: {:.3} (s)", report.elapsed.as_secs_f32()).unwrap();
+            write_details(&mut summary, report, options);
+
+            print_summary(output_path, &summary);
+        }
+    }
+
+    result.ok()
+}
+
+/// What compressed outputs are written as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum OutputFormat {
+    /// The format of this crate.
+    #[default]
+    Huffman,
+    /// Standard gzip streams.
+    Gzip,
+}
+
+/// What directory traversal does with symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Symlinks {
+    /// Treat links as the files or directories they point to.
+    Follow,
+    /// Leave links out.
+    #[default]
+    Skip,
+    /// Recreate links at the output instead of processing their targets.
+    Store,
+}
+
+/// Whether `path`, found while walking `root`, matches one of the `--exclude` patterns,
+/// either by its name or by its path relative to `root`.
+fn is_excluded(path: &Path, root: &Path, options: &Options) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let name = path.file_name().map(Path::new);
+
+    options.exclude.iter().any(|pattern| {
+        pattern.matches_path(relative) || name.is_some_and(|name| pattern.matches_path(name))
+    })
+}
+
+/// Appends every file under `dir` to `files`, in sorted order. Links are handled as
+/// `--*-symlinks` says, stored links are appended like files, and excluded files and
+/// directories are left out.
+fn collect_files(dir: &Path, options: &Options, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut ancestors = vec![dir.canonicalize()?];
+    collect_files_in(dir, dir, options, &mut ancestors, files)
+}
+
+fn collect_files_in(
+    root: &Path,
+    dir: &Path,
+    options: &Options,
+    ancestors: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    for path in paths {
+        if is_excluded(&path, root, options) {
+            continue;
+        }
+
+        if path.is_symlink() {
+            match options.symlinks {
+                Symlinks::Skip => continue,
+                Symlinks::Store => {
+                    files.push(path);
+                    continue;
+                }
+                Symlinks::Follow => {}
+            }
+        }
+
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+
+        // Followed links may lead back up the tree.
+        let canonical = path.canonicalize()?;
+
+        if ancestors.contains(&canonical) {
+            eprintln!(
+                "{} skipping {}: symbolic link loop",
+                paint("Warning", WARNING, stderr_colored()),
+                path.display()
+            );
+            continue;
+        }
+
+        ancestors.push(canonical);
+        collect_files_in(root, &path, options, ancestors, files)?;
+        ancestors.pop();
+    }
+
+    Ok(())
+}
+
+/// Recreates the link `input_path` at `output_path`. Relative targets other than directories
+/// are renamed with `link_for` so they keep pointing at the matching output; absolute ones
+/// are kept.
+fn store_symlink<L>(
+    input_path: &Path,
+    output_path: &Path,
+    link_for: L,
+    options: &Options,
+) -> io::Result<PathBuf>
+where
+    L: Fn(&Path) -> io::Result<PathBuf>,
+{
+    let target = fs::read_link(input_path)?;
+    let resolved = input_path.parent().unwrap_or(Path::new("")).join(&target);
+
+    let target = if target.is_relative() && !resolved.is_dir() {
+        link_for(&target)?
+    } else {
+        target
+    };
+
+    if output_path.symlink_metadata().is_ok() {
+        if !options.force {
+            return Err(already_exists(output_path));
+        }
+
+        if !options.dry_run {
+            fs::remove_file(output_path)?;
+        }
+    }
+
+    if !options.dry_run {
+        create_symlink(&target, output_path)?;
+    }
+
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "storing symbolic links is only supported on Unix",
+    ))
+}
+
+/// Runs `run` on each of `files`, writing each to the path `output_for` gives it. Links
+/// kept by `--store-symlinks` are recreated instead, with targets renamed by `link_for`.
+fn run_mapped<F, O, L>(files: Vec<PathBuf>, output_for: O, link_for: L, options: &Options, run: F)
+where
+    F: FnMut(&Path, &Path),
+    O: Fn(&Path) -> io::Result<PathBuf>,
+    L: Fn(&Path) -> io::Result<PathBuf>,
+{
+    let mut run = run;
+
+    for input_path in files {
+        let output_path = match output_for(&input_path) {
+            Ok(output_path) => output_path,
+            Err(e) => {
+                report(input_path.display(), &e);
+                continue;
+            }
+        };
+
+        if let Some(parent) = output_path.parent().filter(|_| !options.dry_run) {
+            if let Err(e) = fs::create_dir_all(parent) {
+                report(format_args!("failed to create {}", parent.display()), &e);
+                continue;
+            }
+        }
+
+        if options.symlinks == Symlinks::Store && input_path.is_symlink() {
+            match store_symlink(&input_path, &output_path, &link_for, options) {
+                Err(e) => report(format_args!("failed to link {}", output_path.display()), &e),
+                Ok(target) => {
+                    if !options.quiet() && !options.json {
+                        let verb = if options.dry_run {
+                            "Would link"
+                        } else {
+                            "Linked"
+                        };
+                        println!("{} {} -> {}", verb, output_path.display(), target.display());
+                    }
+
+                    if !options.dry_run {
+                        remove_input(&input_path, options);
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        if !options.quiet() && !options.json && !options.dry_run {
+            println!("{}", input_path.display());
+        }
+
+        run(&input_path, &output_path);
+    }
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// The leading directories of `pattern` that contain no wildcards.
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|component| !is_glob(Path::new(component.as_os_str())))
+        .collect()
+}
+
+/// Expands `pattern` into the files it matches, descending into matched directories when
+/// `recursive` is set.
+fn glob_files(pattern: &Path, recursive: bool, options: &Options) -> io::Result<Vec<PathBuf>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let pattern_str = pattern
+        .to_str()
+        .ok_or_else(|| invalid(format!("{} is not valid UTF-8", pattern.display())))?;
+    let paths = glob::glob(pattern_str).map_err(|e| invalid(e.to_string()))?;
+
+    let mut files = Vec::new();
+
+    for path in paths {
+        let path = path.map_err(|e| io::Error::new(e.error().kind(), e.to_string()))?;
+
+        if is_excluded(&path, Path::new(""), options) {
+            continue;
+        }
+
+        if !path.is_dir() {
+            files.push(path);
+        } else if recursive {
+            collect_files(&path, options, &mut files)?;
+        }
+    }
+
+    if files.is_empty() {
+        return Err(invalid(format!("no files match {}", pattern.display())));
+    }
+
+    Ok(files)
+}
+
+/// Extensions of formats that are compressed already and only grow when compressed again.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "br", "bz2", "gz", "huff", "jpeg", "jpg", "lz4", "mp3", "mp4", "png", "rar", "webp",
+    "xz", "zip", "zst",
+];
+
+/// Bits of entropy per byte above which data looks already compressed.
+const COMPRESSED_ENTROPY: f64 = 7.9;
+
+/// Number of leading bytes whose entropy is measured by [`looks_compressed`].
+const ENTROPY_SAMPLE: u64 = 64 * 1024;
+
+/// Whether `path` looks compressed already, judged by its extension, which may also be the
+/// `suffix` of our own outputs, or, failing that, by the entropy of its first bytes.
+fn looks_compressed(path: &Path, suffix: &str) -> io::Result<bool> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension == suffix)
+    {
+        return Ok(true);
+    }
+
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+    if extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str())) {
+        return Ok(true);
+    }
+
+    let sample = BufReader::new(File::open(path)?.take(ENTROPY_SAMPLE));
+    let histogram = count_frequencies(sample, 8)?;
+
+    Ok(histogram.entropy() >= COMPRESSED_ENTROPY)
+}
+
+/// Batch mode filter for compression: reports and drops files that look compressed already.
+fn skip_compressed(file: &Path, options: &Options) -> bool {
+    match looks_compressed(file, &options.suffix) {
+        Ok(true) => {
+            if !options.quiet() && !options.json {
+                println!("{}: skipped, already compressed", file.display());
+            }
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            report(format_args!("failed to read {}", file.display()), &e);
+            true
+        }
+    }
+}
+
+/// Sizes summed over every file of a run.
+#[derive(Debug, Default)]
+struct Totals {
+    files: usize,
+    input_size: u64,
+    output_size: u64,
+}
+
+impl Totals {
+    fn add(&mut self, report: &CompressionReport) {
+        self.files += 1;
+        self.input_size += report.input_size;
+        self.output_size += report.output_size;
+    }
+
+    /// Prints the totals when more than one file was written.
+    fn print(&self, options: &Options) {
+        if self.files < 2 || options.quiet() || options.json || options.dry_run {
+            return;
+        }
+
+        println!("-------------------------------------");
+        println!("Total: {} files", self.files);
+        println!("Input size: {}", options.size(self.input_size));
+        println!("Output size: {}", options.size(self.output_size));
+    }
+}
+
+/// `--stats-csv`: a row for every file written, appended to a CSV file.
+struct StatsCsv {
+    file: File,
+}
+
+impl StatsCsv {
+    /// Opens `path` for appending, writing the column names when it is new.
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "name,input_size,output_size,ratio,seconds")?;
+        }
+
+        Ok(StatsCsv { file })
+    }
+
+    fn add(&mut self, input_path: &Path, report: &CompressionReport) -> io::Result<()> {
+        let name = input_path.to_string_lossy();
+        let name = if name.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", name.replace('"', "\"\""))
+        } else {
+            name.into_owned()
+        };
+
+        writeln!(
+            self.file,
+            "{},{},{},{:.4},{:.3}",
+            name,
+            report.input_size,
+            report.output_size,
+            report.ratio(),
+            report.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Where outputs are written.
+#[derive(Debug, Clone, Copy)]
+enum Destination<'a> {
+    /// Next to their inputs, named by the subcommand.
+    Beside,
+    /// `--output`: the output of a single input, or a directory several are written into
+    /// under their input names.
+    Output(&'a Path),
+    /// `--output-dir`: a directory outputs are written into under the names they would get
+    /// next to their inputs.
+    Directory(&'a Path),
+}
+
+/// Runs `run` on every input selected by `args` and prints the totals. With a single input
+/// `--output` names its output; with several it names a directory to write them into.
+fn for_each_input<D, S, F>(args: &PathArgs, options: &Options, default_output: &D, skip: S, run: F)
+where
+    D: Fn(&Path) -> io::Result<PathBuf>,
+    S: Fn(&Path) -> bool,
+    F: FnMut(&Path, &Path) -> Option<CompressionReport>,
+{
+    let (input_paths, destination) = paths(args, options);
+    let several = input_paths.len() > 1;
+    let recursive = args.recursive;
+    let mut run = run;
+    let mut totals = Totals::default();
+
+    let stats_path = args.stats_csv.as_ref().filter(|_| !options.dry_run);
+    let mut stats = match stats_path.map(|path| StatsCsv::open(path)).transpose() {
+        Ok(stats) => stats,
+        Err(e) => {
+            report(
+                format_args!("failed to open {}", stats_path.unwrap().display()),
+                &e,
+            );
+            return;
+        }
+    };
+
+    let mut run = |input_path: &Path, output_path: &Path| {
+        let Some(summary) = run(input_path, output_path) else {
+            return;
+        };
+
+        totals.add(&summary);
+
+        if let Some(Err(e)) = stats.as_mut().map(|stats| stats.add(input_path, &summary)) {
+            report(
+                format_args!("failed to write {}", stats_path.unwrap().display()),
+                &e,
+            );
+        }
+    };
+
+    // `-c` is global, so clap cannot tell it apart from `-o` when it comes first.
+    if options.stdout && (args.outputs.output.is_some() || args.outputs.output_dir.is_some()) {
+        fail(EXIT_USAGE);
+        eprintln!(
+            "{} --stdout cannot be used together with --output or --output-dir",
+            error_label()
+        );
+        return;
+    }
+
+    if several && input_paths.iter().any(|path| is_stdio(path)) {
+        fail(EXIT_USAGE);
+        eprintln!(
+            "{} stdin cannot be read together with other inputs",
+            error_label()
+        );
+        return;
+    }
+
+    match destination {
+        Destination::Output(path) if several && is_stdio(path) => {
+            fail(EXIT_USAGE);
+            eprintln!(
+                "{} multiple files cannot be written to stdout",
+                error_label()
+            );
+            return;
+        }
+        Destination::Directory(_) if input_paths.iter().any(|path| is_stdio(path)) => {
+            fail(EXIT_USAGE);
+            eprintln!(
+                "{} stdin has no name to write under --output-dir, use --output",
+                error_label()
+            );
+            return;
+        }
+        _ => {}
+    }
+
+    for input_path in input_paths {
+        let glob = is_glob(input_path) && !input_path.exists();
+
+        if !several || glob || input_path.is_dir() {
+            for_input(
+                input_path,
+                destination,
+                recursive,
+                options,
+                default_output,
+                &skip,
+                &mut run,
+            );
+            continue;
+        }
+
+        // Named files are never skipped and their outputs go straight into the directory.
+        let files = vec![input_path.clone()];
+
+        let file_name = |file: &Path| file.file_name().unwrap_or_default().to_owned();
+
+        match destination {
+            Destination::Output(output_dir) => {
+                let output_for = |file: &Path| Ok(output_dir.join(file_name(file)));
+                let link_for = |target: &Path| Ok(target.to_path_buf());
+
+                run_mapped(files, output_for, link_for, options, &mut run);
+            }
+            Destination::Directory(output_dir) => {
+                let output_for = |file: &Path| default_output(&output_dir.join(file_name(file)));
+
+                run_mapped(files, output_for, default_output, options, &mut run);
+            }
+            Destination::Beside => {
+                run_mapped(files, default_output, default_output, options, &mut run)
+            }
+        }
+    }
+
+    totals.print(options);
+}
+
+/// Runs `run` on every file selected by `input_path`. Outputs are named by `default_output`
+/// and mirrored under the directory `destination` names, if any. When several files are
+/// selected, those for which `skip` returns true are left alone.
+fn for_input<D, S, F>(
+    input_path: &Path,
+    destination: Destination,
+    recursive: bool,
+    options: &Options,
+    default_output: &D,
+    skip: &S,
+    run: &mut F,
+) where
+    D: Fn(&Path) -> io::Result<PathBuf>,
+    S: Fn(&Path) -> bool,
+    F: FnMut(&Path, &Path),
+{
+    let (files, base) = if is_glob(input_path) && !input_path.exists() {
+        match glob_files(input_path, recursive, options) {
+            Err(e) => {
+                report(
+                    format_args!("failed to expand {}", input_path.display()),
+                    &e,
+                );
+                return;
+            }
+            Ok(files) => (files, glob_base(input_path)),
+        }
+    } else if !input_path.is_dir() {
+        let output_path = match destination {
+            Destination::Output(output_path) => Ok(output_path.to_path_buf()),
+            Destination::Beside if is_stdio(input_path) => Ok(PathBuf::from("-")),
+            Destination::Beside => default_output(input_path),
+            Destination::Directory(output_dir) => {
+                let file_name = input_path.file_name().unwrap_or_default();
+                let created = match options.dry_run {
+                    true => Ok(()),
+                    false => fs::create_dir_all(output_dir),
+                };
+
+                created.and_then(|()| default_output(&output_dir.join(file_name)))
+            }
+        };
+
+        match output_path {
+            Err(e) => report(input_path.display(), &e),
+            Ok(output_path) => run(input_path, &output_path),
+        }
+
+        return;
+    } else if !recursive {
+        fail(EXIT_USAGE);
+        eprintln!(
+            "{} {} is a directory, use -r to process it recursively",
+            error_label(),
+            input_path.display()
+        );
+        return;
+    } else {
+        let mut files = Vec::new();
+
+        match collect_files(input_path, options, &mut files) {
+            Err(e) => {
+                report(format_args!("failed to read {}", input_path.display()), &e);
+                return;
+            }
+            Ok(()) => (files, input_path.to_path_buf()),
+        }
+    };
+
+    let stored = |file: &Path| options.symlinks == Symlinks::Store && file.is_symlink();
+    let files = files
+        .into_iter()
+        .filter(|file| stored(file) || !skip(file))
+        .collect();
+
+    let relative = |file: &Path| file.strip_prefix(&base).unwrap_or(file).to_path_buf();
+
+    match destination {
+        Destination::Output(output_path) if is_stdio(output_path) => {
+            fail(EXIT_USAGE);
+            eprintln!(
+                "{} multiple files cannot be written to stdout",
+                error_label()
+            );
+        }
+        Destination::Output(output_dir) => {
+            let output_for = |file: &Path| Ok(output_dir.join(relative(file)));
+
+            // Mirrored files keep their names, so relative links need no renaming.
+            let link_for = |target: &Path| Ok(target.to_path_buf());
+
+            run_mapped(files, output_for, link_for, options, run);
+        }
+        Destination::Directory(output_dir) => {
+            let output_for = |file: &Path| default_output(&output_dir.join(relative(file)));
+
+            run_mapped(files, output_for, default_output, options, run);
+        }
+        Destination::Beside => run_mapped(files, default_output, default_output, options, run),
+    }
+}
+
+/// How long a file has to stay unchanged before `watch` compresses it, so files that are still
+/// being written are not picked up half done.
+const WATCH_SETTLE: Duration = Duration::from_millis(500);
+
+/// Compresses files created or modified under `dir` into the same place under `output_dir`
+/// until interrupted.
+fn run_watch(dir: &Path, output_dir: &Path, letter_size: LetterSize, options: &Options) {
+    let dirs = fs::create_dir_all(output_dir)
+        .and_then(|()| Ok((dir.canonicalize()?, output_dir.canonicalize()?)));
+    let (dir, output_dir) = match dirs {
+        Ok(dirs) => dirs,
+        Err(e) => {
+            report(format_args!("failed to watch {}", dir.display()), &e);
+            return;
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let watcher = notify::recommended_watcher(sender).and_then(|mut watcher| {
+        watcher
+            .watch(&dir, RecursiveMode::Recursive)
+            .map(|()| watcher)
+    });
+
+    // Dropping the watcher stops it, so it is kept until the loop ends.
+    let _watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            report(
+                format_args!("failed to watch {}", dir.display()),
+                &io::Error::other(e),
+            );
+            return;
+        }
+    };
+
+    if !options.quiet() && !options.json {
+        println!("Watching {}", dir.display());
+    }
+
+    let mut pending = HashMap::new();
+
+    loop {
+        match receiver.recv_timeout(WATCH_SETTLE) {
+            Ok(Ok(event)) => {
+                let changed = match event.kind {
+                    EventKind::Modify(ModifyKind::Metadata(_)) => false,
+                    EventKind::Create(_) | EventKind::Modify(_) => true,
+                    _ => false,
+                };
+
+                for path in event.paths.into_iter().filter(|_| changed) {
+                    // Outputs may be written inside the watched directory.
+                    if !path.starts_with(&output_dir) && !is_excluded(&path, &dir, options) {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => report("failed to watch", &io::Error::other(e)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let settled = pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= WATCH_SETTLE)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<PathBuf>>();
+
+        for input_path in settled {
+            pending.remove(&input_path);
+
+            // Directories and files that were removed again.
+            if !input_path.is_file() {
+                continue;
+            }
+
+            let relative = input_path.strip_prefix(&dir).unwrap_or(&input_path);
+            let output_path = output_dir.join(relative);
+            let created = output_path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| compressed_name(&output_path, &options.suffix));
+
+            match created {
+                Err(e) => report(input_path.display(), &e),
+                Ok(output_path) => {
+                    if !options.quiet() && !options.json {
+                        println!("{}", input_path.display());
+                    }
+
+                    run_compression(&input_path, &output_path, letter_size, options);
+                }
+            }
+        }
+    }
+}
+
+/// Path of `file` inside an archive: its normal components joined with `/`.
+fn archive_path(file: &Path) -> String {
+    file.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn pack_files(
+    archive: &Path,
+    inputs: &[PathBuf],
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<Vec<ArchiveEntry>> {
+    let mut files = Vec::new();
+
+    for input in inputs {
+        if input.is_dir() {
+            collect_files(input, options, &mut files)?;
+        } else {
+            files.push(input.clone());
+        }
+    }
+
+    let output = BufWriter::with_capacity(32 * 1024, create_file(archive, options)?);
+    let mut writer = ArchiveWriter::new(output)?;
+    let mut entries = Vec::new();
+
+    for file in files {
+        if file.is_symlink() && options.symlinks == Symlinks::Store {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} is a symbolic link, archives cannot store links",
+                    file.display()
+                ),
+            ));
+        }
+
+        let mut input = BufReader::with_capacity(32 * 1024, File::open(&file)?);
+        let letter_size = resolve_letter_size(&mut input, letter_size)?;
+
+        entries.push(writer.add(&archive_path(&file), input, letter_size)?);
+    }
+
+    writer.finish()?;
+
+    Ok(entries)
+}
+
+fn unpack_files(
+    archive: &Path,
+    output_dir: &Path,
+    options: &Options,
+) -> io::Result<Vec<ArchiveEntry>> {
+    let input = BufReader::with_capacity(32 * 1024, File::open(archive)?);
+    let mut reader = ArchiveReader::new(input)?;
+    let mut entries = Vec::new();
+
+    while let Some(entry) = reader.next_entry()? {
+        let output_path = output_dir.join(&entry.path);
+
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut output = BufWriter::with_capacity(32 * 1024, create_file(&output_path, options)?);
+        reader.extract(&mut output)?;
+        output.flush()?;
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn print_entries(entries: &[ArchiveEntry], options: &Options) {
+    for entry in entries {
+        println!(
+            "{}: {}, {} compressed",
+            entry.path,
+            options.size(entry.size),
+            options.size(entry.compressed_size)
+        );
+    }
+}
+
+fn print_entries_json(operation: &str, archive: &Path, entries: &[ArchiveEntry]) {
+    let files = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "path": entry.path,
+                "size": entry.size,
+                "compressed_size": entry.compressed_size,
+                "ratio": entry.ratio(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let summary = json!({
+        "operation": operation,
+        "archive": archive.to_string_lossy(),
+        "files": files,
+    });
+
+    println!("{}", summary);
+}
+
+fn run_pack(archive: &Path, inputs: &[PathBuf], letter_size: LetterSize, options: &Options) {
+    match pack_files(archive, inputs, letter_size, options) {
+        Err(e) => report("failed to pack", &e),
+        Ok(_) if options.quiet() => {}
+        Ok(entries) if options.json => print_entries_json("pack", archive, &entries),
+        Ok(entries) => {
+            print_entries(&entries, options);
+            println!("-------------------------------------");
+            println!("Packed {} files", entries.len());
+        }
+    }
+}
+
+/// `NAME=FILE`, a stream of a container and the file it is compressed from.
+fn parse_named_stream(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected NAME=FILE, got {}", value)),
+    }
+}
+
+/// Streams of a container as archive entries named after them, so they print alike.
+fn stream_entries(entries: &[StreamEntry]) -> Vec<ArchiveEntry> {
+    entries
+        .iter()
+        .map(|entry| ArchiveEntry {
+            path: entry.name.clone(),
+            size: entry.size,
+            compressed_size: entry.compressed_size,
+        })
+        .collect()
+}
+
+fn bundle_files(
+    container: &Path,
+    streams: &[(String, PathBuf)],
+    letter_size: LetterSize,
+    options: &Options,
+) -> io::Result<Vec<StreamEntry>> {
+    let output = BufWriter::with_capacity(32 * 1024, create_file(container, options)?);
+    let mut writer = ContainerWriter::new(output)?;
+    let mut entries = Vec::new();
+
+    for (name, file) in streams {
+        let mut input = BufReader::with_capacity(32 * 1024, File::open(file)?);
+        let letter_size = resolve_letter_size(&mut input, letter_size)?;
+        let mut compressor = compressor(letter_size, None, None, options);
+
+        entries.push(writer.add_with(name, input, &mut compressor)?);
+    }
+
+    writer.finish()?;
+
+    Ok(entries)
+}
+
+fn run_bundle(
+    container: &Path,
+    streams: &[(String, PathBuf)],
+    letter_size: LetterSize,
+    options: &Options,
+) {
+    match bundle_files(container, streams, letter_size, options) {
+        Err(e) => report("failed to bundle", &e),
+        Ok(_) if options.quiet() => {}
+        Ok(entries) if options.json => {
+            print_entries_json("bundle", container, &stream_entries(&entries))
+        }
+        Ok(entries) => {
+            print_entries(&stream_entries(&entries), options);
+            println!("-------------------------------------");
+            println!("Bundled {} streams", entries.len());
+        }
+    }
+}
+
+/// Decompresses the stream `name` of `container` into `output`, or stdout when there is none.
+fn unbundle_stream(
+    container: &Path,
+    name: &str,
+    output: Option<&Path>,
+    options: &Options,
+) -> io::Result<CompressionReport> {
+    let input = BufReader::with_capacity(32 * 1024, File::open(container)?);
+    let mut reader = ContainerReader::new(input)?;
+    let mut decompressor = decompressor(options);
+
+    let Some(output) = output else {
+        return reader.extract_with(name, io::stdout().lock(), &mut decompressor);
+    };
+
+    let mut output = BufWriter::with_capacity(32 * 1024, create_file(output, options)?);
+    let report = reader.extract_with(name, &mut output, &mut decompressor)?;
+    output.flush()?;
+
+    Ok(report)
+}
+
+fn run_unbundle(container: &Path, name: &str, output: Option<&Path>, options: &Options) {
+    match unbundle_stream(container, name, output, options) {
+        Err(e) => report(format_args!("failed to unbundle {}", name), &e),
+        Ok(_) if options.quiet() || output.is_none() => {}
+        Ok(report) if options.json => println!(
+            "{}",
+            json!({
+                "operation": "unbundle",
+                "container": container.to_string_lossy(),
+                "stream": name,
+                "size": report.output_size,
+            })
+        ),
+        Ok(report) => println!(
+            "{}: {} from stream {}",
+            output.unwrap().display(),
+            options.size(report.output_size),
+            name
+        ),
+    }
+}
+
+fn run_unpack(archive: &Path, output_dir: &Path, options: &Options) {
+    match unpack_files(archive, output_dir, options) {
+        Err(e) => report("failed to unpack", &e),
+        Ok(_) if options.quiet() => {}
+        Ok(entries) if options.json => print_entries_json("unpack", archive, &entries),
+        Ok(entries) => {
+            print_entries(&entries, options);
+            println!("-------------------------------------");
+            println!("Unpacked {} files", entries.len());
+        }
+    }
+}
+
+/// Seconds from the Unix epoch to `time`, which stored times never precede.
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Pre-filters this build can undo.
+const PRE_FILTERS: [&str; 2] = ["bwt", "rle"];
+
+/// Names of the pre-filters a stream was written with.
+fn pre_filters(format: &FormatInfo) -> Vec<&'static str> {
+    [(format.bwt, "bwt"), (format.rle, "rle")]
+        .into_iter()
+        .filter_map(|(used, name)| used.then_some(name))
+        .collect()
+}
+
+fn run_info(path: &Path, options: &Options) {
+    let info = open_input(path).and_then(inspect);
+    let compressed_size = fs::metadata(path).map(|metadata| metadata.len()).ok();
+
+    match info {
+        Err(e) => report(format_args!("failed to read {}", path.display()), &e),
+        Ok(_) if options.quiet() => {}
+        Ok(info) => {
+            let output = &mut io::stdout().lock();
+
+            if let Err(e) = write_info(output, path, &info, compressed_size, options) {
+                report_output(&e);
+            }
+        }
+    }
+}
+
+fn write_info(
+    output: &mut impl Write,
+    path: &Path,
+    info: &StreamInfo,
+    compressed_size: Option<u64>,
+    options: &Options,
+) -> io::Result<()> {
+    if options.json {
+        let summary = json!({
+            "file": path.to_string_lossy(),
+            "version": info.format.version,
+            "letter_size": info.format.letter_size,
+            "checksum": info.format.checksum.name(),
+            "coder": info.format.coder.name(),
+            "encrypted": info.format.encrypted,
+            "cipher": info.format.encryption.map(Encryption::name),
+            "indexed": info.format.indexed,
+            "adaptive": info.format.adaptive,
+            "lz77": info.format.lz77,
+            "order1": info.format.order1,
+            "eos": info.format.eos,
+            "lsb_first": info.format.lsb_first,
+            "pre_filters": pre_filters(&info.format),
+            "mtime": info.format.mtime.map(unix_seconds),
+            "mode": info.format.mode.map(|mode| format!("{:04o}", mode)),
+            "original_size": Some(info.format.original_size).filter(|&size| size != UNKNOWN_SIZE),
+            "compressed_size": compressed_size,
+            "symbol_count": info.symbol_count,
+            "tree_depth": info.tree_depth,
+        });
+
+        return writeln!(output, "{}", summary);
+    }
+
+    writeln!(output, "Format version: {}", info.format.version)?;
+    writeln!(output, "Letter size: {} bits", info.format.letter_size)?;
+    writeln!(output, "Checksum: {}", info.format.checksum)?;
+    writeln!(output, "Coder: {}", info.format.coder)?;
+    if let Some(encryption) = info.format.encryption {
+        writeln!(output, "Encrypted: {}", encryption)?;
+    }
+    if info.format.indexed {
+        writeln!(output, "Block index: yes")?;
+    }
+    if let Some(mtime) = info.format.mtime {
+        writeln!(output, "Modified: {} (Unix time)", unix_seconds(mtime))?;
+    }
+    if let Some(mode) = info.format.mode {
+        writeln!(output, "Permissions: {:04o}", mode)?;
+    }
+    if info.format.adaptive {
+        writeln!(output, "Adaptive: yes")?;
+    }
+    if info.format.lz77 {
+        writeln!(output, "LZ77: yes")?;
+    }
+    if info.format.order1 {
+        writeln!(output, "Order-1 contexts: yes")?;
+    }
+    if info.format.eos {
+        writeln!(output, "End-of-stream letter: yes")?;
+    }
+    if info.format.lsb_first {
+        writeln!(output, "Bit order: LSB first")?;
+    }
+    if !pre_filters(&info.format).is_empty() {
+        writeln!(
+            output,
+            "Pre-filters: {}",
+            pre_filters(&info.format).join(", ")
+        )?;
+    }
+    match info.format.original_size {
+        UNKNOWN_SIZE => writeln!(output, "Original size: unknown")?,
+        size => writeln!(output, "Original size: {}", options.size(size))?,
+    }
+    if let Some(compressed_size) = compressed_size {
+        writeln!(output, "Compressed size: {}", options.size(compressed_size))?;
+    }
+    match (info.symbol_count, info.tree_depth) {
+        (Some(symbol_count), Some(tree_depth)) => {
+            writeln!(output, "Distinct symbols: {}", symbol_count)?;
+            writeln!(output, "Tree depth: {}", tree_depth)?;
+        }
+        _ => {
+            writeln!(output, "Distinct symbols: unknown")?;
+            writeln!(output, "Tree depth: unknown")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The password from `--password-file`, or asked for on the terminal with `--password`.
+fn read_password(args: &PasswordArgs, confirm: bool) -> io::Result<Option<String>> {
+    let password = if let Some(path) = &args.password_file {
+        let contents = fs::read_to_string(path)?;
+        contents.lines().next().unwrap_or_default().to_string()
+    } else if args.password {
+        let password = rpassword::prompt_password("Password: ")?;
+
+        if confirm && rpassword::prompt_password("Repeat password: ")? != password {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "passwords do not match",
+            ));
+        }
+
+        password
+    } else {
+        return Ok(None);
+    };
+
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "password is empty",
+        ));
+    }
+
+    Ok(Some(password))
+}
+
+/// A decompressor using the password given on the command line, if any.
+fn decompressor(options: &Options) -> Decompressor<'_> {
+    let mut decompressor = Decompressor::new();
+
+    if let Some(password) = &options.password {
+        decompressor = decompressor.password(password.as_bytes());
+    }
+
+    if let Some(max_memory) = options.max_memory {
+        decompressor = decompressor.limits(Limits::unlimited().max_memory(max_memory));
+    }
+
+    if let Some(dictionary) = &options.dictionary {
+        decompressor = decompressor.code_table(dictionary.table());
+    }
+
+    decompressor
+}
+
+/// The dictionary given with `--dict`, reporting why it could not be read.
+fn read_dictionary(args: &PathArgs) -> Result<Option<Dictionary>, ExitCode> {
+    let Some(path) = &args.dict else {
+        return Ok(None);
+    };
+
+    match File::open(path).and_then(|file| Dictionary::read(BufReader::new(file))) {
+        Ok(dictionary) => Ok(Some(dictionary)),
+        Err(e) => {
+            eprintln!(
+                "{} failed to read dictionary {}: {}",
+                error_label(),
+                path.display(),
+                e
+            );
+            Err(ExitCode::from(EXIT_USAGE))
+        }
+    }
+}
+
+/// Counts the letters of every file in `corpus` and writes the dictionary built from them.
+fn run_train(corpus: &[PathBuf], letter_size: u8, output_path: &Path, options: &Options) {
+    let mut histogram = Histogram::new();
+    let mut corpus_size = 0;
+
+    for path in corpus {
+        let counted = File::open(path).and_then(|input| {
+            corpus_size += input.metadata()?.len();
+            count_frequencies(BufReader::with_capacity(32 * 1024, input), letter_size)
+        });
+
+        match counted {
+            Ok(counted) => histogram.merge(counted),
+            Err(e) => return report(format_args!("failed to count {}", path.display()), &e),
+        }
+    }
+
+    let written = Dictionary::train(&histogram, letter_size).and_then(|dictionary| {
+        let mut output = create_output(output_path, options)?;
+        dictionary.write(&mut output)?;
+        output.flush()?;
+        Ok(dictionary)
+    });
+
+    let dictionary = match written {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            return report(
+                format_args!("failed to write {}", output_path.display()),
+                &e,
+            )
+        }
+    };
+
+    if options.quiet() {
+        return;
+    }
+
+    if options.json {
+        let summary = json!({
+            "output": output_path.to_string_lossy(),
+            "files": corpus.len(),
+            "corpus_size": corpus_size,
+            "letter_size": letter_size,
+            "distinct": histogram.len(),
+            "tree_depth": dictionary.code().max_length(),
+        });
+
+        println!("{}", summary);
+        return;
+    }
+
+    let summary = format!(
+        "Trained a code for {} bit letters on {} files ({}), {} distinct letters seen\n",
+        letter_size,
+        corpus.len(),
+        options.size(corpus_size),
+        histogram.len()
+    );
+    print_summary(output_path, &summary);
+}
+
+/// Decodes `path` without writing anything, failing on trailing data after the stream.
+fn verify_file(path: &Path, options: &Options) -> io::Result<()> {
+    let mut input = open_input(path)?;
+    decompressor(options).decompress(&mut input, io::sink())?;
+
+    if input.read(&mut [0])? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing data after the stream",
+        ));
+    }
+
+    Ok(())
+}
+
+fn run_test(paths: &[PathBuf], options: &Options) {
+    for path in paths {
+        let result = verify_file(path, options);
+
+        if let Err(e) = &result {
+            fail(exit_code(e));
+        }
+
+        if options.json {
+            let summary = json!({
+                "file": path.to_string_lossy(),
+                "ok": result.is_ok(),
+                "error": result.as_ref().err().map(|e| e.to_string()),
+            });
+
+            println!("{}", summary);
+            continue;
+        }
+
+        match result {
+            Err(e) => eprintln!(
+                "{}: {} ({})",
+                path.display(),
+                paint("corrupt", ERROR, stderr_colored()),
+                e
+            ),
+            Ok(()) if options.quiet() => {}
+            Ok(()) => println!(
+                "{}: {}",
+                path.display(),
+                paint("OK", GOOD, stdout_colored())
+            ),
+        }
+    }
+}
+
+/// Decodes `path` without writing anything, skipping damaged blocks, and returns them.
+fn diagnose_file(path: &Path, options: &Options) -> io::Result<Vec<BadBlock>> {
+    let mut damaged = Vec::new();
+    let mut input = open_input(path)?;
+
+    decompressor(options)
+        .bad_blocks(BadBlocks::Skip, |block| damaged.push(block))
+        .decompress(&mut input, io::sink())?;
+
+    if damaged.last().is_none_or(|block| block.skipped.is_some()) && input.read(&mut [0])? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing data after the stream",
+        ));
+    }
+
+    Ok(damaged)
+}
+
+/// Reports every damaged block of the compressed `paths`, and whether the blocks after them
+/// can still be recovered.
+fn run_doctor(paths: &[PathBuf], options: &Options) {
+    for path in paths {
+        let result = diagnose_file(path, options);
+
+        match &result {
+            Err(e) => fail(exit_code(e)),
+            Ok(damaged) if !damaged.is_empty() => fail(EXIT_CORRUPT),
+            Ok(_) => {}
+        }
+
+        if options.json {
+            let damaged = result.as_ref().ok().map(|damaged| {
+                damaged
+                    .iter()
+                    .map(|block| {
+                        json!({
+                            "stream": block.stream,
+                            "block": block.index,
+                            "offset": block.offset,
+                            "length": block.skipped,
+                            "error": block.error.to_string(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let summary = json!({
+                "file": path.to_string_lossy(),
+                "ok": result.as_ref().is_ok_and(Vec::is_empty),
+                "damaged": damaged,
+                "error": result.as_ref().err().map(|e| e.to_string()),
+            });
+
+            println!("{}", summary);
+            continue;
+        }
+
+        let damaged = match result {
+            Err(e) => {
+                eprintln!(
+                    "{}: {} ({})",
+                    path.display(),
+                    paint("corrupt", ERROR, stderr_colored()),
+                    e
+                );
+                continue;
+            }
+            Ok(damaged) if damaged.is_empty() => {
+                if !options.quiet() {
+                    println!(
+                        "{}: {}",
+                        path.display(),
+                        paint("OK", GOOD, stdout_colored())
+                    );
+                }
+                continue;
+            }
+            Ok(damaged) => damaged,
+        };
+
+        eprintln!(
+            "{}: {} damaged {}",
+            path.display(),
+            paint(damaged.len(), ERROR, stderr_colored()),
+            blocks_noun(damaged.len())
+        );
+
+        for block in &damaged {
+            match block.skipped {
+                Some(len) => eprintln!(
+                    "  block {}, bytes {} to {}: {}",
+                    block.index,
+                    block.offset,
+                    block.offset + len,
+                    block.error
+                ),
+                None => eprintln!(
+                    "  block {}, from byte {}: {}, nothing after it can be read",
+                    block.index, block.offset, block.error
+                ),
+            }
+        }
+
+        let recoverable = damaged.iter().any(|block| block.skipped.is_some());
+
+        if recoverable && !options.quiet() {
+            eprintln!("Decompress with --bad-blocks skip to recover the other blocks");
+        }
+    }
+}
+
+/// Checks everything written to it against `original`, failing at the first difference.
+struct Comparer<R> {
+    original: R,
+    offset: u64,
+    mismatch: Option<u64>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> Write for Comparer<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let differs = || io::Error::other("decoded data differs from the original");
+
+        if self.mismatch.is_some() {
+            return Err(differs());
+        }
+
+        self.buffer.clear();
+        (&mut self.original)
+            .take(buf.len() as u64)
+            .read_to_end(&mut self.buffer)?;
+
+        let same = buf
+            .iter()
+            .zip(&self.buffer)
+            .position(|(decoded, original)| decoded != original)
+            .unwrap_or(self.buffer.len());
+
+        if same < buf.len() {
+            self.mismatch = Some(self.offset + same as u64);
+            return Err(differs());
+        }
+
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes `compressed` and compares it with `original`, returning the offset of the first
+/// byte that differs, if any.
+fn compare_files(original: &Path, compressed: &Path, options: &Options) -> io::Result<Option<u64>> {
+    let mut comparer = Comparer {
+        original: BufReader::with_capacity(32 * 1024, File::open(original)?),
+        offset: 0,
+        mismatch: None,
+        buffer: Vec::new(),
+    };
+
+    let result = open_input(compressed).and_then(|input| {
+        let mut writer = BufWriter::with_capacity(32 * 1024, &mut comparer);
+        decompressor(options).decompress(input, &mut writer)?;
+        writer.flush()
+    });
+
+    match result {
+        _ if comparer.mismatch.is_some() => Ok(comparer.mismatch),
+        Err(e) => Err(e),
+        // The original goes on past the decoded data.
+        Ok(_) if comparer.original.read(&mut [0])? != 0 => Ok(Some(comparer.offset)),
+        Ok(_) => Ok(None),
+    }
+}
+
+fn run_compare(original: &Path, compressed: &Path, options: &Options) {
+    let mismatch = match compare_files(original, compressed, options) {
+        Ok(mismatch) => mismatch,
+        Err(e) => {
+            let context = format_args!(
+                "failed to compare {} with {}",
+                original.display(),
+                compressed.display()
+            );
+            return report(context, &e);
+        }
+    };
+
+    if mismatch.is_some() {
+        fail(EXIT_CORRUPT);
+    }
+
+    if options.json {
+        let summary = json!({
+            "original": original.to_string_lossy(),
+            "compressed": compressed.to_string_lossy(),
+            "identical": mismatch.is_none(),
+            "offset": mismatch,
+        });
+
+        println!("{}", summary);
+        return;
+    }
 
-fn run_compression(input_path: &Path, output_path: &Path, letter_size: u8) {
-    let now = Instant::now();
+    match mismatch {
+        Some(offset) => eprintln!(
+            "{} and {} differ at byte {}",
+            original.display(),
+            compressed.display(),
+            offset
+        ),
+        None if options.quiet() => {}
+        None => println!(
+            "{} and {}: identical",
+            original.display(),
+            compressed.display()
+        ),
+    }
+}
 
-    if let Err(e) = compress_file(input_path, output_path, letter_size) {
-        eprintln!("Error failed to compress: {}", e);
-    } else {
-        let input_size = input_path.metadata().unwrap().len();
-        let output_size = output_path.metadata().unwrap().len();
-        let compression_ratio = input_size as f32 / output_size as f32;
-        let duration = now.elapsed();
+/// The tree stored in the compressed `path`, or with `raw` the tree built for `path` itself.
+fn load_tree(path: &Path, raw: Option<LetterSize>) -> io::Result<(u8, Option<Tree>)> {
+    let Some(letter_size) = raw else {
+        let (format, tree) = read_tree(open_input(path)?)?;
+        return Ok((format.letter_size, tree));
+    };
 
-        println!("-------------------------------------");
-        println!("Compression finished");
-        println!("Input file size: {} bytes", input_size);
-        println!("Output file size: {} bytes", output_size);
-        println!(
-            "Compression ratio: {:.3} ({:.2} %)",
-            compression_ratio,
-            compression_ratio * 100.0
-        );
-        println!("Elapsed: {:.3} (s)", duration.as_secs_f32());
+    let mut input = BufReader::with_capacity(32 * 1024, File::open(path)?);
+    let letter_size = resolve_letter_size(&mut input, letter_size)?;
+    let histogram = count_frequencies(input, letter_size)?;
+
+    Ok((letter_size, Tree::from_frequencies(histogram)))
+}
+
+/// A readable name for `symbol`: the character itself for printable bytes, hex otherwise.
+fn symbol_label(symbol: u32, letter_size: u8) -> String {
+    match char::from_u32(symbol) {
+        Some(c) if letter_size == 8 && c.is_ascii_graphic() => format!("'{}'", c),
+        _ => format!(
+            "0x{:0width$x}",
+            symbol,
+            width = (letter_size as usize).div_ceil(4)
+        ),
+    }
+}
+
+fn run_tree(path: &Path, raw: Option<LetterSize>, format: &str) {
+    let (letter_size, tree) = match load_tree(path, raw) {
+        Ok((letter_size, Some(tree))) => (letter_size, tree),
+        Ok((_, None)) => {
+            fail(EXIT_CORRUPT);
+            return eprintln!("{} {} holds no full letters", error_label(), path.display());
+        }
+        Err(e) => {
+            return report(
+                format_args!("failed to read tree from {}", path.display()),
+                &e,
+            )
+        }
+    };
+
+    let label = |symbol: &u32| symbol_label(*symbol, letter_size);
+    let mut output = BufWriter::new(io::stdout().lock());
+
+    let result = match format {
+        "dot" => tree.write_dot(&mut output, label),
+        _ => {
+            let mut codes = CodeTable::from_tree(&tree)
+                .iter()
+                .map(|(symbol, code)| (*symbol, code))
+                .collect::<Vec<_>>();
+            codes.sort_by_key(|&(symbol, code)| (code.length, symbol));
+
+            codes.iter().try_for_each(|(symbol, code)| {
+                let bits = code
+                    .bits()
+                    .map(|bit| if bit { '1' } else { '0' })
+                    .collect::<String>();
+                writeln!(output, "{:>8} {}", label(symbol), bits)
+            })
+        }
+    };
+
+    if let Err(e) = result.and_then(|()| output.flush()) {
+        report_output(&e);
+    }
+}
+
+/// Width of the longest bar printed by `histogram --chart`.
+const CHART_WIDTH: usize = 50;
+
+fn run_histogram(
+    path: &Path,
+    letter_size: LetterSize,
+    top: Option<usize>,
+    chart: bool,
+    options: &Options,
+) {
+    let counted = File::open(path).and_then(|input| {
+        let mut input = BufReader::with_capacity(32 * 1024, input);
+        let letter_size = resolve_letter_size(&mut input, letter_size)?;
+        Ok((letter_size, count_frequencies(input, letter_size)?))
+    });
+
+    let (letter_size, histogram) = match counted {
+        Ok(counted) => counted,
+        Err(e) => return report(format_args!("failed to count {}", path.display()), &e),
+    };
+
+    let output = &mut BufWriter::new(io::stdout().lock());
+
+    if let Err(e) = write_histogram(output, path, letter_size, histogram, top, chart, options) {
+        report_output(&e);
+    }
+}
+
+fn write_histogram(
+    output: &mut impl Write,
+    path: &Path,
+    letter_size: u8,
+    histogram: Histogram,
+    top: Option<usize>,
+    chart: bool,
+    options: &Options,
+) -> io::Result<()> {
+    let total = histogram.total();
+    let entropy = histogram.entropy();
+
+    let mut rows = histogram.into_iter().collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let distinct = rows.len();
+    rows.truncate(top.unwrap_or(usize::MAX));
+
+    let share = |count: usize| count as f64 / total as f64;
+
+    if options.json {
+        let symbols = rows
+            .iter()
+            .map(|&(symbol, count)| json!({ "symbol": symbol, "count": count, "share": share(count) }))
+            .collect::<Vec<_>>();
+
+        let summary = json!({
+            "file": path.to_string_lossy(),
+            "letter_size": letter_size,
+            "letters": total,
+            "distinct": distinct,
+            "entropy": entropy,
+            "symbols": symbols,
+        });
+
+        return writeln!(output, "{}", summary);
+    }
+
+    if options.quiet() {
+        return Ok(());
+    }
+
+    let largest = rows.first().map_or(0, |&(_, count)| count);
+
+    for &(symbol, count) in &rows {
+        write!(
+            output,
+            "{:>8} {:>12} {:>7.3} %",
+            symbol_label(symbol, letter_size),
+            count,
+            share(count) * 100.0
+        )?;
+
+        if chart {
+            let width = (count as f64 / largest as f64 * CHART_WIDTH as f64).round() as usize;
+            write!(output, " {}", "#".repeat(width.max(1)))?;
+        }
+
+        writeln!(output)?;
+    }
+
+    writeln!(output, "-------------------------------------")?;
+    writeln!(output, "Letters: {} of {} bits", total, letter_size)?;
+    writeln!(output, "Distinct symbols: {}", distinct)?;
+    writeln!(output, "Entropy: {:.3} bits per letter", entropy)?;
+    output.flush()
+}
+
+fn run_cat(paths: &[PathBuf], options: &Options) {
+    let mut output = BufWriter::new(io::stdout().lock());
+
+    for path in paths {
+        let result = open_input(path).and_then(|input| {
+            decompressor(options).decompress(input, &mut output)?;
+            output.flush()
+        });
+
+        if let Err(e) = result {
+            report(format_args!("failed to decompress {}", path.display()), &e);
+        }
     }
 }
 
-fn run_decompression(input_path: &Path, output_path: &Path) {
-    let now = Instant::now();
+/// Decompresses `length` bytes from `offset` of the original data of `path`, decoding only the
+/// blocks holding them.
+fn run_extract(
+    path: &Path,
+    output_path: &Path,
+    offset: u64,
+    length: Option<u64>,
+    options: &Options,
+) {
+    let result = File::open(path).and_then(|input| {
+        let input = BufReader::with_capacity(32 * 1024, input);
+        let mut output = create_output(output_path, options)?;
+        let written = decompressor(options).extract(
+            input,
+            &mut output,
+            offset,
+            length.unwrap_or(u64::MAX),
+        )?;
+        output.flush()?;
+        Ok(written)
+    });
+
+    let written = match result {
+        Ok(written) => written,
+        Err(e) => {
+            return report(
+                format_args!("failed to extract from {}", path.display()),
+                &e,
+            )
+        }
+    };
+
+    if options.quiet() {
+        return;
+    }
 
-    if let Err(e) = decompress_file(input_path, output_path) {
-        eprintln!("Error failed to decompress: {}", e);
+    let summary = if options.json {
+        json!({
+            "input": path.to_string_lossy(),
+            "output": output_path.to_string_lossy(),
+            "offset": offset,
+            "length": written,
+        })
+        .to_string()
     } else {
-        let input_size = input_path.metadata().unwrap().len();
-        let output_size = output_path.metadata().unwrap().len();
-        let duration = now.elapsed();
+        format!(
+            "{}: extracted {} from offset {}",
+            path.display(),
+            options.size(written),
+            offset
+        )
+    };
+
+    print_summary(output_path, &format!("{}\n", summary));
+}
+
+/// Counts the letters of the data written to it, as [`count_frequencies`] would reading it.
+struct LetterCounter {
+    letter_size: u8,
+    pending: u32,
+    bits: u32,
+    histogram: Histogram,
+}
+
+impl LetterCounter {
+    fn new(letter_size: u8) -> Self {
+        LetterCounter {
+            letter_size,
+            pending: 0,
+            bits: 0,
+            histogram: Histogram::new(),
+        }
+    }
+}
+
+impl Write for LetterCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let letter_size = self.letter_size as u32;
+
+        for &byte in buf {
+            self.pending = self.pending << 8 | byte as u32;
+            self.bits += 8;
+
+            while self.bits >= letter_size {
+                self.bits -= letter_size;
+                self.histogram
+                    .add(self.pending >> self.bits & ((1 << letter_size) - 1));
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The tree stored in the compressed `path` and the letters it decodes to.
+fn load_stats(path: &Path, options: &Options) -> io::Result<(u8, Option<Tree>, Histogram)> {
+    let (format, tree) = read_tree(open_input(path)?)?;
+
+    if format.encrypted {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the code table of an encrypted file cannot be shown",
+        ));
+    }
+
+    let mut counter = LetterCounter::new(format.letter_size);
+    decompressor(options).decompress(open_input(path)?, &mut counter)?;
+
+    Ok((format.letter_size, tree, counter.histogram))
+}
+
+/// Prints the code length of every symbol stored in the compressed `path` next to how often it
+/// occurs, and how close the code gets to the entropy of the data.
+fn run_stats(path: &Path, options: &Options) {
+    let (letter_size, tree, histogram) = match load_stats(path, options) {
+        Ok(stats) => stats,
+        Err(e) => return report(format_args!("failed to read {}", path.display()), &e),
+    };
+
+    let output = &mut BufWriter::new(io::stdout().lock());
+
+    if let Err(e) = write_stats(output, path, letter_size, tree, histogram, options) {
+        report_output(&e);
+    }
+}
+
+fn write_stats(
+    output: &mut impl Write,
+    path: &Path,
+    letter_size: u8,
+    tree: Option<Tree>,
+    histogram: Histogram,
+    options: &Options,
+) -> io::Result<()> {
+    let total = histogram.total();
+    let entropy = histogram.entropy();
+    let average = tree
+        .as_ref()
+        .map_or(0.0, |tree| tree.expected_code_length(&histogram));
+
+    let mut rows = tree
+        .iter()
+        .flat_map(Tree::leaves)
+        .map(|(&symbol, length)| (symbol, histogram.get(&symbol), length))
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let share = |count: usize| count as f64 / total as f64;
+
+    if options.json {
+        let symbols = rows
+            .iter()
+            .map(|&(symbol, count, length)| {
+                json!({ "symbol": symbol, "count": count, "share": share(count), "code_length": length })
+            })
+            .collect::<Vec<_>>();
+
+        let summary = json!({
+            "file": path.to_string_lossy(),
+            "letter_size": letter_size,
+            "letters": total,
+            "average_code_length": average,
+            "entropy": entropy,
+            "symbols": symbols,
+        });
+
+        return writeln!(output, "{}", summary);
+    }
+
+    if options.quiet() {
+        return Ok(());
+    }
+
+    for &(symbol, count, length) in &rows {
+        writeln!(
+            output,
+            "{:>8} {:>12} {:>7.3} % {:>3} bits",
+            symbol_label(symbol, letter_size),
+            count,
+            share(count) * 100.0,
+            length
+        )?;
+    }
+
+    writeln!(output, "-------------------------------------")?;
+    writeln!(output, "Letters: {} of {} bits", total, letter_size)?;
+    writeln!(output, "Distinct symbols: {}", rows.len())?;
+    writeln!(
+        output,
+        "Average code length: {:.3} bits per letter",
+        average
+    )?;
+    writeln!(output, "Entropy: {:.3} bits per letter", entropy)?;
+    if average > 0.0 {
+        writeln!(output, "Efficiency: {:.2} %", entropy / average * 100.0)?;
+    }
+    output.flush()
+}
+
+fn list_entries(archive: &Path) -> io::Result<Vec<ArchiveEntry>> {
+    let mut input = BufReader::with_capacity(32 * 1024, File::open(archive)?);
+
+    if input.fill_buf()?.starts_with(CONTAINER_MAGIC) {
+        return Ok(stream_entries(ContainerReader::new(input)?.entries()));
+    }
+
+    let mut reader = ArchiveReader::new(input)?;
+    let mut entries = Vec::new();
+
+    while let Some(entry) = reader.next_entry()? {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn run_list(archive: &Path, options: &Options) {
+    match list_entries(archive) {
+        Err(e) => report(format_args!("failed to read {}", archive.display()), &e),
+        Ok(_) if options.quiet() => {}
+        Ok(entries) if options.json => print_entries_json("list", archive, &entries),
+        Ok(entries) => {
+            println!(
+                "{:>12} {:>12} {:>7}  Name",
+                "Original", "Compressed", "Ratio"
+            );
+
+            for entry in &entries {
+                println!(
+                    "{:>12} {:>12} {:>7.3}  {}",
+                    entry.size,
+                    entry.compressed_size,
+                    entry.ratio(),
+                    entry.path
+                );
+            }
+
+            let size = entries.iter().map(|entry| entry.size).sum::<u64>();
+            let compressed_size = entries
+                .iter()
+                .map(|entry| entry.compressed_size)
+                .sum::<u64>();
+            let ratio = match compressed_size {
+                0 => 0.0,
+                _ => size as f64 / compressed_size as f64,
+            };
+
+            println!(
+                "{:>12} {:>12} {:>7.3}  {} files",
+                size,
+                compressed_size,
+                ratio,
+                entries.len()
+            );
+        }
+    }
+}
+
+struct BenchmarkRow {
+    letter_size: u8,
+    output_size: u64,
+    ratio: f64,
+    compress_speed: f64,
+    decompress_speed: f64,
+}
+
+/// Prints what this build can read and write, to check before exchanging files with another.
+fn run_formats(options: &Options) {
+    let checksums = Checksum::ALL
+        .into_iter()
+        .filter(|checksum| checksum.is_supported())
+        .map(Checksum::name)
+        .collect::<Vec<_>>();
+    let ciphers = match Encryption::is_supported() {
+        true => Encryption::ALL.map(Encryption::name).to_vec(),
+        false => Vec::new(),
+    };
+    let coders = Coder::ALL.map(Coder::name);
+
+    if options.json {
+        let summary = json!({
+            "format_versions": FORMAT_VERSIONS.collect::<Vec<_>>(),
+            "baseline_streams": true,
+            "archive_versions": [ARCHIVE_VERSION],
+            "container_versions": [CONTAINER_VERSION],
+            "volume_versions": [VOLUME_VERSION],
+            "letter_sizes": { "min": LETTER_SIZES.start(), "max": LETTER_SIZES.end() },
+            "checksums": checksums,
+            "coders": coders,
+            "encryption": ciphers,
+            "key_derivation": Encryption::is_supported().then_some("argon2id"),
+            "pre_filters": PRE_FILTERS,
+        });
+
+        println!("{}", summary);
+        return;
+    }
+
+    if options.quiet() {
+        return;
+    }
+
+    println!(
+        "Format versions: {} and baseline streams, writes {}",
+        FORMAT_VERSIONS.end(),
+        FORMAT_VERSION
+    );
+    println!("Archive versions: {}", ARCHIVE_VERSION);
+    println!("Container versions: {}", CONTAINER_VERSION);
+    println!("Volume versions: {}", VOLUME_VERSION);
+    println!(
+        "Letter sizes: {} to {} bits",
+        LETTER_SIZES.start(),
+        LETTER_SIZES.end()
+    );
+    println!("Checksums: {}", checksums.join(", "));
+    println!("Coders: {}", coders.join(", "));
+    match ciphers.is_empty() {
+        true => println!("Encryption: none"),
+        false => println!("Encryption: {} (argon2id key)", ciphers.join(", ")),
+    }
+    println!("Pre-filters: {}", PRE_FILTERS.join(", "));
+}
+
+/// Megabytes of `size` processed per second over `elapsed`.
+fn speed(size: u64, elapsed: Duration) -> f64 {
+    size as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Compresses and decompresses `path` in memory at every letter size.
+fn benchmark_file(path: &Path) -> io::Result<Vec<BenchmarkRow>> {
+    let input = fs::read(path)?;
+    let mut rows = Vec::new();
+
+    for letter_size in LETTER_SIZES {
+        let mut compressed = Vec::new();
+        let report = Compressor::new(letter_size).compress(Cursor::new(&input), &mut compressed)?;
+        let restored = Decompressor::new().decompress(&compressed[..], io::sink())?;
+
+        rows.push(BenchmarkRow {
+            letter_size,
+            output_size: report.output_size,
+            ratio: report.ratio(),
+            compress_speed: speed(report.input_size, report.elapsed),
+            decompress_speed: speed(restored.output_size, restored.elapsed),
+        });
+    }
+
+    Ok(rows)
+}
+
+fn run_benchmark(path: &Path, options: &Options) {
+    match benchmark_file(path) {
+        Err(e) => report(format_args!("failed to benchmark {}", path.display()), &e),
+        Ok(_) if options.quiet() => {}
+        Ok(rows) if options.json => {
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    json!({
+                        "letter_size": row.letter_size,
+                        "output_size": row.output_size,
+                        "ratio": row.ratio,
+                        "compress_speed": row.compress_speed,
+                        "decompress_speed": row.decompress_speed,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            println!(
+                "{}",
+                json!({ "file": path.to_string_lossy(), "sizes": rows })
+            );
+        }
+        Ok(rows) => {
+            println!(
+                "{:>4} {:>12} {:>7} {:>12} {:>12}",
+                "Size", "Output", "Ratio", "Comp MB/s", "Decomp MB/s"
+            );
+
+            for row in &rows {
+                println!(
+                    "{:>4} {:>12} {:>7.3} {:>12.2} {:>12.2}",
+                    row.letter_size,
+                    row.output_size,
+                    row.ratio,
+                    row.compress_speed,
+                    row.decompress_speed
+                );
+            }
+        }
+    }
+}
+
+/// Size of the generated `selftest` inputs, odd so the last letter is partial at most sizes.
+const SELFTEST_SIZE: usize = 16 * 1024 + 3;
+
+/// Inputs `selftest` round-trips: data no letter size compresses, runs of one byte, text, and
+/// the edge cases of a single distinct symbol and no data at all.
+fn selftest_corpora() -> [(&'static str, Vec<u8>); 5] {
+    // xorshift, so the data is the same on every platform without a dependency.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let random = (0..SELFTEST_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+
+    let text = b"The quick brown fox jumps over the lazy dog. "
+        .iter()
+        .copied()
+        .cycle()
+        .take(SELFTEST_SIZE)
+        .collect();
+
+    [
+        ("random", random),
+        ("zeroes", vec![0; SELFTEST_SIZE]),
+        ("text", text),
+        ("single-symbol", vec![b'a'; SELFTEST_SIZE]),
+        ("empty", Vec::new()),
+    ]
+}
+
+/// Compresses `input` with a checksum and checks that it decompresses to the same bytes.
+fn round_trip(input: &[u8], letter_size: u8) -> io::Result<()> {
+    let mut compressed = Vec::new();
+    Compressor::new(letter_size)
+        .checksum(Checksum::Crc32)
+        .compress(Cursor::new(input), &mut compressed)?;
+
+    let mut restored = Vec::new();
+    Decompressor::new().decompress(&compressed[..], &mut restored)?;
+
+    if restored != input {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed data differs from the input",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Round-trips the built-in corpora at every letter size.
+fn run_selftest(options: &Options) {
+    let mut passed = 0;
+    let mut failures = Vec::new();
+
+    for (name, input) in selftest_corpora() {
+        let before = failures.len();
+
+        for letter_size in LETTER_SIZES {
+            match round_trip(&input, letter_size) {
+                Ok(()) => passed += 1,
+                Err(e) => failures.push((name, letter_size, e)),
+            }
+        }
+
+        if options.quiet() || options.json {
+            continue;
+        }
+
+        match &failures[before..] {
+            [] => println!("{}: {}", name, paint("OK", GOOD, stdout_colored())),
+            failed => {
+                for (_, letter_size, e) in failed {
+                    println!(
+                        "{}, {} bits: {} ({})",
+                        name,
+                        letter_size,
+                        paint("failed", ERROR, stdout_colored()),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        fail(EXIT_CORRUPT);
+    }
 
+    if options.json {
+        let failed = failures
+            .iter()
+            .map(|(name, letter_size, e)| {
+                json!({ "corpus": name, "letter_size": letter_size, "error": e.to_string() })
+            })
+            .collect::<Vec<_>>();
+
+        println!("{}", json!({ "passed": passed, "failed": failed }));
+    } else if !options.quiet() {
         println!("-------------------------------------");
-        println!("Decompression finished");
-        println!("Input file size: {} bytes", input_size);
-        println!("Output file size: {} bytes", output_size);
-        println!("Elapsed: {:.3} (s)", duration.as_secs_f32());
+        println!("{} round trips passed, {} failed", passed, failures.len());
     }
 }
 
-fn main() {
-    let mut command = command!()
-        .subcommand(
-            Command::new("compress")
-                .arg(
-                    arg!(--input <FILE> "Input file")
-                        .required(true)
-                        .value_parser(value_parser!(PathBuf)),
-                )
-                .arg(
-                    arg!(--output <FILE> "Output file")
-                        .required(true)
-                        .value_parser(value_parser!(PathBuf)),
-                )
-                .arg(
-                    arg!(--size <SIZE> "Letter size")
-                        .required(true)
-                        .value_parser(value_parser!(u8)),
-                ),
-        )
-        .subcommand(
-            Command::new("decompress")
-                .arg(
-                    arg!(--input <FILE> "Input file")
-                        .required(true)
-                        .value_parser(value_parser!(PathBuf)),
-                )
-                .arg(
-                    arg!(--output <FILE> "Output file")
-                        .required(true)
-                        .value_parser(value_parser!(PathBuf)),
-                ),
-        );
+struct Analysis {
+    letter_size: u8,
+    entropy: f64,
+    best_ratio: f64,
+}
+
+fn analyze_file(path: &Path) -> io::Result<Vec<Analysis>> {
+    let mut input = BufReader::with_capacity(32 * 1024, File::open(path)?);
+    let mut rows = Vec::new();
+
+    for letter_size in AUTO_LETTER_SIZES {
+        input.rewind()?;
+        let histogram = count_frequencies(&mut input, letter_size)?;
+        let entropy = histogram.entropy();
+
+        rows.push(Analysis {
+            letter_size,
+            entropy,
+            best_ratio: match entropy {
+                0.0 => f64::INFINITY,
+                _ => letter_size as f64 / entropy,
+            },
+        });
+    }
+
+    Ok(rows)
+}
+
+/// Entropy per byte of the 8 bit analysis, if one was made.
+fn byte_entropy(rows: &[Analysis]) -> Option<f64> {
+    rows.iter()
+        .find(|row| row.letter_size == 8)
+        .map(|row| row.entropy)
+}
 
-    let matches = command.clone().get_matches();
+fn run_analyze(path: &Path, options: &Options) {
+    match analyze_file(path) {
+        Err(e) => report(format_args!("failed to analyze {}", path.display()), &e),
+        Ok(_) if options.quiet() => {}
+        Ok(rows) if options.json => {
+            let sizes = rows
+                .iter()
+                .map(|row| {
+                    json!({
+                        "letter_size": row.letter_size,
+                        "entropy": row.entropy,
+                        "best_ratio": row.best_ratio,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let compressed =
+                byte_entropy(&rows).is_some_and(|entropy| entropy >= COMPRESSED_ENTROPY);
 
-    match matches.subcommand() {
-        Some(("compress", matches)) => {
-            let input_path = matches.get_one::<PathBuf>("input").unwrap();
-            let output_path = matches.get_one::<PathBuf>("output").unwrap();
-            let letter_size = matches.get_one::<u8>("size").unwrap();
+            let summary = json!({
+                "file": path.to_string_lossy(),
+                "sizes": sizes,
+                "looks_compressed": compressed,
+            });
 
-            run_compression(input_path, output_path, *letter_size);
+            println!("{}", summary);
         }
-        Some(("decompress", matches)) => {
-            let input_path = matches.get_one::<PathBuf>("input").unwrap();
-            let output_path = matches.get_one::<PathBuf>("output").unwrap();
+        Ok(rows) => {
+            println!(
+                "{:>4} {:>14} {:>14} {:>10}",
+                "Size", "Bits/letter", "Bits/byte", "Best ratio"
+            );
+
+            for row in &rows {
+                println!(
+                    "{:>4} {:>14.4} {:>14.4} {:>10.3}",
+                    row.letter_size,
+                    row.entropy,
+                    row.entropy * 8.0 / row.letter_size as f64,
+                    row.best_ratio
+                );
+            }
 
-            run_decompression(input_path, output_path);
+            if let Some(entropy) =
+                byte_entropy(&rows).filter(|&entropy| entropy >= COMPRESSED_ENTROPY)
+            {
+                println!(
+                    "{}: {:.3} bits per byte, the data looks already compressed",
+                    paint("Warning", WARNING, stdout_colored()),
+                    entropy
+                );
+            }
         }
-        _ => {
-            command.print_help().unwrap();
+    }
+}
+
+/// Flags accepted before or after any subcommand.
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Global options")]
+struct GlobalArgs {
+    /// Print nothing but errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Print the summary as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    /// Color the output
+    #[arg(long, global = true, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Print sizes in bytes instead of KiB, MiB and GiB
+    #[arg(long, global = true)]
+    bytes: bool,
+    /// Write to stdout, same as --output -
+    #[arg(short = 'c', long, global = true)]
+    stdout: bool,
+    /// Overwrite existing output files without asking
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "HUFFMAN_FORCE",
+        value_parser = FalseyValueParser::new(),
+        overrides_with = "no_clobber"
+    )]
+    force: bool,
+    /// Never overwrite existing output files, without asking
+    #[arg(long, global = true, overrides_with = "force")]
+    no_clobber: bool,
+    #[command(flatten)]
+    password: PasswordArgs,
+    /// Fail instead of allocating more than SIZE for the tree of an untrusted input, e.g. 1MiB
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_byte_size)]
+    max_memory: Option<usize>,
+    /// Extension of compressed files, named after their inputs plus it [default: huff]
+    #[arg(long, global = true, value_parser = parse_suffix)]
+    suffix: Option<String>,
+    /// Read defaults from FILE [default: ~/.config/huffman/config.toml]
+    #[arg(long, global = true, env = "HUFFMAN_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Delete input files after they were processed successfully
+    #[arg(long, global = true, overrides_with = "keep")]
+    rm: bool,
+    /// Keep input files (default)
+    #[arg(long, global = true, overrides_with = "rm")]
+    keep: bool,
+    /// Leave out matching files and directories, may be repeated
+    #[arg(short = 'x', long, global = true, value_name = "GLOB")]
+    exclude: Vec<Pattern>,
+    /// Process what links point to when walking directories
+    #[arg(long, global = true, overrides_with_all = ["skip_symlinks", "store_symlinks"])]
+    follow_symlinks: bool,
+    /// Leave out links when walking directories (default)
+    #[arg(long, global = true, overrides_with_all = ["follow_symlinks", "store_symlinks"])]
+    skip_symlinks: bool,
+    /// Recreate links at the output when walking directories
+    #[arg(long, global = true, overrides_with_all = ["follow_symlinks", "skip_symlinks"])]
+    store_symlinks: bool,
+    /// Print more detail and the time each phase took, repeat for their shares
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+}
+
+#[derive(Debug, Args)]
+struct PasswordArgs {
+    /// Encrypt outputs and decrypt inputs with a password asked for on the terminal
+    #[arg(long, global = true)]
+    password: bool,
+    /// Read the password from the first line of FILE
+    #[arg(long, global = true, value_name = "FILE", conflicts_with = "password")]
+    password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct LetterSizeArg {
+    /// Letter size in bits from 2 to 24, or auto to pick the smallest output [default: 8]
+    #[arg(short, long, env = "HUFFMAN_SIZE", value_parser = parse_letter_size)]
+    size: Option<LetterSize>,
+}
+
+/// Inputs and outputs of `compress` and `decompress`.
+#[derive(Debug, Args)]
+struct PathArgs {
+    /// Process every file under the input directory
+    #[arg(short, long)]
+    recursive: bool,
+    /// Print what would be written without writing anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Give outputs the modification time of their inputs
+    #[arg(short, long)]
+    preserve_times: bool,
+    /// Give outputs the permissions, and as root the owner, of their inputs, and store the
+    /// permissions in compressed files
+    #[arg(long)]
+    preserve_perms: bool,
+    #[command(flatten)]
+    inputs: InputArgs,
+    #[command(flatten)]
+    outputs: OutputArgs,
+    /// Append the name, sizes, ratio and seconds of every file written to a CSV file
+    #[arg(long, value_name = "FILE")]
+    stats_csv: Option<PathBuf>,
+    /// Code table made by `train` to compress with instead of one per file, or to decompress
+    /// such files with; it sets the letter size
+    #[arg(long, value_name = "FILE")]
+    dict: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = true)]
+struct InputArgs {
+    /// Input file, glob pattern or directory (with -r), or - for stdin
+    #[arg(short, long, value_name = "FILE")]
+    input: Vec<PathBuf>,
+    /// Input files, same as --input; two without -i or -o are INPUT OUTPUT unless OUTPUT exists
+    #[arg(value_name = "FILES")]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+struct OutputArgs {
+    /// Output file, directory for several inputs, or - for stdout [default: named after the input]
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Directory to write outputs into, named and mirrored as they would be next to their inputs
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DecompressArgs {
+    #[command(flatten)]
+    paths: PathArgs,
+    /// What to do with a block that fails its checksum or does not decode: fail, stop the
+    /// output before it, or skip it, writing zeros in its place; outputs with damaged blocks
+    /// are kept, but still fail [default: fail]
+    #[arg(long, value_name = "ACTION", value_parser = bad_blocks_parser())]
+    bad_blocks: Option<BadBlocks>,
+}
+
+#[derive(Debug, Args)]
+struct CompressArgs {
+    #[command(flatten)]
+    paths: PathArgs,
+    #[command(flatten)]
+    letter_size: LetterSizeArg,
+    /// Also compress files that look compressed already when processing several
+    #[arg(short, long)]
+    all: bool,
+    /// Threads to compress with, 1 to stay on one thread [default: number of cores]
+    #[arg(
+        short = 'T',
+        long,
+        env = "HUFFMAN_THREADS",
+        value_name = "N",
+        value_parser = value_parser!(u16).range(1..)
+    )]
+    threads: Option<u16>,
+    /// Continue outputs an interrupted run left unfinished after their last good block
+    #[arg(long, conflicts_with_all = ["split_size", "self_extracting"])]
+    resume: bool,
+    /// Preset from 1 (fastest) to 9 (smallest), overridden by --size and --block-size
+    #[arg(short, long, value_parser = value_parser!(u8).range(1..=9))]
+    level: Option<u8>,
+    /// Bytes per block, compressed in parallel and decompressed one at a time, e.g. 1MiB
+    /// [default: 64Ki letters]
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    block_size: Option<usize>,
+    /// Build a code for every block from its own letters instead of one for the whole input
+    #[arg(long)]
+    block_trees: bool,
+    /// Adapt the code to the letters as they arrive, in one pass and without storing it,
+    /// so stdin is compressed as it streams in
+    #[arg(long, conflicts_with = "block_trees")]
+    adaptive: bool,
+    /// Replace repeated strings with references to earlier ones, as gzip does, before coding;
+    /// letters are 8 bits
+    #[arg(long, conflicts_with_all = ["adaptive", "dict"])]
+    lz77: bool,
+    /// Code every letter with a code for the letter before it, smaller for text where letters
+    /// follow each other in set ways; headers grow with the number of codes
+    #[arg(long, conflicts_with_all = ["adaptive", "lz77", "block_trees", "dict"])]
+    order1: bool,
+    /// End the data with an end-of-stream letter instead of relying on stored lengths alone
+    #[arg(long, conflicts_with_all = ["adaptive", "lz77", "order1", "rle", "bwt", "dict"])]
+    eos: bool,
+    /// Pack the bits of coded blocks from the lowest bit of every byte up, as DEFLATE and
+    /// many hardware decoders expect
+    #[arg(long)]
+    lsb_first: bool,
+    /// Run-length code the input first, for data with long runs of the same byte
+    #[arg(long)]
+    rle: bool,
+    /// Sort blocks with the Burrows-Wheeler transform and move-to-front code them first, as
+    /// bzip2 does; much smaller text, slower compression
+    #[arg(long)]
+    bwt: bool,
+    /// How counted letters are coded; arithmetic comes closer to their entropy, for letters of
+    /// up to 16 bits [default: huffman]
+    #[arg(long, value_name = "CODER", value_parser = coder_parser())]
+    coder: Option<Coder>,
+    /// Keep codes at most N bits long, so skewed input cannot produce very long ones
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = value_parser!(u32).range(1..=32),
+        conflicts_with = "adaptive"
+    )]
+    max_code_length: Option<u32>,
+    /// Leave the modification time of inputs out of compressed files
+    #[arg(long)]
+    no_mtime: bool,
+    /// Hash of the input to store and check on decompression [default: crc32]
+    #[arg(long, value_name = "ALGORITHM", value_parser = checksum_parser())]
+    checksum: Option<Checksum>,
+    /// Cipher to encrypt with when a password is given [default: chacha20-poly1305]
+    #[arg(long, value_name = "CIPHER", value_parser = cipher_parser())]
+    cipher: Option<Encryption>,
+    /// Write gzip streams that gzip and other tools read, with a .gz suffix unless --suffix is
+    /// given; they take none of the options that shape our own format [default: huffman]
+    #[arg(long, value_name = "FORMAT", value_parser = format_parser())]
+    format: Option<OutputFormat>,
+    /// Split each output into volumes of at most SIZE bytes, such as 100MiB, named
+    /// file.huff.001, file.huff.002 and on; decompress the first one to read them all
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    split_size: Option<usize>,
+    /// Write outputs as programs that extract themselves when run, with a .sfx suffix (.exe
+    /// on Windows) unless --suffix is given; they need no install of this tool to read, but
+    /// start with a whole copy of this program, so each is its size larger than the data
+    #[arg(long, conflicts_with_all = ["split_size", "dict"])]
+    self_extracting: bool,
+    /// Start self-extracting outputs with FILE, a build of this tool for the platform they
+    /// will run on, instead of this program
+    #[arg(long, value_name = "FILE", requires = "self_extracting")]
+    stub: Option<PathBuf>,
+}
+
+fn checksum_parser() -> impl TypedValueParser<Value = Checksum> {
+    PossibleValuesParser::new(Checksum::ALL.map(Checksum::name))
+        .map(|name| name.parse::<Checksum>().unwrap())
+}
+
+fn coder_parser() -> impl TypedValueParser<Value = Coder> {
+    PossibleValuesParser::new(Coder::ALL.map(Coder::name))
+        .map(|name| name.parse::<Coder>().unwrap())
+}
+
+fn format_parser() -> impl TypedValueParser<Value = OutputFormat> {
+    PossibleValuesParser::new(["huffman", "gzip"]).map(|name| match name.as_str() {
+        "gzip" => OutputFormat::Gzip,
+        _ => OutputFormat::Huffman,
+    })
+}
+
+fn bad_blocks_parser() -> impl TypedValueParser<Value = BadBlocks> {
+    PossibleValuesParser::new(["fail", "stop", "skip"]).map(|name| match name.as_str() {
+        "stop" => BadBlocks::Stop,
+        "skip" => BadBlocks::Skip,
+        _ => BadBlocks::Fail,
+    })
+}
+
+fn cipher_parser() -> impl TypedValueParser<Value = Encryption> {
+    PossibleValuesParser::new(Encryption::ALL.map(Encryption::name))
+        .map(|name| name.parse::<Encryption>().unwrap())
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Compress files
+    #[command(visible_alias = "c")]
+    Compress(CompressArgs),
+    /// Decompress files
+    #[command(visible_aliases = ["d", "x"])]
+    Decompress(DecompressArgs),
+    /// Compress files created or modified under a directory until interrupted
+    Watch {
+        /// Directory to watch for new and modified files
+        dir: PathBuf,
+        /// Directory to write compressed files to
+        #[arg(long, value_name = "DIR")]
+        output_dir: PathBuf,
+        #[command(flatten)]
+        letter_size: LetterSizeArg,
+    },
+    /// Compress files into an archive
+    Pack {
+        /// Archive to create
+        archive: PathBuf,
+        /// Files or directories to add
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        #[command(flatten)]
+        letter_size: LetterSizeArg,
+    },
+    /// Extract an archive
+    Unpack {
+        /// Archive to extract
+        archive: PathBuf,
+        /// Output directory
+        dir: PathBuf,
+    },
+    /// Compress files into named streams of one container, each read back on its own
+    Bundle {
+        /// Container to create
+        container: PathBuf,
+        /// Streams to add, each named and compressed from a file
+        #[arg(required = true, value_name = "NAME=FILE", value_parser = parse_named_stream)]
+        streams: Vec<(String, PathBuf)>,
+        #[command(flatten)]
+        letter_size: LetterSizeArg,
+    },
+    /// Decompress one named stream of a container
+    Unbundle {
+        /// Container to read
+        container: PathBuf,
+        /// Stream to decompress
+        name: String,
+        /// File to write the stream to [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show the header of a compressed file
+    Info {
+        /// Compressed file to inspect
+        file: PathBuf,
+    },
+    /// Check that compressed files decode
+    Test {
+        /// Compressed files to verify
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Find the damaged blocks of compressed files and the bytes of the original they held
+    Doctor {
+        /// Compressed files to examine
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Check that a compressed file decodes to an original
+    Compare {
+        /// Original file
+        original: PathBuf,
+        /// Compressed file to check against it, or - for stdin
+        compressed: PathBuf,
+    },
+    /// Print the code tree of a file
+    Tree {
+        /// Compressed file, or with --raw any file
+        file: PathBuf,
+        /// Build the tree for FILE itself instead of reading its header
+        #[arg(long)]
+        raw: bool,
+        /// Letter size in bits from 2 to 24, or auto to pick the smallest output [default: 8]
+        #[arg(short, long, env = "HUFFMAN_SIZE", requires = "raw", value_parser = parse_letter_size)]
+        size: Option<LetterSize>,
+        /// Print the tree as a Graphviz graph or as a code list
+        #[arg(long, value_parser = ["dot", "codes"], default_value = "dot")]
+        format: String,
+    },
+    /// Count the letters of a file
+    Histogram {
+        /// File to count
+        file: PathBuf,
+        #[command(flatten)]
+        letter_size: LetterSizeArg,
+        /// Show only the N most frequent symbols
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+        /// Draw a bar for every symbol
+        #[arg(long)]
+        chart: bool,
+    },
+    /// Build a code table from a corpus of similar files, to compress each with --dict
+    Train {
+        /// Files to count letters in
+        #[arg(required = true)]
+        corpus: Vec<PathBuf>,
+        /// Letter size in bits from 2 to 16
+        #[arg(
+            short,
+            long,
+            default_value_t = DEFAULT_LETTER_SIZE,
+            value_parser = value_parser!(u8).range(2..=MAX_DICTIONARY_LETTER_SIZE as i64)
+        )]
+        size: u8,
+        /// Table file to write, or - for stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Show the code lengths of a compressed file against its letter counts
+    Stats {
+        /// Compressed file whose code table to show
+        file: PathBuf,
+    },
+    /// Decompress a byte range of a file, decoding only the blocks holding it
+    Extract {
+        /// Compressed file to read from
+        file: PathBuf,
+        /// First byte of the original data to extract
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        offset: u64,
+        /// Bytes to extract [default: up to the end]
+        #[arg(long, value_name = "M")]
+        length: Option<u64>,
+        /// File to write to, or - for stdout
+        #[arg(short, long, value_name = "FILE", default_value = "-")]
+        output: PathBuf,
+    },
+    /// Decompress files to stdout
+    Cat {
+        /// Compressed files to print, or - for stdin
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// List the files in an archive or the streams of a container
+    List {
+        /// Archive or container to list
+        archive: PathBuf,
+    },
+    /// Time compression and decompression of a file at every letter size
+    Benchmark {
+        /// File to benchmark
+        file: PathBuf,
+    },
+    /// Estimate how well a file compresses
+    Analyze {
+        /// File to analyze
+        file: PathBuf,
+    },
+    /// Print shell completions
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Print a man page
+    Manpage,
+    /// List the format versions, letter sizes and checksums this build supports
+    Formats,
+    /// Round-trip built-in data at every letter size to check this build works
+    Selftest,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+/// The inputs and destination given by `-i`, `-o`, `-c`, `--output-dir` and the positional
+/// paths. Two positional paths on their own are an input and an output, as before several
+/// inputs were accepted, unless the second is an existing file, which is then compressed
+/// rather than overwritten.
+fn paths<'a>(args: &'a PathArgs, options: &Options) -> (Vec<&'a PathBuf>, Destination<'a>) {
+    let InputArgs { input, files } = &args.inputs;
+    let output_path = match options.stdout {
+        true => Some(Path::new("-")),
+        false => args.outputs.output.as_deref(),
+    };
+    let output_dir = args.outputs.output_dir.as_deref();
+
+    if let ([input_path, output_path], None, None, true) =
+        (files.as_slice(), output_path, output_dir, input.is_empty())
+    {
+        if !output_path.is_file() {
+            return (vec![input_path], Destination::Output(output_path));
+        }
+    }
+
+    let destination = match (output_path, output_dir) {
+        (Some(output_path), _) => Destination::Output(output_path),
+        (None, Some(output_dir)) => Destination::Directory(output_dir),
+        (None, None) => Destination::Beside,
+    };
+
+    (input.iter().chain(files).collect(), destination)
+}
+
+fn main() -> ExitCode {
+    // Run as a self-extracting output, this binary only extracts what it carries.
+    if let Some((exe, offset, end, name)) = self_extracting_payload() {
+        return run_self_extraction(exe, offset, end, name);
+    }
+
+    // Spans are off unless enabled through RUST_LOG, e.g. RUST_LOG=huffman_code=debug.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // Help and version requests end up here too.
+            let _ = e.print();
+            return ExitCode::from(if e.use_stderr() { EXIT_USAGE } else { 0 });
+        }
+    };
+    set_color(cli.global.color);
+    let mut options = Options::from_args(&cli.global);
+
+    let config = match Config::load(cli.global.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{} failed to read configuration: {}", error_label(), e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    options.exclude.extend(config.exclude.iter().cloned());
+
+    if let (None, Some(suffix)) = (&cli.global.suffix, &config.suffix) {
+        options.suffix = suffix.clone();
+    }
+
+    // New files are encrypted with the password, so it is typed twice to rule out typos.
+    let confirm = matches!(
+        cli.command,
+        Some(Commands::Compress(_) | Commands::Watch { .. })
+    );
+
+    options.password = match read_password(&cli.global.password, confirm) {
+        Ok(password) => password,
+        Err(e) => {
+            eprintln!("{} failed to read password: {}", error_label(), e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let Some(command) = cli.command else {
+        Cli::command().print_help().unwrap();
+        return ExitCode::from(EXIT_USAGE);
+    };
+
+    match command {
+        Commands::Compress(args) => {
+            options.dictionary = match read_dictionary(&args.paths) {
+                Ok(dictionary) => dictionary,
+                Err(code) => return code,
+            };
+
+            let level = args.level.map(|level| &LEVELS[level as usize - 1]);
+            let all = args.all || level.is_some_and(|level| !level.skip_compressed);
+            let letter_size = match (&options.dictionary, level, args.letter_size.size) {
+                (Some(dictionary), _, _) => LetterSize::Bits(dictionary.letter_size()),
+                (None, _, None) if args.lz77 => LetterSize::Bits(8),
+                (None, Some(level), None) => LetterSize::Level(level),
+                (None, _, size) => config.letter_size(size),
+            };
+
+            options.dry_run = args.paths.dry_run;
+            options.preserve_times = args.paths.preserve_times;
+            options.preserve_perms = args.paths.preserve_perms;
+            options.threads = args
+                .threads
+                .or(config.threads)
+                .map(|threads| threads as usize);
+            options.block_size = args.block_size.or(level.and_then(|level| level.block_size));
+            options.block_trees = args.block_trees;
+            options.adaptive = args.adaptive;
+            options.lz77 = args.lz77;
+            options.order1 = args.order1;
+            options.eos = args.eos;
+            options.lsb_first = args.lsb_first;
+            options.rle = args.rle;
+            options.bwt = args.bwt;
+            options.max_code_length = args.max_code_length;
+            options.no_mtime = args.no_mtime;
+            options.checksum = args.checksum.or(config.checksum).unwrap_or_default();
+            options.encryption = args.cipher.unwrap_or_default();
+            options.coder = args.coder.unwrap_or_default();
+            options.gzip = args.format == Some(OutputFormat::Gzip);
+            options.split_size = args.split_size.map(|size| size as u64);
+            options.self_extracting = args.self_extracting;
+            options.resume = args.resume;
+            options.stub = args.stub.clone();
+
+            if options.gzip {
+                let conflicts = [
+                    ("--size", args.letter_size.size.is_some()),
+                    ("--block-trees", args.block_trees),
+                    ("--adaptive", args.adaptive),
+                    ("--lz77", args.lz77),
+                    ("--order1", args.order1),
+                    ("--eos", args.eos),
+                    ("--lsb-first", args.lsb_first),
+                    ("--split-size", args.split_size.is_some()),
+                    ("--self-extracting", args.self_extracting),
+                    ("--rle", args.rle),
+                    ("--bwt", args.bwt),
+                    ("--coder", args.coder.is_some()),
+                    ("--max-code-length", args.max_code_length.is_some()),
+                    ("--checksum", args.checksum.is_some()),
+                    ("--cipher", args.cipher.is_some()),
+                    ("--password", options.password.is_some()),
+                    ("--dict", options.dictionary.is_some()),
+                    ("--resume", args.resume),
+                ];
+
+                if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+                    eprintln!(
+                        "{} --format gzip cannot be used with {}",
+                        error_label(),
+                        name
+                    );
+                    return ExitCode::from(EXIT_USAGE);
+                }
+
+                if cli.global.suffix.is_none() && config.suffix.is_none() {
+                    options.suffix = GZIP_SUFFIX.to_string();
+                }
+            }
+
+            if options.self_extracting && cli.global.suffix.is_none() && config.suffix.is_none() {
+                options.suffix = SFX_SUFFIX.to_string();
+            }
+
+            for_each_input(
+                &args.paths,
+                &options,
+                &|path: &Path| compressed_name(path, &options.suffix),
+                |file| !all && skip_compressed(file, &options),
+                |input_path, output_path| {
+                    run_compression(input_path, output_path, letter_size, &options)
+                },
+            );
+        }
+        Commands::Decompress(DecompressArgs {
+            paths: args,
+            bad_blocks,
+        }) => {
+            options.dictionary = match read_dictionary(&args) {
+                Ok(dictionary) => dictionary,
+                Err(code) => return code,
+            };
+            options.dry_run = args.dry_run;
+            options.preserve_times = args.preserve_times;
+            options.preserve_perms = args.preserve_perms;
+            options.bad_blocks = bad_blocks.unwrap_or_default();
+
+            for_each_input(
+                &args,
+                &options,
+                &|path: &Path| decompressed_name(path, &options.suffix),
+                |_| false,
+                |input_path, output_path| run_decompression(input_path, output_path, &options),
+            );
+        }
+        Commands::Watch {
+            dir,
+            output_dir,
+            letter_size,
+        } => {
+            let letter_size = config.letter_size(letter_size.size);
+            // Modified files replace their earlier outputs.
+            options.force = true;
+            options.threads = config.threads.map(|threads| threads as usize);
+            options.checksum = config.checksum.unwrap_or_default();
+
+            run_watch(&dir, &output_dir, letter_size, &options);
+        }
+        Commands::Pack {
+            archive,
+            files,
+            letter_size,
+        } => {
+            let letter_size = config.letter_size(letter_size.size);
+
+            run_pack(&archive, &files, letter_size, &options);
+        }
+        Commands::Unpack { archive, dir } => run_unpack(&archive, &dir, &options),
+        Commands::Bundle {
+            container,
+            streams,
+            letter_size,
+        } => {
+            let letter_size = config.letter_size(letter_size.size);
+
+            run_bundle(&container, &streams, letter_size, &options);
+        }
+        Commands::Unbundle {
+            container,
+            name,
+            output,
+        } => run_unbundle(&container, &name, output.as_deref(), &options),
+        Commands::Info { file } => run_info(&file, &options),
+        Commands::Test { files } => run_test(&files, &options),
+        Commands::Doctor { files } => run_doctor(&files, &options),
+        Commands::Compare {
+            original,
+            compressed,
+        } => run_compare(&original, &compressed, &options),
+        Commands::Tree {
+            file,
+            raw,
+            size,
+            format,
+        } => {
+            let raw = raw.then(|| config.letter_size(size));
+
+            run_tree(&file, raw, &format);
+        }
+        Commands::Histogram {
+            file,
+            letter_size,
+            top,
+            chart,
+        } => {
+            let letter_size = config.letter_size(letter_size.size);
+
+            run_histogram(&file, letter_size, top, chart, &options);
+        }
+        Commands::Stats { file } => run_stats(&file, &options),
+        Commands::Extract {
+            file,
+            offset,
+            length,
+            output,
+        } => run_extract(&file, &output, offset, length, &options),
+        Commands::Train {
+            corpus,
+            size,
+            output,
+        } => run_train(&corpus, size, &output, &options),
+        Commands::Cat { files } => run_cat(&files, &options),
+        Commands::List { archive } => run_list(&archive, &options),
+        Commands::Benchmark { file } => run_benchmark(&file, &options),
+        Commands::Analyze { file } => run_analyze(&file, &options),
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+
+            clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        }
+        Commands::Formats => run_formats(&options),
+        Commands::Selftest => run_selftest(&options),
+        Commands::Manpage => {
+            if let Err(e) = clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()) {
+                report("failed to write the man page", &e);
+            }
         }
     }
+
+    ExitCode::from(EXIT_STATUS.load(Ordering::Relaxed))
 }
@@ -0,0 +1,209 @@
+use std::io::Read;
+
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::context;
+use crate::filter::Filters;
+use crate::histogram::{self, Histogram};
+use crate::progress::Progress;
+
+#[cfg(feature = "parallel")]
+const BATCH_CHUNKS: usize = 64;
+
+#[cfg(feature = "parallel")]
+#[derive(Clone, Default)]
+pub(crate) enum Threads {
+    #[default]
+    Global,
+    Count(usize),
+    Pool(Arc<ThreadPool>),
+}
+
+#[cfg(feature = "parallel")]
+impl Threads {
+    pub(crate) fn resolve(&self) -> Result<Pool, std::io::Error> {
+        let threads = match self {
+            Threads::Global => None,
+            Threads::Count(0 | 1) => return Ok(Pool::Sequential),
+            Threads::Count(count) => ThreadPoolBuilder::new()
+                .num_threads(*count)
+                .build()
+                .map(|pool| Some(Arc::new(pool)))
+                .map_err(std::io::Error::other)?,
+            Threads::Pool(pool) => Some(pool.clone()),
+        };
+
+        Ok(Pool::Parallel { threads })
+    }
+}
+
+pub(crate) enum Pool {
+    Sequential,
+    /// Works on several blocks at once on `threads`, or on rayon's global pool.
+    #[cfg(feature = "parallel")]
+    Parallel {
+        threads: Option<Arc<ThreadPool>>,
+    },
+}
+
+/// Reads up to `size` bytes, fewer only at the end of `reader`.
+fn read_block<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut block = Vec::with_capacity(size);
+    reader.by_ref().take(size as u64).read_to_end(&mut block)?;
+
+    Ok(block)
+}
+
+impl Pool {
+    pub(crate) fn count<R: Read>(
+        &self,
+        reader: R,
+        letter_size: u8,
+        block_size: usize,
+        filters: Filters,
+        order1: bool,
+        progress: &mut Progress,
+    ) -> Result<Histogram, std::io::Error> {
+        let mut histogram = Histogram::new();
+
+        self.map_blocks(
+            reader,
+            block_size,
+            progress,
+            |_, block| {
+                let block = filters.apply(block);
+
+                match order1 {
+                    true => context::count_pairs(&block, letter_size),
+                    false => histogram::count(&block[..], letter_size, &mut Progress::disabled()),
+                }
+            },
+            |_, partial| {
+                histogram.merge(partial);
+                Ok(())
+            },
+        )?;
+
+        Ok(histogram)
+    }
+
+    /// Reads `reader` in blocks of `block_size` bytes, runs `map` on every block with its
+    /// number and hands the results to `sink` in input order, along with the block.
+    pub(crate) fn map_blocks<R, T, M, S>(
+        &self,
+        mut reader: R,
+        block_size: usize,
+        progress: &mut Progress,
+        map: M,
+        mut sink: S,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        T: Send,
+        M: Fn(usize, &[u8]) -> Result<T, std::io::Error> + Sync,
+        S: FnMut(&[u8], T) -> Result<(), std::io::Error>,
+    {
+        match self {
+            Pool::Sequential => {
+                for index in 0.. {
+                    let block = read_block(&mut reader, block_size)?;
+
+                    if block.is_empty() {
+                        break;
+                    }
+
+                    let result = map(index, &block)?;
+                    sink(&block, result)?;
+                    progress.advance(block.len() as u64 * 8)?;
+
+                    if block.len() < block_size {
+                        break;
+                    }
+                }
+
+                Ok(())
+            }
+            #[cfg(feature = "parallel")]
+            Pool::Parallel { .. } => self.map_batches(reader, block_size, progress, map, sink),
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+fn read_batch<R: Read>(reader: &mut R, block_size: usize) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    let mut batch = Vec::with_capacity(BATCH_CHUNKS);
+
+    while batch.len() < BATCH_CHUNKS {
+        let block = read_block(reader, block_size)?;
+        let last = block.len() < block_size;
+
+        if !block.is_empty() {
+            batch.push(block);
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(batch)
+}
+
+#[cfg(feature = "parallel")]
+impl Pool {
+    fn install<T: Send, F: FnOnce() -> T + Send>(&self, op: F) -> T {
+        match self {
+            Pool::Parallel {
+                threads: Some(pool),
+            } => pool.install(op),
+            _ => op(),
+        }
+    }
+
+    fn map_batches<R, T, M, S>(
+        &self,
+        mut reader: R,
+        block_size: usize,
+        progress: &mut Progress,
+        map: M,
+        mut sink: S,
+    ) -> Result<(), std::io::Error>
+    where
+        R: Read,
+        T: Send,
+        M: Fn(usize, &[u8]) -> Result<T, std::io::Error> + Sync,
+        S: FnMut(&[u8], T) -> Result<(), std::io::Error>,
+    {
+        let mut first = 0;
+
+        loop {
+            let batch = read_batch(&mut reader, block_size)?;
+            let done = batch.len() < BATCH_CHUNKS || batch[BATCH_CHUNKS - 1].len() < block_size;
+
+            let results = self.install(|| {
+                batch
+                    .par_iter()
+                    .enumerate()
+                    .map(|(index, block)| map(first + index, block))
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+
+            for (block, result) in batch.iter().zip(results) {
+                sink(block, result)?;
+            }
+
+            first += batch.len();
+            progress.advance(batch.iter().map(|block| block.len() as u64 * 8).sum())?;
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const REPORT_INTERVAL: u64 = 64 * 1024 * 8;
+
+pub(crate) type Callback<'a> = Option<Box<dyn FnMut(u64, u64) + 'a>>;
+
+/// Error payload returned (wrapped in an `std::io::Error`) when an operation is aborted
+/// through its cancellation flag.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    pub fn is(e: &std::io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+pub(crate) struct Progress<'a> {
+    callback: Option<&'a mut dyn FnMut(u64, u64)>,
+    cancel: Option<&'a AtomicBool>,
+    processed: u64,
+    total: u64,
+    next_report: u64,
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(
+        callback: &'a mut Callback,
+        cancel: Option<&'a AtomicBool>,
+        total: u64,
+    ) -> Self {
+        Progress {
+            callback: callback.as_mut().map(|callback| callback.as_mut() as _),
+            cancel,
+            processed: 0,
+            total,
+            next_report: REPORT_INTERVAL,
+        }
+    }
+
+    pub(crate) fn disabled() -> Progress<'static> {
+        Progress {
+            callback: None,
+            cancel: None,
+            processed: 0,
+            total: 0,
+            next_report: REPORT_INTERVAL,
+        }
+    }
+
+    pub(crate) fn set_total(&mut self, total: u64) {
+        self.total = total;
+    }
+
+    pub(crate) fn advance(&mut self, bits: u64) -> Result<(), std::io::Error> {
+        self.processed += bits;
+
+        if self.processed >= self.next_report {
+            self.next_report = self.processed + REPORT_INTERVAL;
+            self.check_cancelled()?;
+            self.report();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<(), std::io::Error> {
+        match self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(std::io::Error::other(Cancelled)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.report();
+    }
+
+    fn report(&mut self) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.processed / 8, self.total);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::Cursor;
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::decode::decompress as decompress_stream;
+use crate::encode::compress as compress_stream;
+
+fn to_py_err(e: std::io::Error) -> PyErr {
+    match e.kind() {
+        std::io::ErrorKind::InvalidInput
+        | std::io::ErrorKind::InvalidData
+        | std::io::ErrorKind::UnexpectedEof => PyValueError::new_err(e.to_string()),
+        _ => PyIOError::new_err(e.to_string()),
+    }
+}
+
+#[pyfunction]
+fn compress<'py>(py: Python<'py>, data: &[u8], letter_size: u8) -> PyResult<&'py PyBytes> {
+    let mut output = Vec::new();
+    compress_stream(Cursor::new(data), &mut output, letter_size).map_err(to_py_err)?;
+
+    Ok(PyBytes::new(py, &output))
+}
+
+#[pyfunction]
+fn decompress<'py>(py: Python<'py>, data: &[u8]) -> PyResult<&'py PyBytes> {
+    let mut output = Vec::new();
+    decompress_stream(data, &mut output).map_err(to_py_err)?;
+
+    Ok(PyBytes::new(py, &output))
+}
+
+#[pymodule]
+fn huffman_code(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compress, m)?)?;
+    m.add_function(wrap_pyfunction!(decompress, m)?)?;
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// Summary of a finished compression or decompression. Sizes are in bytes; for
+/// decompression `input_size` is the compressed size and `output_size` the restored one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionReport {
+    pub letter_size: u8,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub symbol_count: usize,
+    pub tree_depth: u32,
+    pub elapsed: Duration,
+    pub timings: Timings,
+}
+
+/// Time spent in each phase of a run. Phases that did not run are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    /// The frequency pass.
+    pub counting: Duration,
+    /// Building the tree and code table from the frequencies.
+    pub tree: Duration,
+    /// Writing or reading the tree header.
+    pub header: Duration,
+    /// The encoding or decoding pass.
+    pub coding: Duration,
+}
+
+impl CompressionReport {
+    /// `input_size / output_size`, or 0 when nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.output_size == 0 {
+            return 0.0;
+        }
+
+        self.input_size as f64 / self.output_size as f64
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+pub(crate) struct Counter<T> {
+    inner: T,
+    count: u64,
+}
+
+impl<T> Counter<T> {
+    pub fn new(inner: T) -> Self {
+        Counter { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
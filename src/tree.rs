@@ -0,0 +1,542 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::io::Write;
+
+use bitstream_io::{BigEndian, BitQueue};
+
+use crate::histogram::Histogram;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Tree<S = u32> {
+    Leaf(S),
+    Joint(Box<Tree<S>>, Box<Tree<S>>),
+}
+
+struct Weighted<S> {
+    freq: usize,
+    tree: Tree<S>,
+}
+
+impl<S> PartialEq for Weighted<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.freq == other.freq
+    }
+}
+
+impl<S> Eq for Weighted<S> {}
+
+impl<S> Ord for Weighted<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.freq.cmp(&self.freq)
+    }
+}
+
+impl<S> PartialOrd for Weighted<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S> Tree<S> {
+    pub fn from_frequencies<I: IntoIterator<Item = (S, usize)>>(frequencies: I) -> Option<Self> {
+        let mut nodes = frequencies
+            .into_iter()
+            .map(|(symbol, freq)| Weighted {
+                freq,
+                tree: Tree::Leaf(symbol),
+            })
+            .collect::<BinaryHeap<_>>();
+
+        while nodes.len() > 1 {
+            let left = nodes.pop().unwrap();
+            let right = nodes.pop().unwrap();
+
+            nodes.push(Weighted {
+                freq: left.freq + right.freq,
+                tree: Tree::Joint(Box::new(left.tree), Box::new(right.tree)),
+            });
+        }
+
+        nodes.pop().map(|root| root.tree)
+    }
+
+    pub fn from_symbols<I: IntoIterator<Item = S>>(symbols: I) -> Option<Self>
+    where
+        S: Hash + Eq,
+    {
+        Self::from_frequencies(symbols.into_iter().collect::<Histogram<S>>())
+    }
+
+    /// Rebuilds the tree described by `table`, returning `None` unless its codes form a
+    /// complete prefix code.
+    pub fn from_code_table(table: &CodeTable<S>) -> Option<Self>
+    where
+        S: Hash + Eq + Clone,
+    {
+        enum Partial<S> {
+            Empty,
+            Leaf(S),
+            Joint(Box<Partial<S>>, Box<Partial<S>>),
+        }
+
+        fn complete<S>(node: Partial<S>) -> Option<Tree<S>> {
+            match node {
+                Partial::Empty => None,
+                Partial::Leaf(symbol) => Some(Tree::Leaf(symbol)),
+                Partial::Joint(left, right) => Some(Tree::Joint(
+                    Box::new(complete(*left)?),
+                    Box::new(complete(*right)?),
+                )),
+            }
+        }
+
+        let mut root = Partial::Empty;
+
+        for (symbol, code) in table.iter() {
+            let mut node = &mut root;
+
+            for depth in (0..code.length).rev() {
+                if let Partial::Empty = node {
+                    *node = Partial::Joint(Box::new(Partial::Empty), Box::new(Partial::Empty));
+                }
+
+                node = match node {
+                    Partial::Joint(left, right) => match (code.value >> depth) & 1 {
+                        0 => left,
+                        _ => right,
+                    },
+                    _ => return None,
+                };
+            }
+
+            match node {
+                Partial::Empty => *node = Partial::Leaf(symbol.clone()),
+                _ => return None,
+            }
+        }
+
+        complete(root)
+    }
+
+    /// Length of the longest root-to-leaf path, i.e. the longest code.
+    pub fn depth(&self) -> u32 {
+        match self {
+            Tree::Leaf(_) => 0,
+            Tree::Joint(left, right) => 1 + left.depth().max(right.depth()),
+        }
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        match self {
+            Tree::Leaf(_) => 1,
+            Tree::Joint(left, right) => left.leaf_count() + right.leaf_count(),
+        }
+    }
+
+    /// Visits every node in pre-order (each joint before its left then right subtree),
+    /// paired with its depth.
+    pub fn nodes(&self) -> Nodes<'_, S> {
+        Nodes {
+            stack: vec![(self, 0)],
+        }
+    }
+
+    /// Every symbol paired with the length of its code.
+    pub fn leaves(&self) -> impl Iterator<Item = (&S, u32)> + '_ {
+        self.nodes().filter_map(|(node, depth)| match node {
+            Tree::Leaf(symbol) => Some((symbol, depth)),
+            Tree::Joint(..) => None,
+        })
+    }
+
+    /// Average code length in bits per symbol when coding data distributed as `histogram`.
+    pub fn expected_code_length(&self, histogram: &Histogram<S>) -> f64
+    where
+        S: Hash + Eq,
+    {
+        let total = histogram.total();
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        let bits = self
+            .leaves()
+            .map(|(symbol, length)| histogram.get(symbol) as f64 * length as f64)
+            .sum::<f64>();
+
+        bits / total as f64
+    }
+
+    /// Writes the tree as a Graphviz DOT digraph. Leaves are labelled by `label` and edges
+    /// with the bit they add to the code.
+    pub fn write_dot<W: Write, F: Fn(&S) -> String>(
+        &self,
+        mut writer: W,
+        label: F,
+    ) -> Result<(), std::io::Error> {
+        fn escape(label: &str) -> String {
+            label.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        fn write_node<S, W: Write, F: Fn(&S) -> String>(
+            node: &Tree<S>,
+            writer: &mut W,
+            label: &F,
+            next_id: &mut usize,
+        ) -> Result<usize, std::io::Error> {
+            let id = *next_id;
+            *next_id += 1;
+
+            match node {
+                Tree::Leaf(symbol) => {
+                    writeln!(writer, "  n{} [label=\"{}\"];", id, escape(&label(symbol)))?;
+                }
+                Tree::Joint(left, right) => {
+                    writeln!(writer, "  n{} [shape=point];", id)?;
+
+                    for (bit, child) in [(0, left), (1, right)] {
+                        let child_id = write_node(child, writer, label, next_id)?;
+                        writeln!(writer, "  n{} -> n{} [label=\"{}\"];", id, child_id, bit)?;
+                    }
+                }
+            }
+
+            Ok(id)
+        }
+
+        writeln!(writer, "digraph huffman {{")?;
+        writeln!(writer, "  node [shape=box];")?;
+        write_node(self, &mut writer, &label, &mut 0)?;
+        writeln!(writer, "}}")
+    }
+}
+
+pub struct Nodes<'a, S> {
+    stack: Vec<(&'a Tree<S>, u32)>,
+}
+
+impl<'a, S> Iterator for Nodes<'a, S> {
+    type Item = (&'a Tree<S>, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.stack.pop()?;
+
+        if let Tree::Joint(left, right) = node {
+            self.stack.push((right, depth + 1));
+            self.stack.push((left, depth + 1));
+        }
+
+        Some((node, depth))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Code {
+    pub length: u32,
+    pub value: u32,
+}
+
+impl Code {
+    /// Bits of the code, first bit first.
+    pub fn bits(&self) -> impl Iterator<Item = bool> {
+        let Code { length, value } = *self;
+        (0..length)
+            .rev()
+            .map(move |depth| (value >> depth) & 1 == 1)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CodeTable<S: Hash + Eq = u32> {
+    codes: HashMap<S, Code>,
+}
+
+impl<S: Hash + Eq> Default for CodeTable<S> {
+    fn default() -> Self {
+        CodeTable {
+            codes: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Hash + Eq> CodeTable<S> {
+    pub fn from_tree(tree: &Tree<S>) -> Self
+    where
+        S: Clone,
+    {
+        fn walk<S: Hash + Eq + Clone>(
+            root: &Tree<S>,
+            codes: &mut HashMap<S, Code>,
+            current_path: &mut Vec<bool>,
+        ) {
+            match root {
+                Tree::Leaf(symbol) => {
+                    let mut queue = BitQueue::<BigEndian, u32>::new();
+
+                    for bit in current_path {
+                        match *bit {
+                            false => queue.push(1, 0),
+                            true => queue.push(1, 1),
+                        }
+                    }
+
+                    let code = Code {
+                        length: queue.len(),
+                        value: queue.value(),
+                    };
+
+                    codes.insert(symbol.clone(), code);
+                }
+                Tree::Joint(left, right) => {
+                    current_path.push(false);
+                    walk(left, codes, current_path);
+                    current_path.pop();
+
+                    current_path.push(true);
+                    walk(right, codes, current_path);
+                    current_path.pop();
+                }
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut codes = HashMap::new();
+        walk(tree, &mut codes, &mut path);
+
+        CodeTable { codes }
+    }
+
+    pub fn get(&self, symbol: &S) -> Option<Code> {
+        self.codes.get(symbol).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&S, Code)> + '_ {
+        self.codes.iter().map(|(symbol, code)| (symbol, *code))
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    /// Sum of `2^-length` over all codes. At most 1 for any prefix code, and exactly 1
+    /// when the code is complete.
+    pub fn kraft_sum(&self) -> f64 {
+        self.codes
+            .values()
+            .map(|code| 0.5f64.powi(code.length as i32))
+            .sum()
+    }
+
+    /// Checks the Kraft inequality exactly, which any prefix code must satisfy. Codes longer
+    /// than 32 bits do not fit a [`Code`] and fail the check.
+    pub fn satisfies_kraft(&self) -> bool {
+        let mut sum = 0u128;
+
+        for code in self.codes.values() {
+            if code.length > 32 {
+                return false;
+            }
+
+            sum += 1 << (32 - code.length);
+        }
+
+        sum <= 1 << 32
+    }
+}
+
+/// A canonical Huffman code over `u32` letters: codes are handed out in order of length,
+/// then of symbol, so the code lengths alone describe it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CanonicalCode {
+    /// Number of codes of every length, indexed by length.
+    counts: Vec<u32>,
+    /// Symbols ordered by code length, then value.
+    symbols: Vec<u32>,
+}
+
+impl CanonicalCode {
+    /// Builds the code from `(symbol, length)` pairs. A single symbol has length 0.
+    pub fn from_lengths<I: IntoIterator<Item = (u32, u32)>>(lengths: I) -> Self {
+        let mut lengths = lengths.into_iter().collect::<Vec<_>>();
+        lengths.sort_unstable_by_key(|&(symbol, length)| (length, symbol));
+
+        let max_length = lengths.last().map_or(0, |&(_, length)| length);
+        let mut counts = vec![0; max_length as usize + 1];
+
+        for &(_, length) in &lengths {
+            counts[length as usize] += 1;
+        }
+
+        CanonicalCode {
+            counts,
+            symbols: lengths.into_iter().map(|(symbol, _)| symbol).collect(),
+        }
+    }
+
+    /// The canonical code with the code lengths of `tree`.
+    pub fn from_tree(tree: &Tree) -> Self {
+        Self::from_lengths(tree.leaves().map(|(&symbol, length)| (symbol, length)))
+    }
+
+    /// The shortest code for letters counted as `frequencies` whose codes are at most
+    /// `max_length` bits long, built with the package-merge algorithm. There must be no more
+    /// than `2^max_length` symbols.
+    pub fn length_limited<I: IntoIterator<Item = (u32, usize)>>(
+        frequencies: I,
+        max_length: u32,
+    ) -> Self {
+        let mut leaves = frequencies.into_iter().collect::<Vec<_>>();
+        leaves.sort_unstable_by_key(|&(symbol, freq)| (freq, symbol));
+
+        if leaves.len() < 2 {
+            return Self::from_lengths(leaves.into_iter().map(|(symbol, _)| (symbol, 0)));
+        }
+
+        // A list for every length from the longest up, holding the leaves merged with
+        // packages of pairs from the list before it, lightest first. Only which entries are
+        // leaves is kept for choosing the lengths.
+        let weights = leaves
+            .iter()
+            .map(|&(_, freq)| freq as u64)
+            .collect::<Vec<_>>();
+        let mut lists = vec![vec![true; leaves.len()]];
+        let mut previous = weights.clone();
+
+        for _ in 1..max_length {
+            let mut packages = previous
+                .chunks_exact(2)
+                .map(|pair| pair[0] + pair[1])
+                .peekable();
+            let mut leaf_weights = weights.iter().copied().peekable();
+            let mut merged = Vec::with_capacity(leaves.len() + previous.len() / 2);
+            let mut kinds = Vec::with_capacity(merged.capacity());
+
+            loop {
+                let leaf = match (leaf_weights.peek(), packages.peek()) {
+                    (None, None) => break,
+                    (Some(leaf), Some(package)) => leaf <= package,
+                    (leaf, _) => leaf.is_some(),
+                };
+
+                match leaf {
+                    true => merged.extend(leaf_weights.next()),
+                    false => merged.extend(packages.next()),
+                }
+                kinds.push(leaf);
+            }
+
+            previous = merged;
+            lists.push(kinds);
+        }
+
+        // Taking the 2n - 2 lightest entries of the shortest list, every leaf taken from a
+        // list adds a bit to its code and every package takes two entries of the list below.
+        let mut lengths = vec![0; leaves.len()];
+        let mut taken = 2 * leaves.len() - 2;
+
+        for kinds in lists.iter().rev() {
+            let leaf_count = kinds[..taken].iter().filter(|&&leaf| leaf).count();
+
+            for length in &mut lengths[..leaf_count] {
+                *length += 1;
+            }
+
+            taken = 2 * (taken - leaf_count);
+        }
+
+        Self::from_lengths(
+            leaves
+                .into_iter()
+                .zip(lengths)
+                .map(|((symbol, _), length)| (symbol, length)),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Length of the longest code.
+    pub fn max_length(&self) -> u32 {
+        self.counts.len().saturating_sub(1) as u32
+    }
+
+    /// Number of codes of every length from 0 to [`max_length`](Self::max_length).
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Symbols ordered by code length, then value.
+    pub fn symbols(&self) -> &[u32] {
+        &self.symbols
+    }
+
+    /// Every symbol paired with the length of its code, in canonical order.
+    pub fn lengths(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .flat_map(|(length, &count)| std::iter::repeat_n(length as u32, count as usize))
+            .zip(&self.symbols)
+            .map(|(length, &symbol)| (symbol, length))
+    }
+
+    /// Whether the lengths describe a complete prefix code, as every Huffman code is.
+    pub fn is_complete(&self) -> bool {
+        match self.max_length() {
+            0 => self.symbols.len() == 1,
+            max_length if max_length > 32 => false,
+            max_length => {
+                let sum = self
+                    .counts
+                    .iter()
+                    .enumerate()
+                    .skip(1)
+                    .map(|(length, &count)| (count as u64) << (max_length as usize - length))
+                    .sum::<u64>();
+
+                sum == 1 << max_length
+            }
+        }
+    }
+
+    pub fn table(&self) -> CodeTable {
+        let mut codes = HashMap::with_capacity(self.symbols.len());
+        let mut value = 0u32;
+        let mut previous = 0;
+
+        for (symbol, length) in self.lengths() {
+            value = value.checked_shl(length - previous).unwrap_or(0);
+            codes.insert(symbol, Code { length, value });
+            value = value.wrapping_add(1);
+            previous = length;
+        }
+
+        CodeTable { codes }
+    }
+
+    /// The tree of the code, `None` unless the code is complete.
+    pub fn tree(&self) -> Option<Tree> {
+        if !self.is_complete() {
+            return None;
+        }
+
+        Tree::from_code_table(&self.table())
+    }
+}
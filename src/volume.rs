@@ -0,0 +1,197 @@
+use std::ffi::OsString;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Version of the volume header, the digit ending its magic.
+pub const VOLUME_VERSION: u8 = 1;
+
+/// First bytes of every volume.
+pub const VOLUME_MAGIC: &[u8; 4] = b"HVL1";
+
+/// Bytes taken by the magic, the volume number and the flags byte.
+pub const VOLUME_HEADER_SIZE: u64 = 9;
+
+/// Flag marking the last volume.
+const LAST: u8 = 0x01;
+
+/// `file.huff` becomes `file.huff.001` for volume 1.
+pub fn volume_path(path: &Path, number: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{:03}", number));
+
+    PathBuf::from(name)
+}
+
+/// Reads the header of a volume, returning its number and whether it is the last one.
+fn read_header<R: Read>(reader: &mut R) -> Result<(u32, bool), std::io::Error> {
+    let mut header = [0; VOLUME_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+
+    if &header[..4] != VOLUME_MAGIC || header[8] & !LAST != 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "not a volume of split output",
+        ));
+    }
+
+    let number = u32::from_be_bytes(header[4..8].try_into().unwrap());
+
+    Ok((number, header[8] & LAST != 0))
+}
+
+/// Splits what is written to it over volumes of at most `volume_size` bytes each, created as
+/// they are needed, so output fits on media with a limit on the size of files.
+///
+/// Every volume starts with a continuation header: the magic `HVL1`, the number of the volume
+/// from 1 as a big endian `u32` and a flags byte, bit 0 of which marks the last volume. The
+/// bytes after the headers, in order of the volumes, are what was written.
+pub struct VolumeWriter<W: Write + Seek, F: FnMut(u32) -> Result<W, std::io::Error>> {
+    create: F,
+    volume_size: u64,
+    current: Option<W>,
+    number: u32,
+    /// Bytes the current volume has room for.
+    left: u64,
+}
+
+impl<W: Write + Seek, F: FnMut(u32) -> Result<W, std::io::Error>> VolumeWriter<W, F> {
+    /// Writes volumes of `volume_size` bytes, creating volume `n` with `create(n)`.
+    pub fn new(volume_size: u64, create: F) -> Result<Self, std::io::Error> {
+        if volume_size <= VOLUME_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("volumes must be larger than {} bytes", VOLUME_HEADER_SIZE),
+            ));
+        }
+
+        Ok(VolumeWriter {
+            create,
+            volume_size,
+            current: None,
+            number: 0,
+            left: 0,
+        })
+    }
+
+    fn next_volume(&mut self) -> Result<(), std::io::Error> {
+        if let Some(mut volume) = self.current.take() {
+            volume.flush()?;
+        }
+
+        self.number += 1;
+        let mut volume = (self.create)(self.number)?;
+        volume.write_all(VOLUME_MAGIC)?;
+        volume.write_all(&self.number.to_be_bytes())?;
+        volume.write_all(&[0])?;
+
+        self.current = Some(volume);
+        self.left = self.volume_size - VOLUME_HEADER_SIZE;
+
+        Ok(())
+    }
+
+    /// Marks the current volume as the last one and returns the number of volumes. Empty
+    /// output still gets a volume.
+    pub fn finish(mut self) -> Result<u32, std::io::Error> {
+        if self.current.is_none() {
+            self.next_volume()?;
+        }
+
+        let volume = self.current.as_mut().unwrap();
+        volume.seek(SeekFrom::Start(VOLUME_HEADER_SIZE - 1))?;
+        volume.write_all(&[LAST])?;
+        volume.flush()?;
+
+        Ok(self.number)
+    }
+}
+
+impl<W: Write + Seek, F: FnMut(u32) -> Result<W, std::io::Error>> Write for VolumeWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.left == 0 {
+            self.next_volume()?;
+        }
+
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let written = self.current.as_mut().unwrap().write(&buf[..len])?;
+        self.left -= written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match &mut self.current {
+            Some(volume) => volume.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the bytes of the volumes written by [`VolumeWriter`] as one input, opening volume
+/// `n` with `open(n)` once the one before it runs out.
+pub struct VolumeReader<R: Read, F: FnMut(u32) -> Result<R, std::io::Error>> {
+    open: F,
+    current: R,
+    number: u32,
+    last: bool,
+}
+
+impl<R: Read, F: FnMut(u32) -> Result<R, std::io::Error>> VolumeReader<R, F> {
+    /// Reads on from `first`, which must be volume 1.
+    pub fn new(mut first: R, open: F) -> Result<Self, std::io::Error> {
+        let (number, last) = read_header(&mut first)?;
+
+        if number != 1 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "input is volume {} of split output, start from volume 1",
+                    number
+                ),
+            ));
+        }
+
+        Ok(VolumeReader {
+            open,
+            current: first,
+            number,
+            last,
+        })
+    }
+}
+
+impl<R: Read, F: FnMut(u32) -> Result<R, std::io::Error>> Read for VolumeReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        loop {
+            let read = self.current.read(buf)?;
+
+            if read != 0 || buf.is_empty() || self.last {
+                return Ok(read);
+            }
+
+            let number = self.number + 1;
+            let mut volume = (self.open)(number).map_err(|e| match e.kind() {
+                ErrorKind::NotFound => std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("volume {} of split output is missing", number),
+                ),
+                _ => e,
+            })?;
+
+            let (found, last) = read_header(&mut volume)?;
+
+            if found != number {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("volume {} of split output is out of sequence", number),
+                ));
+            }
+
+            (self.current, self.number, self.last) = (volume, number, last);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::io::Cursor;
+
+use wasm_bindgen::prelude::*;
+
+use crate::decode::decompress as decompress_stream;
+use crate::encode::compress as compress_stream;
+
+#[wasm_bindgen]
+pub fn compress(input: &[u8], letter_size: u8) -> Result<Vec<u8>, JsError> {
+    let mut output = Vec::new();
+    compress_stream(Cursor::new(input), &mut output, letter_size)?;
+
+    Ok(output)
+}
+
+#[wasm_bindgen]
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, JsError> {
+    let mut output = Vec::new();
+    decompress_stream(input, &mut output)?;
+
+    Ok(output)
+}